@@ -0,0 +1,239 @@
+use crate::config::AdmissionSettings;
+use crate::resource_usage::ResourceUsage;
+use log::info;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Weight of the newest scrape in a domain's running cost average.
+const COST_SMOOTHING: f64 = 0.3;
+
+/// Domains whose cost is remembered; beyond this the map is cleared.
+const MAX_DOMAINS: usize = 10_000;
+
+/// Turns away scrapes of domains that recently cost a lot of Chrome CPU or
+/// memory while the host itself is short on either, so cheap requests keep
+/// getting served when a few heavy sites would otherwise tip it over.
+pub struct AdmissionController {
+    settings: AdmissionSettings,
+    costs: Mutex<HashMap<String, Cost>>,
+}
+
+#[derive(Clone, Copy)]
+struct Cost {
+    cpu_ms: f64,
+    peak_rss_bytes: f64,
+}
+
+impl AdmissionController {
+    pub fn new(settings: AdmissionSettings) -> Self {
+        AdmissionController {
+            settings,
+            costs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds a finished scrape's resource usage to `domain`'s running cost.
+    pub fn record(&self, domain: &str, usage: &ResourceUsage) {
+        let mut costs = self.costs.lock().unwrap();
+        if costs.len() >= MAX_DOMAINS && !costs.contains_key(domain) {
+            costs.clear();
+        }
+
+        let latest = Cost {
+            cpu_ms: usage.cpu_ms as f64,
+            peak_rss_bytes: usage.peak_rss_bytes as f64,
+        };
+        let cost = costs.entry(domain.to_string()).or_insert(latest);
+        cost.cpu_ms += COST_SMOOTHING * (latest.cpu_ms - cost.cpu_ms);
+        cost.peak_rss_bytes += COST_SMOOTHING * (latest.peak_rss_bytes - cost.peak_rss_bytes);
+    }
+
+    /// `Err` with how long the caller should wait when `domain` is expensive
+    /// and the host is under pressure right now.
+    pub fn admit(&self, domain: &str) -> Result<(), Duration> {
+        self.admit_under(domain, HostLoad::current)
+    }
+
+    /// `admit` with the host's load from `load`, which is only read for
+    /// expensive domains.
+    fn admit_under(&self, domain: &str, load: impl FnOnce() -> HostLoad) -> Result<(), Duration> {
+        if !self.settings.enabled {
+            return Ok(());
+        }
+
+        let expensive = self
+            .costs
+            .lock()
+            .unwrap()
+            .get(domain)
+            .map_or(false, |cost| {
+                cost.cpu_ms >= self.settings.expensive_cpu_ms as f64
+                    || cost.peak_rss_bytes >= self.settings.expensive_rss_bytes as f64
+            });
+        if expensive && load().under_pressure(&self.settings) {
+            info!("rejecting expensive scrape of {} under load", domain);
+            return Err(self.settings.retry_after());
+        }
+
+        Ok(())
+    }
+}
+
+/// How busy the host is; either reading is `None` where `/proc` doesn't
+/// have it, which never counts as pressure.
+struct HostLoad {
+    load_per_cpu: Option<f64>,
+    available_memory_ratio: Option<f64>,
+}
+
+impl HostLoad {
+    fn current() -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get()) as f64;
+        HostLoad {
+            load_per_cpu: load_average().map(|load| load / cpus),
+            available_memory_ratio: available_memory_ratio(),
+        }
+    }
+
+    fn under_pressure(&self, settings: &AdmissionSettings) -> bool {
+        let cpu_pressure = self
+            .load_per_cpu
+            .map_or(false, |load| load >= settings.max_load_per_cpu);
+        let memory_pressure = self
+            .available_memory_ratio
+            .map_or(false, |ratio| ratio <= settings.min_available_memory_ratio);
+        cpu_pressure || memory_pressure
+    }
+}
+
+/// One-minute load average from `/proc/loadavg`.
+fn load_average() -> Option<f64> {
+    fs::read_to_string("/proc/loadavg")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// `MemAvailable / MemTotal` from `/proc/meminfo`.
+fn available_memory_ratio() -> Option<f64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let field = |name: &str| -> Option<f64> {
+        meminfo
+            .lines()
+            .find(|line| line.starts_with(name))?
+            .split_whitespace()
+            .nth(1)?
+            .parse()
+            .ok()
+    };
+    let total = field("MemTotal:")?;
+    let available = field("MemAvailable:")?;
+    (total > 0.0).then(|| available / total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDLE: HostLoad = HostLoad {
+        load_per_cpu: Some(0.1),
+        available_memory_ratio: Some(0.8),
+    };
+    const BUSY: HostLoad = HostLoad {
+        load_per_cpu: Some(2.0),
+        available_memory_ratio: Some(0.8),
+    };
+    const SHORT_OF_MEMORY: HostLoad = HostLoad {
+        load_per_cpu: Some(0.1),
+        available_memory_ratio: Some(0.05),
+    };
+
+    fn usage(cpu_ms: u64, peak_rss_bytes: u64) -> ResourceUsage {
+        ResourceUsage {
+            cpu_ms,
+            peak_rss_bytes,
+            shared: false,
+        }
+    }
+
+    fn controller() -> AdmissionController {
+        AdmissionController::new(AdmissionSettings::default())
+    }
+
+    fn admitted(controller: &AdmissionController, domain: &str, load: HostLoad) -> bool {
+        controller.admit_under(domain, || load).is_ok()
+    }
+
+    #[test]
+    fn cheap_and_unknown_domains_are_admitted_under_pressure() {
+        let controller = controller();
+        controller.record("cheap.example", &usage(100, 1024));
+        assert!(admitted(&controller, "cheap.example", BUSY));
+        assert!(admitted(&controller, "new.example", BUSY));
+    }
+
+    #[test]
+    fn expensive_domains_are_rejected_only_under_pressure() {
+        let controller = controller();
+        controller.record("cpu.example", &usage(60_000, 1024));
+        controller.record("memory.example", &usage(100, 4 * 1024 * 1024 * 1024));
+        assert!(admitted(&controller, "cpu.example", IDLE));
+        assert_eq!(
+            controller.admit_under("cpu.example", || BUSY),
+            Err(Duration::from_secs(30))
+        );
+        assert!(!admitted(&controller, "memory.example", SHORT_OF_MEMORY));
+        assert!(!admitted(&controller, "memory.example", BUSY));
+    }
+
+    #[test]
+    fn unreadable_load_is_no_pressure() {
+        let controller = controller();
+        controller.record("cpu.example", &usage(60_000, 1024));
+        let unknown = HostLoad {
+            load_per_cpu: None,
+            available_memory_ratio: None,
+        };
+        assert!(admitted(&controller, "cpu.example", unknown));
+    }
+
+    #[test]
+    fn disabled_admits_everything() {
+        let controller = AdmissionController::new(AdmissionSettings {
+            enabled: false,
+            ..Default::default()
+        });
+        controller.record("cpu.example", &usage(60_000, 1024));
+        assert!(admitted(&controller, "cpu.example", BUSY));
+    }
+
+    #[test]
+    fn cheap_scrapes_bring_the_running_cost_back_down() {
+        let controller = controller();
+        controller.record("site.example", &usage(20_000, 1024));
+        assert!(!admitted(&controller, "site.example", BUSY));
+        // 20s decays by 0.7 a scrape: 14s, 9.8s
+        controller.record("site.example", &usage(0, 1024));
+        assert!(!admitted(&controller, "site.example", BUSY));
+        controller.record("site.example", &usage(0, 1024));
+        assert!(admitted(&controller, "site.example", BUSY));
+    }
+
+    #[test]
+    fn a_full_cost_table_starts_over() {
+        let controller = controller();
+        controller.record("expensive.example", &usage(60_000, 1024));
+        for i in 1..MAX_DOMAINS {
+            controller.record(&format!("{}.example", i), &usage(100, 1024));
+        }
+        assert!(!admitted(&controller, "expensive.example", BUSY));
+
+        controller.record("one-too-many.example", &usage(100, 1024));
+        assert!(admitted(&controller, "expensive.example", BUSY));
+        assert_eq!(controller.costs.lock().unwrap().len(), 1);
+    }
+}
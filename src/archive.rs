@@ -0,0 +1,130 @@
+use crate::config::ArchiveSettings;
+use anyhow::bail;
+use http_req::{request::Request, uri::Uri};
+use log::{info, warn};
+use std::convert::TryFrom;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use url::Url;
+
+/// Save Page Now renders the page itself, which can take a while.
+const SAVE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Submits scraped pages to the Internet Archive's Save Page Now in the
+/// background, one at a time and at most one per `min_interval_ms`, so
+/// requests never wait on the archive.
+pub struct Archiver {
+    queue: mpsc::Sender<Url>,
+}
+
+impl Archiver {
+    /// Starts the worker that works through the queue.
+    pub fn start(settings: ArchiveSettings) -> Self {
+        let (queue, mut queued) = mpsc::channel::<Url>(settings.queue_size.max(1));
+        tokio::spawn(async move {
+            while let Some(url) = queued.recv().await {
+                let save_url = url.clone();
+                match tokio::task::spawn_blocking(move || save(&save_url)).await {
+                    Ok(Ok(())) => info!("saved {} to the Wayback Machine", url),
+                    Ok(Err(e)) => warn!("failed to save {} to the Wayback Machine: {}", url, e),
+                    Err(_) => {}
+                }
+                tokio::time::sleep(settings.min_interval()).await;
+            }
+        });
+        Archiver { queue }
+    }
+
+    /// Queues `url` for archiving and returns a Wayback Machine URL to cite
+    /// it by. The URL names the current time, and the archive redirects it
+    /// to the capture nearest to that: the one queued here once it's done.
+    /// `None` when the queue is full.
+    pub fn submit(&self, url: &Url) -> Option<String> {
+        if self.queue.try_send(url.clone()).is_err() {
+            warn!("archive queue is full, not saving {}", url);
+            return None;
+        }
+        Some(format!(
+            "https://web.archive.org/web/{}/{}",
+            wayback_timestamp(SystemTime::now()),
+            url
+        ))
+    }
+}
+
+fn save(url: &Url) -> anyhow::Result<()> {
+    let uri = Uri::try_from(format!("https://web.archive.org/save/{}", url).as_str())?;
+    let res = Request::new(&uri)
+        .timeout(Some(SAVE_TIMEOUT))
+        .send(&mut io::sink())?;
+    // a capture answers with a redirect to it
+    if !res.status_code().is_success() && !res.status_code().is_redirect() {
+        bail!("Save Page Now returned {}", res.status_code());
+    }
+    Ok(())
+}
+
+/// `time` in UTC as the Wayback Machine's `YYYYMMDDhhmmss`.
+fn wayback_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+
+    // civil-from-days, for days since 1970-01-01
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> String {
+        wayback_timestamp(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn the_epoch_and_times_before_it() {
+        assert_eq!(at(0), "19700101000000");
+        assert_eq!(
+            wayback_timestamp(UNIX_EPOCH - Duration::from_secs(1)),
+            "19700101000000"
+        );
+    }
+
+    #[test]
+    fn leap_days() {
+        assert_eq!(at(951_827_696), "20000229123456");
+        assert_eq!(at(1_709_251_199), "20240229235959");
+        // not a leap year: February 28th is followed by March 1st
+        assert_eq!(at(1_677_628_800), "20230301000000");
+        // nor is 2100, a century not divisible by 400
+        assert_eq!(at(4_107_542_399), "21000228235959");
+        assert_eq!(at(4_107_542_400), "21000301000000");
+    }
+
+    #[test]
+    fn year_boundaries() {
+        assert_eq!(at(946_684_799), "19991231235959");
+        assert_eq!(at(946_684_800), "20000101000000");
+    }
+}
@@ -0,0 +1,385 @@
+use crate::config::AssetCacheSettings;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use headless_chrome::browser::tab::RequestPausedDecision;
+use headless_chrome::browser::transport::{SessionId, Transport};
+use headless_chrome::browser::Tab;
+use headless_chrome::protocol::cdp::Fetch::events::RequestPausedEvent;
+use headless_chrome::protocol::cdp::Fetch::{
+    FulfillRequest, GetResponseBody, HeaderEntry, RequestPattern, RequestStage,
+};
+use headless_chrome::protocol::cdp::Network::ResourceType;
+use log::debug;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Subresources worth keeping: the bundles sites ship unchanged to every
+/// page, unlike documents, XHRs or images.
+const CACHED_TYPES: [ResourceType; 3] = [
+    ResourceType::Script,
+    ResourceType::Stylesheet,
+    ResourceType::Font,
+];
+
+/// Largest share of the cache one asset may take.
+const MAX_ASSET_SHARE: usize = 8;
+
+/// Response headers that describe the body as it came over the wire; the
+/// cached body is the decoded one.
+const WIRE_HEADERS: [&str; 3] = ["content-encoding", "content-length", "transfer-encoding"];
+
+/// Scripts, stylesheets and fonts shared by the pool's tabs through CDP
+/// request interception, so repeated scrapes of a site don't download the
+/// same bundles again. Each browser's own HTTP cache lives in a throwaway
+/// profile and dies with it; this one outlives browsers.
+///
+/// Only `200` GET responses that `Cache-Control` lets shared caches keep for
+/// a `max-age`, and that don't set cookies or vary on anything but encoding,
+/// are kept, for that `max-age` capped by `max_ttl_ms`. The least recently
+/// used go first once the bodies reach `max_bytes`.
+pub struct AssetCache {
+    settings: AssetCacheSettings,
+    state: Mutex<AssetState>,
+}
+
+#[derive(Default)]
+struct AssetState {
+    assets: HashMap<String, Asset>,
+    /// Sum of the stored bodies' lengths.
+    bytes: usize,
+    /// Bumped on every access; an asset's `last_used` is the value then.
+    clock: u64,
+}
+
+#[derive(Clone)]
+struct Asset {
+    headers: Vec<HeaderEntry>,
+    /// Base64, as `Fetch.fulfillRequest` takes it.
+    body: String,
+    expires: Instant,
+    last_used: u64,
+}
+
+impl AssetCache {
+    pub fn new(settings: AssetCacheSettings) -> Self {
+        AssetCache {
+            settings,
+            state: Mutex::new(AssetState::default()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.settings.max_bytes > 0
+    }
+
+    /// Answers `tab`'s asset requests from the cache and stores the assets
+    /// it downloads. Fetch interception also carries proxy authentication,
+    /// so `handle_auth` has to say whether the tab's proxy needs it.
+    pub fn intercept(self: &Arc<Self>, tab: &Tab, handle_auth: bool) -> anyhow::Result<()> {
+        let patterns: Vec<RequestPattern> = CACHED_TYPES
+            .iter()
+            .flat_map(|resource_type| {
+                [RequestStage::Request, RequestStage::Response].map(|stage| RequestPattern {
+                    url_pattern: None,
+                    resource_Type: Some(resource_type.clone()),
+                    request_stage: Some(stage),
+                })
+            })
+            .collect();
+        tab.enable_fetch(Some(&patterns), Some(handle_auth))?;
+
+        let cache = self.clone();
+        tab.enable_request_interception(Arc::new(
+            move |transport: Arc<Transport>, session_id: SessionId, event: RequestPausedEvent| {
+                cache.paused(&transport, session_id, event)
+            },
+        ))?;
+        Ok(())
+    }
+
+    /// Fulfills a paused request from the cache, or lets it go on and keeps
+    /// a copy of its response if it's cacheable.
+    fn paused(
+        &self,
+        transport: &Transport,
+        session_id: SessionId,
+        event: RequestPausedEvent,
+    ) -> RequestPausedDecision {
+        let params = event.params;
+        if params.request.method != "GET" {
+            return RequestPausedDecision::Continue(None);
+        }
+        let url = params.request.url;
+
+        let headers = match (params.response_status_code, params.response_headers) {
+            // the request stage
+            (None, _) => {
+                return match self.get(&url) {
+                    Some(asset) => RequestPausedDecision::Fulfill(FulfillRequest {
+                        request_id: params.request_id,
+                        response_code: 200,
+                        response_headers: Some(asset.headers),
+                        binary_response_headers: None,
+                        body: Some(asset.body),
+                        response_phrase: None,
+                    }),
+                    None => RequestPausedDecision::Continue(None),
+                };
+            }
+            (Some(200), Some(headers)) => headers,
+            _ => return RequestPausedDecision::Continue(None),
+        };
+        let ttl = match freshness(&headers, self.settings.max_ttl()) {
+            Some(ttl) => ttl,
+            None => return RequestPausedDecision::Continue(None),
+        };
+        let body = GetResponseBody {
+            request_id: params.request_id,
+        };
+        match transport.call_method_on_target(session_id, body) {
+            Ok(body) => {
+                let body = if body.base_64_encoded {
+                    body.body
+                } else {
+                    STANDARD.encode(body.body)
+                };
+                self.insert(url, headers, body, ttl);
+            }
+            Err(e) => debug!("couldn't read asset {} for the cache: {}", url, e),
+        }
+        RequestPausedDecision::Continue(None)
+    }
+
+    fn get(&self, url: &str) -> Option<Asset> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        let asset = state.assets.get_mut(url)?;
+        if asset.expires <= Instant::now() {
+            let stale = state.assets.remove(url).unwrap();
+            state.bytes -= stale.body.len();
+            return None;
+        }
+        asset.last_used = clock;
+        Some(asset.clone())
+    }
+
+    fn insert(&self, url: String, headers: Vec<HeaderEntry>, body: String, ttl: Duration) {
+        let max_bytes = self.settings.max_bytes;
+        if body.len() > max_bytes / MAX_ASSET_SHARE {
+            return;
+        }
+        let headers = headers
+            .into_iter()
+            .filter(|header| !WIRE_HEADERS.contains(&header.name.to_ascii_lowercase().as_str()))
+            .collect();
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(replaced) = state.assets.remove(&url) {
+            state.bytes -= replaced.body.len();
+        }
+        if state.bytes + body.len() > max_bytes {
+            let now = Instant::now();
+            state.assets.retain(|_, asset| asset.expires > now);
+            state.bytes = state.assets.values().map(|asset| asset.body.len()).sum();
+        }
+        while state.bytes + body.len() > max_bytes {
+            let least_recent = state
+                .assets
+                .iter()
+                .min_by_key(|(_, asset)| asset.last_used)
+                .map(|(url, _)| url.clone());
+            match least_recent.and_then(|url| state.assets.remove(&url)) {
+                Some(evicted) => state.bytes -= evicted.body.len(),
+                None => break,
+            }
+        }
+
+        state.clock += 1;
+        state.bytes += body.len();
+        let asset = Asset {
+            headers,
+            body,
+            expires: Instant::now() + ttl,
+            last_used: state.clock,
+        };
+        state.assets.insert(url, asset);
+    }
+}
+
+/// How long a response with `headers` may be kept by a shared cache, capped
+/// at `max_ttl`; `None` when it mustn't be kept at all.
+fn freshness(headers: &[HeaderEntry], max_ttl: Duration) -> Option<Duration> {
+    // the comma-separated values of every `name` header, lowercased
+    let values = |name: &str| -> Vec<String> {
+        headers
+            .iter()
+            .filter(|header| header.name.eq_ignore_ascii_case(name))
+            .flat_map(|header| {
+                header
+                    .value
+                    .split(',')
+                    .map(|value| value.trim().to_ascii_lowercase())
+            })
+            .filter(|value| !value.is_empty())
+            .collect()
+    };
+    if !values("set-cookie").is_empty() {
+        return None;
+    }
+    if values("vary")
+        .iter()
+        .any(|field| field != "accept-encoding")
+    {
+        return None;
+    }
+
+    let mut max_age = None;
+    for directive in values("cache-control") {
+        match directive.split_once('=') {
+            None if ["no-store", "no-cache", "private"].contains(&directive.as_str()) => {
+                return None
+            }
+            // a shared cache's own limit wins over the general one
+            Some(("s-maxage", secs)) => max_age = secs.trim_matches('"').parse().ok().or(max_age),
+            Some(("max-age", secs)) if max_age.is_none() => {
+                max_age = secs.trim_matches('"').parse().ok()
+            }
+            _ => {}
+        }
+    }
+    match max_age {
+        Some(0) | None => None,
+        Some(secs) => Some(Duration::from_secs(secs).min(max_ttl)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(86_400);
+
+    fn headers(entries: &[(&str, &str)]) -> Vec<HeaderEntry> {
+        entries
+            .iter()
+            .map(|(name, value)| HeaderEntry {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect()
+    }
+
+    fn cache(max_bytes: usize) -> AssetCache {
+        AssetCache::new(AssetCacheSettings {
+            max_bytes,
+            max_ttl_ms: DAY.as_millis() as u64,
+        })
+    }
+
+    fn body(len: usize) -> String {
+        "x".repeat(len)
+    }
+
+    #[test]
+    fn max_age_is_kept_up_to_the_cap() {
+        let fresh =
+            |cache_control: &str| freshness(&headers(&[("Cache-Control", cache_control)]), DAY);
+        assert_eq!(fresh("public, max-age=600"), Some(Duration::from_secs(600)));
+        assert_eq!(fresh("max-age=31536000, immutable"), Some(DAY));
+        assert_eq!(
+            fresh("max-age=60, s-maxage=300"),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(
+            fresh("s-maxage=300, max-age=60"),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(fresh("max-age=0"), None);
+        assert_eq!(fresh("public"), None);
+        assert_eq!(freshness(&[], DAY), None);
+    }
+
+    #[test]
+    fn private_and_personalized_responses_are_not_kept() {
+        for cache_control in ["private, max-age=600", "no-store", "max-age=600, no-cache"] {
+            assert_eq!(
+                freshness(&headers(&[("cache-control", cache_control)]), DAY),
+                None,
+                "{}",
+                cache_control
+            );
+        }
+        let with = |name: &str, value: &str| {
+            freshness(
+                &headers(&[("Cache-Control", "max-age=600"), (name, value)]),
+                DAY,
+            )
+        };
+        assert_eq!(with("Set-Cookie", "session=1"), None);
+        assert_eq!(with("Vary", "Cookie"), None);
+        assert_eq!(
+            with("Vary", "Accept-Encoding"),
+            Some(Duration::from_secs(600))
+        );
+    }
+
+    #[test]
+    fn stored_assets_drop_their_wire_headers() {
+        let cache = cache(1024);
+        let headers = headers(&[
+            ("Content-Type", "text/javascript"),
+            ("Content-Encoding", "br"),
+            ("Content-Length", "12"),
+        ]);
+        cache.insert(
+            "https://a.example/app.js".to_string(),
+            headers,
+            body(16),
+            DAY,
+        );
+        let asset = cache.get("https://a.example/app.js").unwrap();
+        assert_eq!(asset.headers.len(), 1);
+        assert_eq!(asset.headers[0].name, "Content-Type");
+        assert_eq!(asset.body, body(16));
+        assert_eq!(
+            cache.get("https://a.example/other.js").map(|a| a.body),
+            None
+        );
+    }
+
+    #[test]
+    fn expired_assets_are_dropped() {
+        let cache = cache(1024);
+        cache.insert("a".to_string(), Vec::new(), body(16), Duration::ZERO);
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.state.lock().unwrap().bytes, 0);
+    }
+
+    #[test]
+    fn the_least_recently_used_assets_make_room() {
+        let cache = cache(800);
+        cache.insert("a".to_string(), Vec::new(), body(100), DAY);
+        cache.insert("b".to_string(), Vec::new(), body(100), DAY);
+        cache.insert("c".to_string(), Vec::new(), body(100), DAY);
+        cache.insert("d".to_string(), Vec::new(), body(100), DAY);
+        assert!(cache.get("a").is_some());
+        // 400 of 800 bytes used; 500 more pushes out b, the least recent
+        cache.insert("e".to_string(), Vec::new(), body(100), DAY);
+        cache.insert("f".to_string(), Vec::new(), body(100), DAY);
+        cache.insert("g".to_string(), Vec::new(), body(100), DAY);
+        cache.insert("h".to_string(), Vec::new(), body(100), DAY);
+        cache.insert("i".to_string(), Vec::new(), body(100), DAY);
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("i").is_some());
+        assert_eq!(cache.state.lock().unwrap().bytes, 800);
+    }
+
+    #[test]
+    fn assets_too_big_for_their_share_are_skipped() {
+        let cache = cache(800);
+        cache.insert("big".to_string(), Vec::new(), body(101), DAY);
+        assert!(cache.get("big").is_none());
+    }
+}
@@ -0,0 +1,217 @@
+use crate::config::{BlocklistAction, BlocklistSettings};
+use crate::error::ScrapeError;
+use anyhow::Context;
+use log::info;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::net::IpAddr;
+
+/// Domains from the configured category lists, so scrapes of sensitive
+/// sites can be refused or flagged before any user-submitted URL reaches
+/// Chrome. A listed domain also covers its subdomains.
+#[derive(Default)]
+pub struct DomainBlocklist {
+    categories: Vec<(String, BlocklistAction)>,
+    /// Listed domain to indexes into `categories`.
+    domains: HashMap<String, Vec<usize>>,
+}
+
+impl DomainBlocklist {
+    /// Reads every category's list; a list that can't be read is an error
+    /// rather than a silently open door.
+    pub fn load(lists: &BTreeMap<String, BlocklistSettings>) -> anyhow::Result<Self> {
+        let mut blocklist = DomainBlocklist::default();
+        for (category, settings) in lists {
+            let contents = fs::read_to_string(&settings.path).with_context(|| {
+                format!(
+                    "reading the {} blocklist from {}",
+                    category,
+                    settings.path.display()
+                )
+            })?;
+            let index = blocklist.categories.len();
+            blocklist
+                .categories
+                .push((category.clone(), settings.action));
+
+            let mut count = 0;
+            for domain in contents.lines().filter_map(listed_domain) {
+                let indexes = blocklist.domains.entry(domain).or_default();
+                if !indexes.contains(&index) {
+                    indexes.push(index);
+                    count += 1;
+                }
+            }
+            info!("loaded {} domains into the {} blocklist", count, category);
+        }
+        Ok(blocklist)
+    }
+
+    /// The flagged categories `host` is listed under, or `BlockedDomain`
+    /// when a refusing list has it.
+    pub fn check(&self, host: &str) -> Result<Vec<String>, ScrapeError> {
+        if self.domains.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let mut indexes: Vec<usize> = Vec::new();
+        let mut domain = host.as_str();
+        loop {
+            if let Some(listed) = self.domains.get(domain) {
+                indexes.extend(listed);
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) => domain = parent,
+                None => break,
+            }
+        }
+        indexes.sort_unstable();
+        indexes.dedup();
+
+        let mut flagged = Vec::new();
+        for index in indexes {
+            let (category, action) = &self.categories[index];
+            match action {
+                BlocklistAction::Refuse => {
+                    return Err(ScrapeError::BlockedDomain {
+                        category: category.clone(),
+                    })
+                }
+                BlocklistAction::Flag => flagged.push(category.clone()),
+            }
+        }
+        Ok(flagged)
+    }
+}
+
+/// The domain a list line names, if any. Understands plain domain lists,
+/// hosts files and adblock domain rules. Rules with paths or options, and
+/// entries that aren't dotted names (`localhost`, bare addresses), are
+/// skipped.
+fn listed_domain(line: &str) -> Option<String> {
+    let line = line.split('#').next().unwrap_or("").trim();
+    if line.starts_with('!') || line.starts_with('[') {
+        return None;
+    }
+    // hosts files put the address first
+    let entry = line.split_whitespace().last()?;
+    let entry = entry
+        .trim_start_matches("||")
+        .trim_end_matches('^')
+        .trim_start_matches("*.")
+        .trim_end_matches('.')
+        .to_ascii_lowercase();
+
+    let plain = entry
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+    if !plain || !entry.contains('.') || entry.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    Some(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocklist(lists: &[(&str, BlocklistAction, &str)]) -> DomainBlocklist {
+        let mut blocklist = DomainBlocklist::default();
+        for (index, (category, action, contents)) in lists.iter().enumerate() {
+            blocklist.categories.push((category.to_string(), *action));
+            for domain in contents.lines().filter_map(listed_domain) {
+                blocklist.domains.entry(domain).or_default().push(index);
+            }
+        }
+        blocklist
+    }
+
+    #[test]
+    fn reads_plain_hosts_and_adblock_lines() {
+        assert_eq!(
+            listed_domain("example.com"),
+            Some("example.com".to_string())
+        );
+        assert_eq!(
+            listed_domain("0.0.0.0 Ads.Example.com"),
+            Some("ads.example.com".to_string())
+        );
+        assert_eq!(
+            listed_domain("||tracker.example.net^"),
+            Some("tracker.example.net".to_string())
+        );
+        assert_eq!(
+            listed_domain("*.example.org"),
+            Some("example.org".to_string())
+        );
+        assert_eq!(
+            listed_domain("example.com. # trailing dot"),
+            Some("example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn skips_lines_that_name_no_domain() {
+        assert_eq!(listed_domain(""), None);
+        assert_eq!(listed_domain("   "), None);
+        assert_eq!(listed_domain("# comment"), None);
+        assert_eq!(listed_domain("! adblock comment"), None);
+        assert_eq!(listed_domain("[Adblock Plus 2.0]"), None);
+        assert_eq!(listed_domain("127.0.0.1 localhost"), None);
+        assert_eq!(listed_domain("10.0.0.1"), None);
+        assert_eq!(listed_domain("||example.com/ads/*"), None);
+        assert_eq!(listed_domain("||example.com^$third-party"), None);
+    }
+
+    #[test]
+    fn empty_blocklist_allows_everything() {
+        assert_eq!(
+            DomainBlocklist::default().check("example.com").unwrap(),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            DomainBlocklist::default().check("").unwrap(),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn listed_domains_cover_subdomains_but_not_suffixes() {
+        let blocklist = blocklist(&[("malware", BlocklistAction::Refuse, "bad.com\n")]);
+        assert!(blocklist.check("bad.com").is_err());
+        assert!(blocklist.check("www.bad.com").is_err());
+        assert!(blocklist.check("WWW.Bad.Com.").is_err());
+        assert!(blocklist.check("notbad.com").is_ok());
+        assert!(blocklist.check("bad.com.example").is_ok());
+    }
+
+    #[test]
+    fn flagging_lists_name_their_categories() {
+        let blocklist = blocklist(&[
+            ("gambling", BlocklistAction::Flag, "casino.example\n"),
+            (
+                "tracking",
+                BlocklistAction::Flag,
+                "example\ncasino.example\n",
+            ),
+        ]);
+        assert_eq!(
+            blocklist.check("www.casino.example").unwrap(),
+            vec!["gambling".to_string(), "tracking".to_string()]
+        );
+        assert_eq!(blocklist.check("other.org").unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn a_refusing_list_wins_over_flags() {
+        let blocklist = blocklist(&[
+            ("adult", BlocklistAction::Flag, "site.example\n"),
+            ("malware", BlocklistAction::Refuse, "site.example\n"),
+        ]);
+        match blocklist.check("site.example") {
+            Err(ScrapeError::BlockedDomain { category }) => assert_eq!(category, "malware"),
+            other => panic!("expected a refusal, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,516 @@
+use crate::browser_pool::{BrowserPool, TabLease};
+use crate::config::{Config, Timeouts};
+use crate::deterministic;
+use crate::error::{PhaseTimeout, ScrapeError};
+use crate::pipeline::{report_error, run_blocking_phase, Timings};
+use crate::proxy::Proxy;
+use crate::visible_html::visible_html;
+use headless_chrome::protocol::cdp::{Emulation, Network};
+use headless_chrome::{browser::Tab, types::PrintToPdfOptions, Browser};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// Checks out a tab in a browser launched with the request's host overrides
+/// and proxy, and applies its cookies, headers, proxy credentials and
+/// rendering settings. Tabs without credentials also use the pool's asset
+/// cache when it's on.
+#[allow(clippy::too_many_arguments)]
+pub async fn open_tab(
+    pool: &Arc<BrowserPool>,
+    config: &Config,
+    url: &str,
+    host_overrides: &BTreeMap<String, IpAddr>,
+    proxy: Option<&Proxy>,
+    cookies: &[Network::CookieParam],
+    headers: &BTreeMap<String, String>,
+    rendering: Rendering,
+) -> Result<TabLease, ScrapeError> {
+    let protocols = Url::parse(url)
+        .ok()
+        .map(|parsed| config.protocols_for(parsed.host_str().unwrap_or("")))
+        .unwrap_or_default();
+    // a host with a comma or space would smuggle its own rules into the flag
+    if !valid_host_overrides(host_overrides) {
+        return Err(ScrapeError::InvalidHostOverrides);
+    }
+    let launch_args = host_resolver_rules(host_overrides)
+        .into_iter()
+        .chain(proxy.map(Proxy::launch_arg))
+        .chain(protocols.launch_args())
+        .collect();
+    let isolated = has_credentials(cookies, headers);
+    let lease = pool.checkout(launch_args, isolated).await.map_err(|e| {
+        report_error(&e, "launch", url);
+        ScrapeError::LaunchFailed
+    })?;
+
+    // the configured proxy is in every browser's launch arguments
+    let proxy = proxy.or(config.proxy.as_ref()).cloned();
+    let proxy_auth = proxy.as_ref().map_or(false, Proxy::has_credentials);
+    if let Some(proxy) = proxy {
+        on_tab(&lease.tab, move |tab| proxy.authenticate(tab))
+            .await
+            .map_err(|e| {
+                report_error(&e, "proxy", url);
+                ScrapeError::LaunchFailed
+            })?;
+    }
+    // a request with credentials may load assets made for its user, so it
+    // neither reads nor fills the shared cache
+    if !isolated && pool.asset_cache().enabled() {
+        let asset_cache = pool.asset_cache().clone();
+        on_tab(&lease.tab, move |tab| {
+            asset_cache.intercept(tab, proxy_auth)
+        })
+        .await
+        .map_err(|e| {
+            report_error(&e, "asset_cache", url);
+            ScrapeError::LaunchFailed
+        })?;
+    }
+    let (page_url, cookies, headers) = (url.to_string(), cookies.to_vec(), headers.clone());
+    on_tab(&lease.tab, move |tab| {
+        apply_credentials(tab, &page_url, &cookies, &headers)
+    })
+    .await
+    .map_err(|e| {
+        report_error(&e, "credentials", url);
+        ScrapeError::InvalidCredentials
+    })?;
+    on_tab(&lease.tab, move |tab| apply_rendering(tab, rendering))
+        .await
+        .map_err(|e| {
+            report_error(&e, "rendering", url);
+            ScrapeError::LaunchFailed
+        })?;
+    Ok(lease)
+}
+
+/// How a request wants its tab to render pages.
+#[derive(Debug, Clone, Copy)]
+pub struct Rendering {
+    /// See `ScrapeOptions::deterministic`.
+    pub deterministic: bool,
+    /// Off only when the request sets `javascript: false`.
+    pub javascript: bool,
+}
+
+fn apply_rendering(tab: &Tab, rendering: Rendering) -> anyhow::Result<()> {
+    if !rendering.javascript {
+        tab.call_method(Emulation::SetScriptExecutionDisabled { value: true })?;
+    }
+    if rendering.deterministic {
+        deterministic::apply(tab)?;
+    }
+    Ok(())
+}
+
+/// Whether a request brings its own cookies or headers, in which case its
+/// tab gets a private browser context: whatever session the page sets up
+/// must not leak into other requests sharing the browser.
+pub fn has_credentials(
+    cookies: &[Network::CookieParam],
+    headers: &BTreeMap<String, String>,
+) -> bool {
+    !cookies.is_empty() || !headers.is_empty()
+}
+
+/// Sets the request's cookies and extra HTTP headers on `tab` before it
+/// navigates. Cookies without a `url` or `domain` are scoped to `url`; a
+/// `User-Agent` header also changes `navigator.userAgent`.
+fn apply_credentials(
+    tab: &Tab,
+    url: &str,
+    cookies: &[Network::CookieParam],
+    headers: &BTreeMap<String, String>,
+) -> anyhow::Result<()> {
+    if !cookies.is_empty() {
+        let cookies = cookies
+            .iter()
+            .cloned()
+            .map(|cookie| match (&cookie.url, &cookie.domain) {
+                (None, None) => Network::CookieParam {
+                    url: Some(url.to_string()),
+                    ..cookie
+                },
+                _ => cookie,
+            })
+            .collect();
+        tab.call_method(Network::SetCookies { cookies })?;
+    }
+
+    let mut extra_headers = HashMap::new();
+    for (name, value) in headers {
+        if name.eq_ignore_ascii_case("user-agent") {
+            tab.set_user_agent(value, None, None)?;
+        } else {
+            extra_headers.insert(name.as_str(), value.as_str());
+        }
+    }
+    if !extra_headers.is_empty() {
+        tab.set_extra_http_headers(extra_headers)?;
+    }
+    Ok(())
+}
+
+/// Hostnames end up in a comma/space separated Chrome flag.
+pub fn valid_host_overrides(host_overrides: &BTreeMap<String, IpAddr>) -> bool {
+    !host_overrides
+        .keys()
+        .any(|host| host.is_empty() || host.contains(|c: char| c == ',' || c.is_whitespace()))
+}
+
+/// Builds Chrome's `--host-resolver-rules` flag from `host_overrides`.
+fn host_resolver_rules(host_overrides: &BTreeMap<String, IpAddr>) -> Option<String> {
+    if host_overrides.is_empty() {
+        return None;
+    }
+
+    let rules = host_overrides
+        .iter()
+        .map(|(host, ip)| match ip {
+            IpAddr::V4(ip) => format!("MAP {} {}", host, ip),
+            IpAddr::V6(ip) => format!("MAP {} [{}]", host, ip),
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    Some(format!("--host-resolver-rules={}", rules))
+}
+
+/// When a loaded page is ready to be extracted. Every strategy is bounded by
+/// the `wait_ms` timeout, e.g. `{"type": "selector", "selector": "#app p"}`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WaitStrategy {
+    /// The `<body>` element exists; enough for server-rendered pages.
+    #[default]
+    Body,
+    /// An element matching a CSS selector exists.
+    Selector { selector: String },
+    /// A fixed delay after `<body>` appears.
+    Delay { ms: u64 },
+    /// The document has loaded and no new resources were fetched for
+    /// `idle_ms`.
+    NetworkIdle {
+        #[serde(default = "default_idle_ms")]
+        idle_ms: u64,
+    },
+    /// A JS expression (awaited if it's a promise) becomes truthy.
+    Js { expression: String },
+}
+
+fn default_idle_ms() -> u64 {
+    500
+}
+
+/// Loads `url` in `tab` and prints it to PDF.
+pub async fn print_page(
+    url: &str,
+    tab: &Arc<Tab>,
+    wait: &WaitStrategy,
+    pdf_options: PrintToPdfOptions,
+    timeouts: &Timeouts,
+    timings: &mut Timings,
+) -> anyhow::Result<Vec<u8>> {
+    navigate(url, tab, wait, timeouts, timings).await?;
+
+    let print_tab = tab.clone();
+    run_blocking_phase("print_to_pdf", timeouts.print(), timings, move || {
+        print_tab.print_to_pdf(Some(pdf_options))
+    })
+    .await
+}
+
+/// Navigates `tab` to `url` and waits for the page as `wait` says, each
+/// step bounded by its configured timeout.
+pub async fn navigate(
+    url: &str,
+    tab: &Arc<Tab>,
+    wait: &WaitStrategy,
+    timeouts: &Timeouts,
+    timings: &mut Timings,
+) -> anyhow::Result<()> {
+    let nav_tab = tab.clone();
+    let nav_url = url.to_string();
+    run_blocking_phase("navigate", timeouts.navigate(), timings, move || {
+        nav_tab.navigate_to(&nav_url)?;
+        Ok(())
+    })
+    .await?;
+
+    let started = Instant::now();
+    let waited = wait_for_page(tab, wait, timeouts.wait()).await;
+    timings.add("wait", started.elapsed());
+    waited
+}
+
+/// How often network-idle and JS waits re-check the page.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Runs a blocking CDP call on `tab` off the async worker threads.
+pub async fn on_tab<T, F>(tab: &Arc<Tab>, f: F) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Tab) -> anyhow::Result<T> + Send + 'static,
+{
+    let tab = tab.clone();
+    tokio::task::spawn_blocking(move || f(&tab)).await?
+}
+
+/// Evaluates `js` in `tab` for a wait's poll; `None` when it throws or
+/// doesn't answer within `limit`.
+async fn poll_value(
+    tab: &Arc<Tab>,
+    js: &str,
+    await_promise: bool,
+    limit: Duration,
+) -> Option<serde_json::Value> {
+    let js = js.to_string();
+    let evaluated = on_tab(tab, move |tab| Ok(tab.evaluate(&js, await_promise)?));
+    tokio::time::timeout(limit, evaluated)
+        .await
+        .ok()?
+        .ok()?
+        .value
+}
+
+async fn wait_for_page(tab: &Arc<Tab>, wait: &WaitStrategy, limit: Duration) -> anyhow::Result<()> {
+    let timed_out = || {
+        anyhow::Error::from(PhaseTimeout {
+            phase: "wait",
+            limit,
+        })
+    };
+    let wait_for_element = |selector: &str| {
+        let selector = selector.to_string();
+        on_tab(tab, move |tab| {
+            tab.wait_for_element_with_custom_timeout(&selector, limit)?;
+            Ok(())
+        })
+    };
+    let started = Instant::now();
+
+    match wait {
+        WaitStrategy::Body => {
+            wait_for_element("body").await.map_err(|_| timed_out())?;
+        }
+        WaitStrategy::Selector { selector } => {
+            wait_for_element(selector).await.map_err(|_| timed_out())?;
+        }
+        WaitStrategy::Delay { ms } => {
+            wait_for_element("body").await.map_err(|_| timed_out())?;
+            let remaining = limit.saturating_sub(started.elapsed());
+            tokio::time::sleep(Duration::from_millis(*ms).min(remaining)).await;
+        }
+        WaitStrategy::NetworkIdle { idle_ms } => {
+            // -1 until the document has loaded, then the number of
+            // resources fetched so far
+            let js = r#"
+                document.readyState === "complete"
+                    ? performance.getEntriesByType("resource").length
+                    : -1
+            "#;
+            let idle = Duration::from_millis(*idle_ms);
+            let mut last_count = -1.0;
+            let mut last_change = Instant::now();
+            loop {
+                let remaining = limit.saturating_sub(started.elapsed());
+                let count = poll_value(tab, js, false, remaining)
+                    .await
+                    .and_then(|v| v.as_f64())
+                    .unwrap_or(-1.0);
+                if count != last_count {
+                    last_count = count;
+                    last_change = Instant::now();
+                } else if count >= 0.0 && last_change.elapsed() >= idle {
+                    break;
+                }
+                if started.elapsed() >= limit {
+                    return Err(timed_out());
+                }
+                tokio::time::sleep(WAIT_POLL_INTERVAL).await;
+            }
+        }
+        WaitStrategy::Js { expression } => {
+            // awaited inside the function, so a promise counts by what it
+            // resolves to rather than by being an object
+            let js = format!("(async () => !!(await ({})))()", expression);
+            loop {
+                let remaining = limit.saturating_sub(started.elapsed());
+                let truthy = poll_value(tab, &js, true, remaining)
+                    .await
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false);
+                if truthy {
+                    break;
+                }
+                if started.elapsed() >= limit {
+                    return Err(timed_out());
+                }
+                tokio::time::sleep(WAIT_POLL_INTERVAL).await;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Loads `url` in `tab` and returns its rendered HTML, without the
+/// elements the reader can't see.
+pub async fn get_html_headless(
+    url: &str,
+    tab: &Arc<Tab>,
+    wait: &WaitStrategy,
+    timeouts: &Timeouts,
+    timings: &mut Timings,
+) -> anyhow::Result<String> {
+    navigate(url, tab, wait, timeouts, timings).await?;
+    on_tab(tab, visible_html).await
+}
+
+/// The `href` of every link on the page the tab shows, resolved against it.
+/// Empty when the page can't be read.
+pub async fn page_links(tab: &Arc<Tab>) -> Vec<String> {
+    let js = "JSON.stringify(Array.from(document.links, (a) => a.href))";
+    on_tab(tab, move |tab| Ok(tab.evaluate(js, false)?.value))
+        .await
+        .ok()
+        .flatten()
+        .and_then(|v| v.as_str().and_then(|s| serde_json::from_str(s).ok()))
+        .unwrap_or_default()
+}
+
+/// Reads the visible text of the page already loaded in `tab` by selecting
+/// the whole body, falling back to `innerText`. Both respect CSS visibility,
+/// so this skips hidden markup that ends up in `get_content()`.
+pub async fn get_inner_text_headless(tab: &Arc<Tab>) -> anyhow::Result<String> {
+    let js = r#"
+        (() => {
+            const selection = window.getSelection();
+            selection.removeAllRanges();
+            selection.selectAllChildren(document.body);
+            const text = selection.toString();
+            selection.removeAllRanges();
+            return text || document.body.innerText || "";
+        })()
+    "#;
+
+    let text = on_tab(tab, move |tab| Ok(tab.evaluate(js, false)?.value))
+        .await?
+        .and_then(|v| v.as_str().map(|s| s.to_string()))
+        .unwrap_or_default();
+
+    Ok(text)
+}
+
+/// Looks for tabs the page in `tab` opened while loading (OAuth walls,
+/// interstitials, "continue in new window" links), logs them and closes
+/// them. Returns the most recently opened popup's URL when `follow` is set,
+/// so the caller can scrape the intended target instead of the opener.
+/// Only tabs whose opener is `tab` count, since the browser is shared with
+/// other requests.
+pub async fn handle_popups(
+    browser: &Browser,
+    tab: &Arc<Tab>,
+    url: &str,
+    follow: bool,
+) -> Option<String> {
+    let (browser, url) = (browser.clone(), url.to_string());
+    on_tab(tab, move |tab| {
+        Ok(close_popups(&browser, tab, &url, follow))
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+fn close_popups(browser: &Browser, tab: &Tab, url: &str, follow: bool) -> Option<String> {
+    let candidates: Vec<Arc<Tab>> = browser
+        .get_tabs()
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|other| other.get_target_id() != tab.get_target_id())
+        .cloned()
+        .collect();
+    let popups: Vec<Arc<Tab>> = candidates
+        .into_iter()
+        .filter(|other| {
+            other.get_target_info().map_or(false, |info| {
+                info.opener_id.as_ref() == Some(tab.get_target_id())
+            })
+        })
+        .collect();
+    if popups.is_empty() {
+        return None;
+    }
+
+    let popup_urls: Vec<String> = popups.iter().map(|popup| popup.get_url()).collect();
+    info!("{} opened new tabs: {:?}", url, popup_urls);
+
+    for popup in &popups {
+        let _ = popup.close(false);
+    }
+
+    if follow {
+        popup_urls
+            .into_iter()
+            .rev()
+            .find(|popup_url| Url::parse(popup_url).is_ok() && popup_url.starts_with("http"))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overrides(entries: &[(&str, &str)]) -> BTreeMap<String, IpAddr> {
+        entries
+            .iter()
+            .map(|(host, ip)| (host.to_string(), ip.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn rules_map_each_host_and_bracket_ipv6() {
+        assert_eq!(host_resolver_rules(&BTreeMap::new()), None);
+        assert_eq!(
+            host_resolver_rules(&overrides(&[
+                ("a.example", "10.0.0.1"),
+                ("b.example", "2001:db8::1")
+            ]))
+            .as_deref(),
+            Some("--host-resolver-rules=MAP a.example 10.0.0.1, MAP b.example [2001:db8::1]")
+        );
+    }
+
+    #[test]
+    fn hosts_that_would_break_the_flag_are_invalid() {
+        assert!(valid_host_overrides(&BTreeMap::new()));
+        assert!(valid_host_overrides(&overrides(&[(
+            "*.example",
+            "10.0.0.1"
+        )])));
+        for host in [
+            "",
+            "a.example,MAP * 10.0.0.2",
+            "a.example MAP",
+            "a.example\tb",
+            "a.example\n",
+        ] {
+            assert!(
+                !valid_host_overrides(&overrides(&[(host, "10.0.0.1")])),
+                "{:?}",
+                host
+            );
+        }
+    }
+}
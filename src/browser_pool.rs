@@ -0,0 +1,417 @@
+use crate::asset_cache::AssetCache;
+use crate::config::PoolSettings;
+use crate::container::ChromeEnvironment;
+use crate::metrics::metrics;
+use headless_chrome::protocol::cdp::Target;
+use headless_chrome::{browser::Tab, Browser, LaunchOptions};
+use log::{debug, error, warn};
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Window size every pooled browser is launched with.
+pub const WINDOW_SIZE: (u32, u32) = (820, 1180);
+
+/// How long `launch_health` waits for a retried launch.
+const LAUNCH_PROBE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Keeps warm Chrome instances around and hands out tabs in them, instead of
+/// launching a browser for every request.
+///
+/// Browsers are partitioned by their extra launch arguments (for example
+/// host-resolver rules): a tab is only ever opened in a browser launched with
+/// exactly the arguments the request needs. At most `max_browsers` run at
+/// once; when the pool is full, an idle browser from another partition is
+/// replaced, or the request waits for a tab to be returned.
+pub struct BrowserPool {
+    chrome_path: Option<PathBuf>,
+    environment: ChromeEnvironment,
+    settings: PoolSettings,
+    state: Mutex<PoolState>,
+    released: Notify,
+    maintenance: Once,
+    asset_cache: Arc<AssetCache>,
+}
+
+#[derive(Default)]
+struct PoolState {
+    browsers: Vec<PooledBrowser>,
+    /// Browsers being launched; they count towards `max_browsers`.
+    launching: usize,
+    next_id: u64,
+    /// Why the last launch failed; cleared by the next one that succeeds.
+    launch_error: Option<String>,
+}
+
+struct PooledBrowser {
+    id: u64,
+    browser: Browser,
+    launch_args: Vec<String>,
+    active_tabs: usize,
+    /// Tabs ever checked out of it, so a lease can tell whether another
+    /// one overlapped it.
+    checkouts: u64,
+}
+
+enum Reservation {
+    Existing(u64, Browser),
+    Launch(u64),
+    Full,
+}
+
+/// A tab checked out of the pool. Dropping it closes the tab and returns its
+/// slot to the browser it was opened in.
+pub struct TabLease {
+    pool: Arc<BrowserPool>,
+    browser_id: u64,
+    pub browser: Browser,
+    pub tab: Arc<Tab>,
+    /// The private browser context the tab was opened in, if any; it's
+    /// disposed together with the tab.
+    context_id: Option<String>,
+    /// The browser's `checkouts` when this was its only tab at checkout.
+    alone_since: Option<u64>,
+}
+
+impl TabLease {
+    /// Whether no other tab has used the browser since this one was checked
+    /// out, so everything the browser did in that time was for this lease.
+    pub fn had_browser_alone(&self) -> bool {
+        self.alone_since.map_or(false, |since| {
+            self.pool
+                .state
+                .lock()
+                .unwrap()
+                .browsers
+                .iter()
+                .any(|b| b.id == self.browser_id && b.checkouts == since)
+        })
+    }
+}
+
+impl Drop for TabLease {
+    fn drop(&mut self) {
+        let tab = self.tab.clone();
+        let browser = self.browser.clone();
+        let context_id = self.context_id.take();
+        tokio::task::spawn_blocking(move || {
+            let _ = tab.close(false);
+            if let Some(browser_context_id) = context_id {
+                let _ = browser.call_method(Target::DisposeBrowserContext { browser_context_id });
+            }
+        });
+        self.pool.release(self.browser_id);
+    }
+}
+
+impl BrowserPool {
+    pub fn new(
+        chrome_path: Option<PathBuf>,
+        environment: ChromeEnvironment,
+        settings: PoolSettings,
+    ) -> Arc<Self> {
+        Arc::new(BrowserPool {
+            chrome_path,
+            environment,
+            asset_cache: Arc::new(AssetCache::new(settings.asset_cache.clone())),
+            settings,
+            state: Mutex::new(PoolState::default()),
+            released: Notify::new(),
+            maintenance: Once::new(),
+        })
+    }
+
+    /// Scripts, stylesheets and fonts shared by the pool's tabs.
+    pub fn asset_cache(&self) -> &Arc<AssetCache> {
+        &self.asset_cache
+    }
+
+    /// Opens a tab in a browser launched with `launch_args`, reusing a warm
+    /// browser when one has room and launching one otherwise. An `isolated`
+    /// tab gets a browser context of its own, so cookies set in it (or by
+    /// the pages it loads) never reach other requests sharing the browser.
+    pub async fn checkout(
+        self: &Arc<Self>,
+        launch_args: Vec<String>,
+        isolated: bool,
+    ) -> anyhow::Result<TabLease> {
+        self.spawn_maintenance();
+        loop {
+            match self.reserve(&launch_args) {
+                Reservation::Existing(id, browser) => {
+                    let tab_browser = browser.clone();
+                    match tokio::task::spawn_blocking(move || open_tab(&tab_browser, isolated))
+                        .await?
+                    {
+                        Ok((tab, context_id)) => {
+                            return Ok(self.lease(id, browser, tab, context_id))
+                        }
+                        Err(e) => {
+                            // most likely the browser crashed; drop it and retry
+                            warn!("discarding browser {}: {}", id, e);
+                            self.remove(id);
+                            metrics().browser_restarts.inc();
+                        }
+                    }
+                }
+                Reservation::Launch(id) => {
+                    let slot = LaunchSlot { pool: self };
+                    let browser = self.launch(id, launch_args.clone()).await?;
+                    let tab_browser = browser.clone();
+                    let (tab, context_id) =
+                        tokio::task::spawn_blocking(move || open_tab(&tab_browser, isolated))
+                            .await??;
+                    slot.fill(PooledBrowser {
+                        id,
+                        browser: browser.clone(),
+                        launch_args,
+                        active_tabs: 1,
+                        checkouts: 1,
+                    });
+                    return Ok(self.lease(id, browser, tab, context_id));
+                }
+                Reservation::Full => self.released.notified().await,
+            }
+        }
+    }
+
+    /// Number of running browsers and of tabs currently checked out.
+    pub fn status(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        let tabs = state.browsers.iter().map(|b| b.active_tabs).sum();
+        (state.browsers.len(), tabs)
+    }
+
+    /// Why Chrome can't be used, or `None` when it can: a browser is running
+    /// or none has failed to launch (lazily started pools may not have
+    /// launched any yet). When the last launch failed and no browser is
+    /// running, launching is retried here, so a recovered host is noticed
+    /// without waiting for a request.
+    pub async fn launch_health(self: &Arc<Self>) -> Option<String> {
+        let failure = {
+            let state = self.state.lock().unwrap();
+            if !state.browsers.is_empty() {
+                return None;
+            }
+            state.launch_error.clone()?
+        };
+        match tokio::time::timeout(LAUNCH_PROBE_TIMEOUT, self.checkout(Vec::new(), false)).await {
+            Ok(Ok(_lease)) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(_) => Some(failure),
+        }
+    }
+
+    /// Periodically replaces idle browsers that stopped responding and tops
+    /// the default partition up to `min_browsers`. Only the first call
+    /// starts anything; `checkout` calls it too, for lazily started pools.
+    pub fn spawn_maintenance(self: &Arc<Self>) {
+        self.maintenance.call_once(|| {
+            let pool = self.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(pool.settings.health_check_interval());
+                loop {
+                    interval.tick().await;
+                    pool.check_health().await;
+                    pool.warm_up().await;
+                }
+            });
+        });
+    }
+
+    fn reserve(&self, launch_args: &[String]) -> Reservation {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(pooled) = state.browsers.iter_mut().find(|b| {
+            b.launch_args == launch_args && b.active_tabs < self.settings.max_tabs_per_browser
+        }) {
+            pooled.active_tabs += 1;
+            pooled.checkouts += 1;
+            return Reservation::Existing(pooled.id, pooled.browser.clone());
+        }
+
+        if state.browsers.len() + state.launching >= self.settings.max_browsers {
+            let idle_elsewhere = state
+                .browsers
+                .iter()
+                .position(|b| b.active_tabs == 0 && b.launch_args != launch_args);
+            match idle_elsewhere {
+                Some(index) => {
+                    state.browsers.remove(index);
+                }
+                None => return Reservation::Full,
+            }
+        }
+
+        state.launching += 1;
+        state.next_id += 1;
+        Reservation::Launch(state.next_id)
+    }
+
+    async fn launch(&self, id: u64, launch_args: Vec<String>) -> anyhow::Result<Browser> {
+        let chrome_path = self.chrome_path.clone();
+        let sandbox = self.environment.sandbox;
+        let mut args = self.environment.extra_args.clone();
+        args.extend(launch_args);
+        debug!("launching browser {} with args {:?}", id, args);
+
+        let launched = tokio::task::spawn_blocking(move || {
+            let options = LaunchOptions {
+                headless: true,
+                sandbox,
+                args: args.iter().map(OsStr::new).collect(),
+                window_size: Some(WINDOW_SIZE),
+                path: chrome_path,
+                // pooled browsers sit idle between requests
+                idle_browser_timeout: Duration::from_secs(60 * 60 * 24 * 365),
+                ..Default::default()
+            };
+            Browser::new(options)
+        })
+        .await?;
+        self.state.lock().unwrap().launch_error = launched.as_ref().err().map(|e| e.to_string());
+        launched
+    }
+
+    fn lease(
+        self: &Arc<Self>,
+        browser_id: u64,
+        browser: Browser,
+        tab: Arc<Tab>,
+        context_id: Option<String>,
+    ) -> TabLease {
+        let alone_since = self
+            .state
+            .lock()
+            .unwrap()
+            .browsers
+            .iter()
+            .find(|b| b.id == browser_id && b.active_tabs == 1)
+            .map(|b| b.checkouts);
+        TabLease {
+            pool: self.clone(),
+            browser_id,
+            browser,
+            tab,
+            context_id,
+            alone_since,
+        }
+    }
+
+    fn release(&self, browser_id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(pooled) = state.browsers.iter_mut().find(|b| b.id == browser_id) {
+            pooled.active_tabs = pooled.active_tabs.saturating_sub(1);
+        }
+        drop(state);
+        self.released.notify_one();
+    }
+
+    fn remove(&self, browser_id: u64) {
+        self.state
+            .lock()
+            .unwrap()
+            .browsers
+            .retain(|b| b.id != browser_id);
+        self.released.notify_one();
+    }
+
+    async fn check_health(&self) {
+        let idle: Vec<(u64, Browser)> = self
+            .state
+            .lock()
+            .unwrap()
+            .browsers
+            .iter()
+            .filter(|b| b.active_tabs == 0)
+            .map(|b| (b.id, b.browser.clone()))
+            .collect();
+
+        for (id, browser) in idle {
+            let alive = tokio::task::spawn_blocking(move || browser.get_version().is_ok())
+                .await
+                .unwrap_or(false);
+            if !alive {
+                warn!("browser {} failed its health check, restarting", id);
+                self.remove(id);
+                metrics().browser_restarts.inc();
+            }
+        }
+    }
+
+    async fn warm_up(self: &Arc<Self>) {
+        let missing = {
+            let state = self.state.lock().unwrap();
+            let default_browsers = state
+                .browsers
+                .iter()
+                .filter(|b| b.launch_args.is_empty())
+                .count();
+            self.settings.min_browsers.saturating_sub(default_browsers)
+        };
+
+        for _ in 0..missing {
+            let id = {
+                let mut state = self.state.lock().unwrap();
+                if state.browsers.len() + state.launching >= self.settings.max_browsers {
+                    return;
+                }
+                state.launching += 1;
+                state.next_id += 1;
+                state.next_id
+            };
+
+            let slot = LaunchSlot { pool: self };
+            match self.launch(id, Vec::new()).await {
+                Ok(browser) => slot.fill(PooledBrowser {
+                    id,
+                    browser,
+                    launch_args: Vec::new(),
+                    active_tabs: 0,
+                    checkouts: 0,
+                }),
+                Err(e) => error!("failed to warm up browser pool: {}", e),
+            }
+        }
+    }
+}
+
+/// Opens a tab in `browser`, in a new browser context when `isolated`.
+/// Returns the context's id alongside the tab.
+fn open_tab(browser: &Browser, isolated: bool) -> anyhow::Result<(Arc<Tab>, Option<String>)> {
+    if !isolated {
+        return Ok((browser.new_tab()?, None));
+    }
+    let context = browser.new_context()?;
+    let context_id = context.get_id().to_string();
+    match context.new_tab() {
+        Ok(tab) => Ok((tab, Some(context_id))),
+        Err(e) => {
+            let _ = browser.call_method(Target::DisposeBrowserContext {
+                browser_context_id: context_id,
+            });
+            Err(e)
+        }
+    }
+}
+
+/// A reserved `launching` slot. Released when dropped, so a failed or
+/// cancelled launch doesn't permanently eat into `max_browsers`.
+struct LaunchSlot<'a> {
+    pool: &'a BrowserPool,
+}
+
+impl LaunchSlot<'_> {
+    fn fill(self, browser: PooledBrowser) {
+        self.pool.state.lock().unwrap().browsers.push(browser);
+    }
+}
+
+impl Drop for LaunchSlot<'_> {
+    fn drop(&mut self) {
+        self.pool.state.lock().unwrap().launching -= 1;
+        self.pool.released.notify_one();
+    }
+}
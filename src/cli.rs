@@ -0,0 +1,231 @@
+use anyhow::anyhow;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use headless_chrome::types::PrintToPdfOptions;
+use scrape_web_by_virtual_printing::browser::{open_tab, print_page, Rendering};
+use scrape_web_by_virtual_printing::browser_pool::BrowserPool;
+use scrape_web_by_virtual_printing::config::Config;
+use scrape_web_by_virtual_printing::container::ChromeEnvironment;
+use scrape_web_by_virtual_printing::diagnostics::DiagnosticsStore;
+use scrape_web_by_virtual_printing::extractor_cache::ExtractorCache;
+use scrape_web_by_virtual_printing::pdf_extract::PaperPreset;
+use scrape_web_by_virtual_printing::pipeline::{
+    self, ExtractionMode, OutputFormat, ScrapeOptions, Timings,
+};
+use scrape_web_by_virtual_printing::post_process::PostProcessRules;
+use scrape_web_by_virtual_printing::readability_extract::sanitize_html;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::{fs, str::FromStr};
+use url::Url;
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Scrapes web pages by printing them in headless Chrome"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Serve the HTTP API (what runs without a subcommand).
+    Serve,
+    /// Check that Chrome, pdfium and Readability work on this host.
+    Doctor,
+    /// Scrape one page without the HTTP server, e.g. from scripts and cron.
+    Scrape(ScrapeArgs),
+}
+
+/// Options of `scrape`; the rest come from the service configuration.
+#[derive(Args)]
+pub struct ScrapeArgs {
+    url: String,
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+    /// Which extraction path produces the text.
+    #[arg(long, value_enum, default_value_t = Mode::Auto)]
+    mode: Mode,
+    /// Write the result to this file instead of standard output.
+    #[arg(long)]
+    out: Option<PathBuf>,
+    /// Overall deadline for the scrape.
+    #[arg(long)]
+    timeout_ms: Option<u64>,
+    /// Overrides `timeouts.navigate_ms`.
+    #[arg(long)]
+    navigate_ms: Option<u64>,
+    /// Overrides `timeouts.wait_ms`.
+    #[arg(long)]
+    wait_ms: Option<u64>,
+    /// Overrides `timeouts.print_ms`.
+    #[arg(long)]
+    print_ms: Option<u64>,
+    /// Printed pages to use, e.g. `1-5, 8`.
+    #[arg(long)]
+    page_ranges: Option<String>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Text,
+    Html,
+    Markdown,
+    /// The page as printed, before any text is extracted from it.
+    Pdf,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Mode {
+    Auto,
+    Pdf,
+    Readability,
+    InnerText,
+    RawHtml,
+}
+
+/// Runs `scrape` and writes its result, or reports why it failed on
+/// standard error. Returns whether it succeeded.
+pub async fn scrape(args: ScrapeArgs) -> bool {
+    match run(args).await {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("scrape failed: {:#}", e);
+            false
+        }
+    }
+}
+
+async fn run(args: ScrapeArgs) -> anyhow::Result<()> {
+    let url = Url::from_str(&args.url)?;
+    let mut config = Config::load()?;
+    if let Some(ms) = args.navigate_ms {
+        config.timeouts.navigate_ms = ms;
+    }
+    if let Some(ms) = args.wait_ms {
+        config.timeouts.wait_ms = ms;
+    }
+    if let Some(ms) = args.print_ms {
+        config.timeouts.print_ms = ms;
+    }
+    if let Some(ms) = args.timeout_ms {
+        config.request_timeout_ms = ms;
+    }
+
+    // one page needs one browser, launched on checkout
+    let mut pool_settings = config.pool.clone();
+    pool_settings.min_browsers = 0;
+    let pool = BrowserPool::new(
+        config.chrome_path.clone(),
+        ChromeEnvironment::detect(&config),
+        pool_settings,
+    );
+
+    let output = match args.format {
+        Format::Pdf => print(&url, &args, &config, &pool).await?,
+        Format::Text | Format::Html | Format::Markdown => {
+            let options = ScrapeOptions {
+                format: match args.format {
+                    Format::Html => OutputFormat::Html,
+                    Format::Markdown => OutputFormat::Markdown,
+                    _ => OutputFormat::Text,
+                },
+                mode: match args.mode {
+                    Mode::Auto => ExtractionMode::Auto,
+                    Mode::Pdf => ExtractionMode::Pdf,
+                    Mode::Readability => ExtractionMode::Readability,
+                    Mode::InnerText => ExtractionMode::InnerText,
+                    Mode::RawHtml => ExtractionMode::RawHtml,
+                },
+                page_ranges: args.page_ranges.clone(),
+                ..Default::default()
+            };
+            let extractors = Arc::new(ExtractorCache::new(config.extractor_cache_ttl()));
+            let diagnostics = Arc::new(DiagnosticsStore::new(config.diagnostics.clone()));
+            let scraped = pipeline::scrape(
+                url.to_string(),
+                options.clone(),
+                Arc::new(config),
+                pool,
+                extractors,
+                diagnostics.clone(),
+            )
+            .await
+            .map_err(|e| {
+                // there's no admin API to fetch it from here
+                for bundle in diagnostics.get(None) {
+                    eprintln!("{}", serde_json::to_string_pretty(&bundle).unwrap());
+                }
+                anyhow!("{}", e)
+            })?;
+
+            // the same clean-up `/api` does
+            let raw = options.mode == ExtractionMode::RawHtml;
+            let text = match options.format {
+                OutputFormat::Text if !raw => {
+                    PostProcessRules::from_env()?.apply(url.host_str().unwrap_or(""), &scraped.text)
+                }
+                OutputFormat::Html if !raw => sanitize_html(&scraped.text, None),
+                _ => scraped.text,
+            };
+            text.into_bytes()
+        }
+    };
+
+    match &args.out {
+        Some(path) => fs::write(path, output)?,
+        None => io::stdout().write_all(&output)?,
+    }
+    Ok(())
+}
+
+/// The page printed to PDF the way the PDF extraction path prints it.
+async fn print(
+    url: &Url,
+    args: &ScrapeArgs,
+    config: &Config,
+    pool: &Arc<BrowserPool>,
+) -> anyhow::Result<Vec<u8>> {
+    let lease = open_tab(
+        pool,
+        config,
+        url.as_str(),
+        &BTreeMap::new(),
+        None,
+        &[],
+        &BTreeMap::new(),
+        Rendering {
+            deterministic: false,
+            javascript: true,
+        },
+    )
+    .await
+    .map_err(|e| anyhow!("{}", e))?;
+
+    let (paper_width, paper_height) = PaperPreset::default().size_inches();
+    let pdf_options = PrintToPdfOptions {
+        paper_width: Some(paper_width),
+        paper_height: Some(paper_height),
+        margin_top: Some(0.1),
+        margin_bottom: Some(0.1),
+        margin_left: Some(0.1),
+        margin_right: Some(0.1),
+        page_ranges: args.page_ranges.clone(),
+        ignore_invalid_page_ranges: Some(true),
+        ..Default::default()
+    };
+    let printing = print_page(
+        url.as_str(),
+        &lease.tab,
+        &Default::default(),
+        pdf_options,
+        &config.timeouts,
+        &mut Timings::default(),
+    );
+    tokio::time::timeout(config.request_timeout(), printing)
+        .await
+        .map_err(|_| anyhow!("gave up after {:?}", config.request_timeout()))?
+}
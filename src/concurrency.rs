@@ -0,0 +1,79 @@
+use crate::config::ConcurrencySettings;
+use crate::error::ScrapeError;
+use axum::{
+    extract::State,
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// Caps how many API requests are worked on at once. Requests beyond that
+/// wait in a bounded queue; once the queue is full they're turned away with
+/// 429 and `Retry-After` instead of piling more Chrome work onto the host.
+pub struct ConcurrencyLimit {
+    settings: ConcurrencySettings,
+    permits: Semaphore,
+    queued: AtomicUsize,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(settings: ConcurrencySettings) -> Self {
+        ConcurrencyLimit {
+            permits: Semaphore::new(settings.max_concurrent_requests.max(1)),
+            queued: AtomicUsize::new(0),
+            settings,
+        }
+    }
+
+    /// Requests being worked on and requests waiting for a slot.
+    pub fn status(&self) -> (usize, usize) {
+        let permits = self.settings.max_concurrent_requests.max(1);
+        (
+            permits - self.permits.available_permits(),
+            self.queued.load(Ordering::SeqCst),
+        )
+    }
+
+    /// Whether new requests are being turned away.
+    pub fn is_saturated(&self) -> bool {
+        self.permits.available_permits() == 0
+            && self.queued.load(Ordering::SeqCst) >= self.settings.max_queued_requests
+    }
+}
+
+/// Middleware enforcing a `ConcurrencyLimit`.
+pub async fn limit_concurrency<B>(
+    State(limit): State<Arc<ConcurrencyLimit>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let _permit = match limit.permits.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => {
+            if limit.queued.fetch_add(1, Ordering::SeqCst) >= limit.settings.max_queued_requests {
+                limit.queued.fetch_sub(1, Ordering::SeqCst);
+                return ScrapeError::QueueFull {
+                    retry_after: limit.settings.retry_after(),
+                }
+                .into_response();
+            }
+            let _queued = QueueSlot(&limit.queued);
+            limit.permits.acquire().await.unwrap()
+        }
+    };
+
+    next.run(req).await
+}
+
+/// A place in the queue, given up when the request gets a permit or goes
+/// away while waiting.
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::config::Config;
+use log::{info, warn};
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Below this `/dev/shm` size Chrome's renderers crash on larger pages;
+/// Docker's default is 64 MiB.
+const MIN_SHM_BYTES: u64 = 512 * 1024 * 1024;
+
+/// How Chrome has to be launched on this host. Minimal containers commonly
+/// run as root (where Chrome refuses to start with its sandbox) and have a
+/// tiny `/dev/shm`; both otherwise fail with nothing more helpful than a
+/// browser that never comes up.
+#[derive(Debug, Clone)]
+pub struct ChromeEnvironment {
+    pub sandbox: bool,
+    /// Added to every browser's launch arguments.
+    pub extra_args: Vec<String>,
+}
+
+impl ChromeEnvironment {
+    /// Inspects the host, applies `chrome_sandbox` / `chrome_disable_dev_shm`
+    /// from the config where set, and prints what was decided and why.
+    pub fn detect(config: &Config) -> Self {
+        let in_container = in_container();
+        let root = running_as_root();
+        let shm = shm_size();
+        info!(
+            "environment: container={} root={} /dev/shm={}",
+            in_container,
+            root,
+            shm.map_or("unknown".to_string(), |bytes| format!(
+                "{} MiB",
+                bytes / 1024 / 1024
+            ))
+        );
+
+        let sandbox = config.chrome_sandbox.unwrap_or(!root);
+        if !sandbox {
+            warn!(
+                "launching Chrome with --no-sandbox{}",
+                if config.chrome_sandbox.is_some() {
+                    " (chrome_sandbox = false)"
+                } else {
+                    ": running as root, where Chrome's sandbox can't start; \
+                     run as an unprivileged user to keep it"
+                }
+            );
+        } else if in_container {
+            info!(
+                "Chrome's sandbox is on inside a container; if browsers fail to \
+                 launch, allow user namespaces or set chrome_sandbox = false"
+            );
+        }
+
+        let small_shm = shm.map_or(false, |bytes| bytes < MIN_SHM_BYTES);
+        let mut extra_args = Vec::new();
+        if config.chrome_disable_dev_shm.unwrap_or(small_shm) {
+            if small_shm {
+                warn!(
+                    "/dev/shm is smaller than {} MiB, so Chrome will use /tmp \
+                     instead (--disable-dev-shm-usage); give the container a \
+                     larger --shm-size to avoid this",
+                    MIN_SHM_BYTES / 1024 / 1024
+                );
+            }
+            extra_args.push("--disable-dev-shm-usage".to_string());
+        }
+        // a request's own proxy comes later in the arguments, and Chrome
+        // uses the last --proxy-server it's given
+        if let Some(proxy) = &config.proxy {
+            info!("launching Chrome with {}", proxy.launch_arg());
+            extra_args.push(proxy.launch_arg());
+        }
+
+        ChromeEnvironment {
+            sandbox,
+            extra_args,
+        }
+    }
+}
+
+fn in_container() -> bool {
+    if env::var_os("container").is_some()
+        || Path::new("/.dockerenv").exists()
+        || Path::new("/run/.containerenv").exists()
+    {
+        return true;
+    }
+    fs::read_to_string("/proc/1/cgroup").map_or(false, |cgroup| {
+        ["docker", "kubepods", "containerd", "lxc", "podman"]
+            .iter()
+            .any(|runtime| cgroup.contains(runtime))
+    })
+}
+
+fn running_as_root() -> bool {
+    fs::read_to_string("/proc/self/status").map_or(false, |status| {
+        status
+            .lines()
+            .find(|line| line.starts_with("Uid:"))
+            .and_then(|line| line.split_whitespace().nth(2))
+            == Some("0")
+    })
+}
+
+/// Size of the `/dev/shm` tmpfs from its mount options, when it has one.
+fn shm_size() -> Option<u64> {
+    let mounts = fs::read_to_string("/proc/mounts").ok()?;
+    let options = mounts
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+        .find(|fields| fields.get(1) == Some(&"/dev/shm"))?
+        .get(3)?
+        .to_string();
+    let size = options
+        .split(',')
+        .find_map(|option| option.strip_prefix("size="))?;
+
+    let (digits, unit) = size.split_at(size.trim_end_matches(char::is_alphabetic).len());
+    let value: u64 = digits.parse().ok()?;
+    let multiplier = match unit.to_ascii_lowercase().as_str() {
+        "" => 1,
+        "k" => 1024,
+        "m" => 1024 * 1024,
+        "g" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    Some(value * multiplier)
+}
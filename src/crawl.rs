@@ -0,0 +1,188 @@
+use anyhow::bail;
+use http_req::{request::Request, uri::Uri};
+use log::debug;
+use regex::Regex;
+use std::convert::TryFrom;
+use std::time::Duration;
+use url::Url;
+
+/// Fetching one sitemap, which is plain HTTP without a browser.
+const SITEMAP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which discovered links a crawl follows: http(s) pages on the seed's host
+/// whose URL matches one of `include` (if any are given) and none of
+/// `exclude`.
+pub struct CrawlScope {
+    host: String,
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl CrawlScope {
+    pub fn new(seed: &Url, include: &[String], exclude: &[String]) -> Result<Self, regex::Error> {
+        Ok(CrawlScope {
+            host: seed.host_str().unwrap_or_default().to_string(),
+            include: include
+                .iter()
+                .map(|pattern| Regex::new(pattern))
+                .collect::<Result<_, _>>()?,
+            exclude: exclude
+                .iter()
+                .map(|pattern| Regex::new(pattern))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn allows(&self, url: &Url) -> bool {
+        matches!(url.scheme(), "http" | "https")
+            && url.host_str() == Some(self.host.as_str())
+            && (self.include.is_empty() || self.include.iter().any(|re| re.is_match(url.as_str())))
+            && !self.exclude.iter().any(|re| re.is_match(url.as_str()))
+    }
+}
+
+/// Page URLs listed in the site's `/sitemap.xml`, including those of the
+/// sitemaps a sitemap index points to on the seed's own host; an index
+/// naming other hosts would otherwise have the crawler fetch arbitrary URLs.
+/// This blocks on network I/O; call it from `spawn_blocking`.
+pub fn sitemap_urls(seed: &Url) -> anyhow::Result<Vec<Url>> {
+    let mut pages = Vec::new();
+    for loc in locs(&fetch(&seed.join("/sitemap.xml")?)?) {
+        let url = match Url::parse(&loc) {
+            Ok(url) => url,
+            Err(_) => continue,
+        };
+        if !url.path().ends_with(".xml") {
+            pages.push(url);
+            continue;
+        }
+        // a sitemap index; one level deep is as far as sites go in practice
+        if !matches!(url.scheme(), "http" | "https") || url.host_str() != seed.host_str() {
+            debug!("skipping sitemap {} off {}", url, seed);
+            continue;
+        }
+        match fetch(&url) {
+            Ok(sitemap) => {
+                pages.extend(locs(&sitemap).iter().filter_map(|loc| Url::parse(loc).ok()))
+            }
+            Err(e) => debug!("skipping sitemap {}: {}", url, e),
+        }
+    }
+    Ok(pages)
+}
+
+fn fetch(url: &Url) -> anyhow::Result<String> {
+    let uri = Uri::try_from(url.as_str())?;
+    let mut writer = Vec::new();
+    let res = Request::new(&uri)
+        .timeout(Some(SITEMAP_TIMEOUT))
+        .send(&mut writer)?;
+    if !res.status_code().is_success() {
+        bail!("{} returned {}", url, res.status_code());
+    }
+    Ok(String::from_utf8_lossy(&writer).into_owned())
+}
+
+/// The `<loc>` entries of a sitemap or sitemap index, whether the sitemap
+/// namespace is the default one or has a prefix, and whether the URL is
+/// escaped or wrapped in CDATA.
+fn locs(xml: &str) -> Vec<String> {
+    let loc = Regex::new(
+        r"(?s)<(?:[\w.-]+:)?loc>\s*(?:<!\[CDATA\[\s*(.*?)\s*\]\]>|([^<]+?))\s*</(?:[\w.-]+:)?loc>",
+    )
+    .unwrap();
+    loc.captures_iter(xml)
+        .map(|captures| match captures.get(1) {
+            Some(cdata) => cdata.as_str().to_string(),
+            None => unescape(&captures[2]),
+        })
+        .collect()
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    fn scope(include: &[&str], exclude: &[&str]) -> CrawlScope {
+        let patterns = |list: &[&str]| list.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        CrawlScope::new(
+            &url("https://example.com/start"),
+            &patterns(include),
+            &patterns(exclude),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn scope_keeps_to_the_seed_host_over_http() {
+        let scope = scope(&[], &[]);
+        assert!(scope.allows(&url("https://example.com/a")));
+        assert!(scope.allows(&url("http://example.com/b")));
+        assert!(!scope.allows(&url("https://sub.example.com/a")));
+        assert!(!scope.allows(&url("https://example.org/a")));
+        assert!(!scope.allows(&url("ftp://example.com/a")));
+    }
+
+    #[test]
+    fn scope_applies_include_then_exclude() {
+        let scope = scope(&["/blog/"], &[r"\?page="]);
+        assert!(scope.allows(&url("https://example.com/blog/post")));
+        assert!(!scope.allows(&url("https://example.com/about")));
+        assert!(!scope.allows(&url("https://example.com/blog/?page=2")));
+    }
+
+    #[test]
+    fn scope_rejects_bad_patterns() {
+        assert!(CrawlScope::new(&url("https://example.com/"), &["(".to_string()], &[]).is_err());
+    }
+
+    #[test]
+    fn locs_reads_plain_entries_and_unescapes_them() {
+        let xml = r#"<?xml version="1.0"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <url><loc>https://example.com/a</loc></url>
+  <url><loc>
+    https://example.com/b?x=1&amp;y=2
+  </loc></url>
+</urlset>"#;
+        assert_eq!(
+            locs(xml),
+            ["https://example.com/a", "https://example.com/b?x=1&y=2"]
+        );
+    }
+
+    #[test]
+    fn locs_reads_namespaced_and_cdata_entries() {
+        let xml = r#"<sm:urlset xmlns:sm="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <sm:url><sm:loc>https://example.com/a</sm:loc></sm:url>
+  <sm:url><sm:loc><![CDATA[https://example.com/b?x=1&y=2]]></sm:loc></sm:url>
+  <url><loc> <![CDATA[ https://example.com/c ]]> </loc></url>
+</sm:urlset>"#;
+        assert_eq!(
+            locs(xml),
+            [
+                "https://example.com/a",
+                "https://example.com/b?x=1&y=2",
+                "https://example.com/c"
+            ]
+        );
+    }
+
+    #[test]
+    fn locs_ignores_other_tags() {
+        let xml = "<url><lastmod>2024-01-01</lastmod><location>x</location></url>";
+        assert!(locs(xml).is_empty());
+    }
+}
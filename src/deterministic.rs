@@ -0,0 +1,169 @@
+use crate::browser_pool::WINDOW_SIZE;
+use headless_chrome::browser::Tab;
+use headless_chrome::protocol::cdp::{Emulation, Page};
+
+/// What `Date` reports in a deterministic render: 2000-01-01T00:00:00Z.
+const FROZEN_TIME_MS: u64 = 946_684_800_000;
+
+/// Stands in for `FROZEN_TIME_MS` in PDF date strings.
+const FROZEN_PDF_DATE: &[u8] = b"20000101000000";
+
+/// Runs before any of the page's scripts: the clock stands still, random
+/// numbers come from a fixed seed, and CSS animations and transitions are
+/// switched off once the document exists.
+const FREEZE_JS: &str = r#"
+    (() => {
+        const RealDate = Date;
+        function FrozenDate(...args) {
+            if (!new.target) {
+                return new RealDate(NOW).toString();
+            }
+            return new RealDate(...(args.length ? args : [NOW]));
+        }
+        FrozenDate.prototype = RealDate.prototype;
+        FrozenDate.now = () => NOW;
+        FrozenDate.parse = RealDate.parse;
+        FrozenDate.UTC = RealDate.UTC;
+        window.Date = FrozenDate;
+
+        // mulberry32
+        let seed = 1;
+        Math.random = () => {
+            seed = (seed + 0x6d2b79f5) | 0;
+            let t = Math.imul(seed ^ (seed >>> 15), 1 | seed);
+            t = (t + Math.imul(t ^ (t >>> 7), 61 | t)) ^ t;
+            return ((t ^ (t >>> 14)) >>> 0) / 4294967296;
+        };
+
+        document.addEventListener("DOMContentLoaded", () => {
+            const style = document.createElement("style");
+            style.textContent =
+                "*, *::before, *::after { animation: none !important; " +
+                "transition: none !important; caret-color: transparent !important; }";
+            (document.head || document.documentElement).appendChild(style);
+        });
+    })()
+"#;
+
+/// Makes what `tab` renders from here on independent of when and where it
+/// runs: a fixed clock, `Math.random` seed, viewport, locale and timezone,
+/// and no animations. Call it before navigating.
+pub fn apply(tab: &Tab) -> anyhow::Result<()> {
+    tab.call_method(Emulation::SetDeviceMetricsOverride {
+        width: WINDOW_SIZE.0,
+        height: WINDOW_SIZE.1,
+        device_scale_factor: 1.0,
+        mobile: false,
+        scale: None,
+        screen_width: None,
+        screen_height: None,
+        position_x: None,
+        position_y: None,
+        dont_set_visible_size: None,
+        screen_orientation: None,
+        viewport: None,
+        display_feature: None,
+    })?;
+    tab.call_method(Emulation::SetLocaleOverride {
+        locale: Some("en-US".to_string()),
+    })?;
+    tab.call_method(Emulation::SetTimezoneOverride {
+        timezone_id: "UTC".to_string(),
+    })?;
+    tab.call_method(Emulation::SetEmulatedMedia {
+        media: None,
+        features: Some(vec![Emulation::MediaFeature {
+            name: "prefers-reduced-motion".to_string(),
+            value: "reduce".to_string(),
+        }]),
+    })?;
+    tab.call_method(Page::AddScriptToEvaluateOnNewDocument {
+        source: FREEZE_JS.replace("NOW", &FROZEN_TIME_MS.to_string()),
+        world_name: None,
+        include_command_line_api: None,
+        run_immediately: None,
+    })?;
+    Ok(())
+}
+
+/// Overwrites what Chrome stamps into a printed PDF at print time (the
+/// creation and modification dates and the document ID) with fixed values
+/// of the same length, so byte offsets and the xref table stay valid.
+pub fn pin_pdf(pdf: &mut [u8]) {
+    for key in [&b"/CreationDate (D:"[..], b"/ModDate (D:"] {
+        for start in find_all(pdf, key) {
+            let date = start + key.len();
+            let digits = pdf[date..]
+                .iter()
+                .take(FROZEN_PDF_DATE.len())
+                .take_while(|b| b.is_ascii_digit())
+                .count();
+            pdf[date..date + digits].copy_from_slice(&FROZEN_PDF_DATE[..digits]);
+        }
+    }
+    for start in find_all(pdf, b"/ID [") {
+        let start = start + b"/ID [".len();
+        let end = pdf[start..]
+            .iter()
+            .position(|&b| b == b']')
+            .map_or(pdf.len(), |end| start + end);
+        for b in &mut pdf[start..end] {
+            if b.is_ascii_hexdigit() {
+                *b = b'0';
+            }
+        }
+    }
+}
+
+fn find_all(haystack: &[u8], needle: &[u8]) -> Vec<usize> {
+    haystack
+        .windows(needle.len())
+        .enumerate()
+        .filter(|(_, window)| *window == needle)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pdf(created: &str, modified: &str, id: &str) -> Vec<u8> {
+        format!(
+            "%PDF-1.4\n1 0 obj\n<< /Creator (Chromium) /CreationDate (D:{}+00'00') \
+             /ModDate (D:{}+00'00') >>\nendobj\ntrailer\n<< /Size 2 /ID [<{}> <{}>] >>\n%%EOF\n",
+            created, modified, id, id
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn prints_that_differ_only_in_dates_and_id_come_out_identical() {
+        let mut first = pdf("20240102030405", "20240102030406", "8f3a9c01d2e4b5a6");
+        let mut second = pdf("20251231235959", "20260101000000", "00ff12ab34cd56ef");
+        let len = first.len();
+        pin_pdf(&mut first);
+        pin_pdf(&mut second);
+        assert_eq!(first, second);
+        assert_eq!(first.len(), len);
+        assert_eq!(
+            first,
+            pdf("20000101000000", "20000101000000", "0000000000000000")
+        );
+    }
+
+    #[test]
+    fn the_rest_of_the_pdf_is_left_alone() {
+        let original = b"%PDF-1.4\n<< /Title (ID [abc] made 2024) >>\n%%EOF\n".to_vec();
+        let mut pinned = original.clone();
+        pin_pdf(&mut pinned);
+        assert_eq!(pinned, original);
+    }
+
+    #[test]
+    fn short_dates_only_have_their_digits_replaced() {
+        let mut pdf = b"/CreationDate (D:2024)".to_vec();
+        pin_pdf(&mut pdf);
+        assert_eq!(pdf, b"/CreationDate (D:2000)");
+    }
+}
@@ -0,0 +1,239 @@
+use crate::config::DiagnosticsSettings;
+use headless_chrome::browser::tab::EventListener;
+use headless_chrome::protocol::cdp::{
+    types::Event,
+    Inspector, Log,
+    Runtime::{self, RemoteObject},
+};
+use headless_chrome::{browser::Tab, Browser};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// CDP events and console messages kept per tab; older ones are dropped.
+const MAX_LOG_ENTRIES: usize = 100;
+/// Longest event or console message kept, in bytes.
+const MAX_ENTRY_BYTES: usize = 500;
+/// Most of the page's HTML kept in a bundle, in bytes.
+const MAX_HTML_BYTES: usize = 1024 * 1024;
+
+/// What was known about a tab when its scrape failed: why the renderer or
+/// browser went away, if it did, the last CDP events and console messages,
+/// and as much of the page as could still be read.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiagnosticsBundle {
+    pub url: String,
+    /// Seconds since the Unix epoch.
+    pub failed_at: u64,
+    /// The pipeline error with its causes.
+    pub error: String,
+    /// Set when the renderer crashed, the tab was detached or the browser
+    /// exited.
+    pub crash_reason: Option<String>,
+    /// Oldest first.
+    pub events: Vec<String>,
+    /// Console messages, browser log entries and uncaught exceptions, oldest
+    /// first.
+    pub console: Vec<String>,
+    /// The page's HTML, unless the renderer was gone.
+    pub html: Option<String>,
+}
+
+/// The most recent bundles, for the admin API to hand out.
+pub struct DiagnosticsStore {
+    settings: DiagnosticsSettings,
+    bundles: Mutex<VecDeque<DiagnosticsBundle>>,
+}
+
+impl DiagnosticsStore {
+    pub fn new(settings: DiagnosticsSettings) -> Self {
+        DiagnosticsStore {
+            settings,
+            bundles: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Starts recording `tab`, unless diagnostics are off.
+    pub fn record(&self, tab: &Arc<Tab>) -> Option<TabRecorder> {
+        if self.settings.max_bundles == 0 {
+            return None;
+        }
+        TabRecorder::attach(tab, self.settings.console)
+    }
+
+    pub fn insert(&self, bundle: DiagnosticsBundle) {
+        let mut bundles = self.bundles.lock().unwrap();
+        while bundles.len() >= self.settings.max_bundles.max(1) {
+            bundles.pop_front();
+        }
+        bundles.push_back(bundle);
+    }
+
+    /// The stored bundles for `url`, or all of them; newest first.
+    pub fn get(&self, url: Option<&str>) -> Vec<DiagnosticsBundle> {
+        self.bundles
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|bundle| url.map_or(true, |url| bundle.url == url))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Keeps a tab's recent CDP events and console messages until it's dropped.
+pub struct TabRecorder {
+    tab: Arc<Tab>,
+    log: Arc<Mutex<TabLog>>,
+    listener: Weak<dyn EventListener<Event> + Send + Sync>,
+}
+
+#[derive(Default)]
+struct TabLog {
+    events: VecDeque<String>,
+    console: VecDeque<String>,
+    crash_reason: Option<String>,
+}
+
+impl TabRecorder {
+    /// `None` when the tab doesn't take the listener, which only costs the
+    /// diagnostics. With `console`, the Runtime domain is enabled for
+    /// console messages and exceptions; pages can notice that.
+    fn attach(tab: &Arc<Tab>, console: bool) -> Option<Self> {
+        let log = Arc::new(Mutex::new(TabLog::default()));
+        let recording = log.clone();
+        let listener = tab
+            .add_event_listener(Arc::new(move |event: &Event| {
+                recording.lock().unwrap().record(event)
+            }))
+            .ok()?;
+
+        let _ = tab.call_method(Inspector::Enable(None));
+        let _ = tab.call_method(Log::Enable(None));
+        if console {
+            let _ = tab.call_method(Runtime::Enable(None));
+        }
+
+        Some(TabRecorder {
+            tab: tab.clone(),
+            log,
+            listener,
+        })
+    }
+
+    /// The bundle for a scrape of `url` that failed with `err`.
+    pub fn bundle(&self, url: &str, err: &anyhow::Error, browser: &Browser) -> DiagnosticsBundle {
+        let (events, console, mut crash_reason) = {
+            let log = self.log.lock().unwrap();
+            (
+                log.events.iter().cloned().collect(),
+                log.console.iter().cloned().collect(),
+                log.crash_reason.clone(),
+            )
+        };
+        if crash_reason.is_none() && browser.get_version().is_err() {
+            crash_reason = Some("browser process exited".to_string());
+        }
+        let html = match crash_reason {
+            Some(_) => None,
+            None => self
+                .tab
+                .get_content()
+                .ok()
+                .map(|html| truncate(html, MAX_HTML_BYTES)),
+        };
+
+        DiagnosticsBundle {
+            url: url.to_string(),
+            failed_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            error: format!("{:#}", err),
+            crash_reason,
+            events,
+            console,
+            html,
+        }
+    }
+}
+
+impl Drop for TabRecorder {
+    fn drop(&mut self) {
+        let _ = self.tab.remove_event_listener(&self.listener);
+    }
+}
+
+impl TabLog {
+    fn record(&mut self, event: &Event) {
+        match event {
+            Event::InspectorTargetCrashed(_) => {
+                self.crash_reason = Some("renderer crashed".to_string())
+            }
+            Event::InspectorDetached(detached) => {
+                self.crash_reason
+                    .get_or_insert(format!("tab detached: {}", detached.params.reason));
+            }
+            Event::RuntimeConsoleAPICalled(called) => {
+                let message = called
+                    .params
+                    .args
+                    .iter()
+                    .map(remote_object_text)
+                    .collect::<Vec<String>>()
+                    .join(" ");
+                push(&mut self.console, message);
+            }
+            Event::RuntimeExceptionThrown(thrown) => {
+                let details = &thrown.params.exception_details;
+                let description = details
+                    .exception
+                    .as_ref()
+                    .map(remote_object_text)
+                    .unwrap_or_default();
+                push(
+                    &mut self.console,
+                    format!("{} {}", details.text, description),
+                );
+            }
+            Event::LogEntryAdded(added) => {
+                let entry = &added.params.entry;
+                push(
+                    &mut self.console,
+                    format!("{:?}: {}", entry.level, entry.text),
+                );
+            }
+            _ => {}
+        }
+        push(&mut self.events, format!("{:?}", event));
+    }
+}
+
+fn push(entries: &mut VecDeque<String>, entry: String) {
+    if entries.len() >= MAX_LOG_ENTRIES {
+        entries.pop_front();
+    }
+    entries.push_back(truncate(entry, MAX_ENTRY_BYTES));
+}
+
+/// A console argument the way DevTools would print it.
+fn remote_object_text(object: &RemoteObject) -> String {
+    match (&object.value, &object.description) {
+        (Some(serde_json::Value::String(s)), _) => s.clone(),
+        (Some(value), _) => value.to_string(),
+        (None, Some(description)) => description.clone(),
+        (None, None) => String::new(),
+    }
+}
+
+fn truncate(mut s: String, max_bytes: usize) -> String {
+    if s.len() > max_bytes {
+        let mut end = max_bytes;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        s.truncate(end);
+    }
+    s
+}
@@ -0,0 +1,232 @@
+use crate::browser::{print_page, WaitStrategy};
+use crate::browser_pool::BrowserPool;
+use crate::config::Config;
+use crate::container::ChromeEnvironment;
+use crate::pdf_extract::bind_pdfium;
+use crate::pipeline::Timings;
+use crate::readability_extract::extract_article_text_from_html;
+use headless_chrome::types::PrintToPdfOptions;
+use std::env;
+use std::fs;
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// Page the self-test prints and extracts.
+const TEST_PAGE: &str = include_str!("doctor_page.html");
+/// Phrase from the article body of `TEST_PAGE` that every check must find.
+const MARKER: &str = "virtual printing self-test";
+/// Only in the page footer, which Readability is expected to drop.
+const FOOTER: &str = "Footer text";
+/// Chrome starting and opening a tab; a cold start on a small VM can take
+/// a while.
+const LAUNCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Runs each pipeline component against known input and prints a pass/fail
+/// line per component, with a hint for failures. Returns whether everything
+/// passed.
+pub async fn run() -> bool {
+    let mut report = Report::default();
+
+    let config = match Config::load() {
+        Ok(config) => {
+            report.pass(
+                "config",
+                format!(
+                    "chrome_path={} pdfium_path={}",
+                    config
+                        .chrome_path
+                        .as_ref()
+                        .map_or("auto".to_string(), |p| p.display().to_string()),
+                    config
+                        .pdfium_path
+                        .as_ref()
+                        .map_or("system".to_string(), |p| p.display().to_string()),
+                ),
+            );
+            config
+        }
+        Err(e) => {
+            report.fail(
+                "config",
+                format!("{:#}", e),
+                "fix the file named by SCRAPER_CONFIG (or config.toml) and the SCRAPER_* variables",
+            );
+            return report.finish();
+        }
+    };
+
+    let readability =
+        extract_article_text_from_html("http://localhost/self-test", TEST_PAGE.to_string()).await;
+    match readability {
+        Ok(text) if contains_marker(&text) && !text.contains(FOOTER) => report.pass(
+            "readability",
+            format!("{} chars of article text", text.len()),
+        ),
+        Ok(_) => report.fail(
+            "readability",
+            "extracted text doesn't match the test article".to_string(),
+            "the readah build is misbehaving; rebuild against the locked dependency versions",
+        ),
+        Err(e) => report.fail(
+            "readability",
+            format!("{:#}", e),
+            "the readah build is misbehaving; rebuild against the locked dependency versions",
+        ),
+    }
+
+    let mut pool_settings = config.pool.clone();
+    pool_settings.min_browsers = 0;
+    let pool = BrowserPool::new(
+        config.chrome_path.clone(),
+        ChromeEnvironment::detect(&config),
+        pool_settings,
+    );
+    let started = Instant::now();
+    let lease = match tokio::time::timeout(LAUNCH_TIMEOUT, pool.checkout(Vec::new(), false)).await {
+        Ok(Ok(lease)) => {
+            report.pass("chrome", format!("started in {:?}", started.elapsed()));
+            Some(lease)
+        }
+        Ok(Err(e)) => {
+            report.fail(
+                "chrome",
+                format!("{:#}", e),
+                "install Chrome or Chromium, or set chrome_path / SCRAPER_CHROME_PATH; \
+                 as root or in a container, try chrome_sandbox = false",
+            );
+            None
+        }
+        Err(_) => {
+            report.fail(
+                "chrome",
+                format!("didn't start within {:?}", LAUNCH_TIMEOUT),
+                "check that Chrome runs headless on this host, \
+                 e.g. `chrome --headless --dump-dom about:blank`",
+            );
+            None
+        }
+    };
+
+    let pdf = match &lease {
+        Some(lease) => {
+            let printed = match write_test_page() {
+                Ok(url) => {
+                    print_page(
+                        &url,
+                        &lease.tab,
+                        &WaitStrategy::Body,
+                        PrintToPdfOptions::default(),
+                        &config.timeouts,
+                        &mut Timings::default(),
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            match printed {
+                Ok(pdf) => {
+                    report.pass("print", format!("{} bytes of PDF", pdf.len()));
+                    Some(pdf)
+                }
+                Err(e) => {
+                    report.fail(
+                        "print",
+                        format!("{:#}", e),
+                        "Chrome started but couldn't render; with a small /dev/shm set \
+                         chrome_disable_dev_shm = true, otherwise raise timeouts.print_ms",
+                    );
+                    None
+                }
+            }
+        }
+        None => {
+            report.skip("print", "needs chrome");
+            None
+        }
+    };
+
+    match pdf {
+        Some(pdf) => {
+            let pdfium_path = config.pdfium_path.clone();
+            let parsed = tokio::task::spawn_blocking(move || -> anyhow::Result<String> {
+                let text = bind_pdfium(pdfium_path.as_deref())?
+                    .load_pdf_from_byte_vec(pdf, None)?
+                    .pages()
+                    .iter()
+                    .map(|page| -> anyhow::Result<String> { Ok(page.text()?.all()) })
+                    .collect::<anyhow::Result<Vec<String>>>()?
+                    .join(" ");
+                Ok(text)
+            })
+            .await
+            .unwrap_or_else(|e| Err(e.into()));
+            match parsed {
+                Ok(text) if contains_marker(&text) => {
+                    report.pass("pdfium", format!("{} chars of text", text.len()))
+                }
+                Ok(_) => report.fail(
+                    "pdfium",
+                    "the printed PDF doesn't contain the test text".to_string(),
+                    "Chrome printed a blank page; check that fonts are installed on this host",
+                ),
+                Err(e) => report.fail(
+                    "pdfium",
+                    format!("{:#}", e),
+                    "download the pdfium library for this platform and set pdfium_path / \
+                     SCRAPER_PDFIUM_PATH to the directory containing it",
+                ),
+            }
+        }
+        None => report.skip("pdfium", "needs a printed page"),
+    }
+
+    report.finish()
+}
+
+/// Writes `TEST_PAGE` to the temp directory and returns its `file://` URL.
+fn write_test_page() -> anyhow::Result<String> {
+    let path = env::temp_dir().join("scrape-web-by-virtual-printing-self-test.html");
+    fs::write(&path, TEST_PAGE)?;
+    Url::from_file_path(&path)
+        .map(String::from)
+        .map_err(|_| anyhow::anyhow!("no file URL for {}", path.display()))
+}
+
+/// Whether `text` has the marker phrase, ignoring how it was wrapped.
+fn contains_marker(text: &str) -> bool {
+    text.split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .contains(MARKER)
+}
+
+/// Prints check results as they come in.
+#[derive(Default)]
+struct Report {
+    failed: bool,
+}
+
+impl Report {
+    fn pass(&mut self, component: &str, detail: String) {
+        println!("PASS {:<12} {}", component, detail);
+    }
+
+    fn fail(&mut self, component: &str, error: String, hint: &str) {
+        self.failed = true;
+        println!("FAIL {:<12} {}", component, error);
+        println!("     {:<12} hint: {}", "", hint);
+    }
+
+    fn skip(&mut self, component: &str, reason: &str) {
+        println!("SKIP {:<12} {}", component, reason);
+    }
+
+    fn finish(self) -> bool {
+        if self.failed {
+            println!("\nsome checks failed");
+        } else {
+            println!("\nall checks passed");
+        }
+        !self.failed
+    }
+}
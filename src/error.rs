@@ -0,0 +1,176 @@
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use pdfium_render::prelude::PdfiumError;
+use serde_json::json;
+use std::fmt;
+use std::time::Duration;
+
+/// Why a request produced no result, as reported to clients: a stable
+/// machine-readable `code`, an HTTP status and a short message.
+#[derive(Debug, Clone)]
+pub enum ScrapeError {
+    InvalidUrl,
+    InvalidHostOverrides,
+    /// Chrome rejected the request's cookies or headers.
+    InvalidCredentials,
+    /// A crawl's `include` or `exclude` pattern isn't a valid regex.
+    InvalidCrawlPattern,
+    /// A `language_filter` allows no languages or names an unknown one.
+    InvalidLanguage,
+    LaunchFailed,
+    /// The page didn't load (or show a body) within its timeouts.
+    NavigationTimeout,
+    /// A later phase (printing, parsing, Readability, ...) ran out of time.
+    Timeout { phase: &'static str },
+    /// pdfium couldn't be loaded or couldn't read the printed PDF.
+    Pdfium,
+    /// Extraction ran but found no text at all.
+    ExtractionEmpty,
+    ExtractionFailed,
+    /// Printing or capturing the page for `/api/pdf` or `/api/screenshot`.
+    RenderFailed,
+    TranslationFailed,
+    /// The site's robots.txt disallows the URL.
+    DisallowedByRobots,
+    /// The URL's domain is on a refusing blocklist of this category.
+    BlockedDomain { category: String },
+    /// Turned away by admission control.
+    Overloaded { retry_after: Duration },
+    /// Too many requests already running and waiting.
+    QueueFull { retry_after: Duration },
+    /// An admin endpoint was called without its configured token.
+    Unauthorized,
+}
+
+impl ScrapeError {
+    /// Maps an error from the browser/extraction pipeline to what clients
+    /// see, using `otherwise` when nothing more specific applies.
+    pub fn from_pipeline(err: &anyhow::Error, otherwise: ScrapeError) -> ScrapeError {
+        if let Some(timeout) = err.downcast_ref::<PhaseTimeout>() {
+            return match timeout.phase {
+                "navigate" | "wait" => ScrapeError::NavigationTimeout,
+                phase => ScrapeError::Timeout { phase },
+            };
+        }
+        if err.downcast_ref::<PdfiumError>().is_some() {
+            return ScrapeError::Pdfium;
+        }
+        otherwise
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ScrapeError::InvalidUrl => "invalid_url",
+            ScrapeError::InvalidHostOverrides => "invalid_host_overrides",
+            ScrapeError::InvalidCredentials => "invalid_credentials",
+            ScrapeError::InvalidCrawlPattern => "invalid_crawl_pattern",
+            ScrapeError::InvalidLanguage => "invalid_language",
+            ScrapeError::LaunchFailed => "launch_failed",
+            ScrapeError::NavigationTimeout => "navigation_timeout",
+            ScrapeError::Timeout { .. } => "timeout",
+            ScrapeError::Pdfium => "pdfium_error",
+            ScrapeError::ExtractionEmpty => "extraction_empty",
+            ScrapeError::ExtractionFailed => "extraction_failed",
+            ScrapeError::RenderFailed => "render_failed",
+            ScrapeError::TranslationFailed => "translation_failed",
+            ScrapeError::DisallowedByRobots => "disallowed_by_robots",
+            ScrapeError::BlockedDomain { .. } => "blocked_domain",
+            ScrapeError::Overloaded { .. } => "overloaded",
+            ScrapeError::QueueFull { .. } => "queue_full",
+            ScrapeError::Unauthorized => "unauthorized",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ScrapeError::InvalidUrl
+            | ScrapeError::InvalidHostOverrides
+            | ScrapeError::InvalidCredentials
+            | ScrapeError::InvalidCrawlPattern
+            | ScrapeError::InvalidLanguage => StatusCode::BAD_REQUEST,
+            ScrapeError::LaunchFailed | ScrapeError::Overloaded { .. } => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ScrapeError::NavigationTimeout | ScrapeError::Timeout { .. } => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            ScrapeError::Pdfium => StatusCode::INTERNAL_SERVER_ERROR,
+            ScrapeError::ExtractionEmpty => StatusCode::UNPROCESSABLE_ENTITY,
+            ScrapeError::QueueFull { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ScrapeError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ScrapeError::DisallowedByRobots | ScrapeError::BlockedDomain { .. } => {
+                StatusCode::FORBIDDEN
+            }
+            ScrapeError::ExtractionFailed
+            | ScrapeError::RenderFailed
+            | ScrapeError::TranslationFailed => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+impl fmt::Display for ScrapeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScrapeError::InvalidUrl => write!(f, "parse target url failure"),
+            ScrapeError::InvalidHostOverrides => write!(f, "parse host_overrides failure"),
+            ScrapeError::InvalidCredentials => write!(f, "apply cookies or headers failure"),
+            ScrapeError::InvalidCrawlPattern => write!(f, "parse include/exclude pattern failure"),
+            ScrapeError::InvalidLanguage => write!(f, "parse language_filter failure"),
+            ScrapeError::LaunchFailed => write!(f, "failed to launch browser"),
+            ScrapeError::NavigationTimeout => write!(f, "the page did not finish loading in time"),
+            ScrapeError::Timeout { phase } => write!(f, "{} timed out", phase),
+            ScrapeError::Pdfium => write!(f, "failed to read the printed page"),
+            ScrapeError::ExtractionEmpty => write!(f, "no text found on webpage"),
+            ScrapeError::ExtractionFailed => write!(f, "failed to get text from webpage"),
+            ScrapeError::RenderFailed => write!(f, "failed to render webpage"),
+            ScrapeError::TranslationFailed => write!(f, "failed to translate text from webpage"),
+            ScrapeError::DisallowedByRobots => {
+                write!(f, "the site's robots.txt disallows this url")
+            }
+            ScrapeError::BlockedDomain { category } => {
+                write!(f, "the url's domain is blocked as {}", category)
+            }
+            ScrapeError::Overloaded { .. } => {
+                write!(f, "host is under load, try this site again later")
+            }
+            ScrapeError::QueueFull { .. } => write!(f, "too many requests, try again later"),
+            ScrapeError::Unauthorized => write!(f, "missing or wrong admin token"),
+        }
+    }
+}
+
+impl IntoResponse for ScrapeError {
+    fn into_response(self) -> Response {
+        let body = Json(json!({
+            "error": self.code(),
+            "message": self.to_string(),
+        }));
+        let mut response = (self.status(), body).into_response();
+        if let ScrapeError::Overloaded { retry_after } | ScrapeError::QueueFull { retry_after } =
+            self
+        {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after.as_secs().into());
+        }
+        response
+    }
+}
+
+/// A pipeline phase that ran past its configured limit.
+#[derive(Debug)]
+pub struct PhaseTimeout {
+    pub phase: &'static str,
+    pub limit: Duration,
+}
+
+impl fmt::Display for PhaseTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} timed out after {:?}", self.phase, self.limit)
+    }
+}
+
+impl std::error::Error for PhaseTimeout {}
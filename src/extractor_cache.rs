@@ -0,0 +1,70 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Domains remembered at once; beyond this the oldest entry is dropped.
+const MAX_DOMAINS: usize = 10_000;
+
+/// The ways `text_to_use` can get text out of a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Extractor {
+    Pdf,
+    InnerText,
+    Readability,
+}
+
+impl Extractor {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Extractor::Pdf => "pdf",
+            Extractor::InnerText => "inner_text",
+            Extractor::Readability => "readability",
+        }
+    }
+}
+
+/// Remembers which extractor produced the text for each domain recently, so
+/// the next scrape of that domain can try it alone and skip the others.
+pub struct ExtractorCache {
+    ttl: Duration,
+    winners: Mutex<HashMap<String, (Extractor, Instant)>>,
+}
+
+impl ExtractorCache {
+    pub fn new(ttl: Duration) -> Self {
+        ExtractorCache {
+            ttl,
+            winners: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The extractor that last won for `domain`, unless that was longer than
+    /// the TTL ago.
+    pub fn get(&self, domain: &str) -> Option<Extractor> {
+        let winners = self.winners.lock().unwrap();
+        winners
+            .get(domain)
+            .filter(|(_, at)| at.elapsed() < self.ttl)
+            .map(|(extractor, _)| *extractor)
+    }
+
+    pub fn record(&self, domain: &str, extractor: Extractor) {
+        let mut winners = self.winners.lock().unwrap();
+        if winners.len() >= MAX_DOMAINS && !winners.contains_key(domain) {
+            let ttl = self.ttl;
+            winners.retain(|_, (_, at)| at.elapsed() < ttl);
+            if winners.len() >= MAX_DOMAINS {
+                let oldest = winners
+                    .iter()
+                    .min_by_key(|(_, (_, at))| *at)
+                    .map(|(domain, _)| domain.clone());
+                if let Some(oldest) = oldest {
+                    winners.remove(&oldest);
+                }
+            }
+        }
+        winners.insert(domain.to_string(), (extractor, Instant::now()));
+    }
+}
@@ -0,0 +1,137 @@
+use serde_json::{Map, Value};
+
+/// Keeps only the parts of a JSON response named in `fields`, a
+/// comma-separated list of dot paths such as `text,timings.navigate_ms`.
+/// Paths through an array apply to each of its elements; paths that don't
+/// exist are left out. An empty list keeps everything.
+pub fn project(value: Value, fields: &str) -> Value {
+    let paths: Vec<Vec<&str>> = fields
+        .split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(|field| field.split('.').collect())
+        .collect();
+    if paths.is_empty() {
+        return value;
+    }
+
+    let paths: Vec<&[&str]> = paths.iter().map(Vec::as_slice).collect();
+    select(value, &paths).unwrap_or_else(|| Value::Object(Map::new()))
+}
+
+/// The parts of `value` reached by `paths`, relative to it, or `None` when
+/// none of them exist.
+fn select(value: Value, paths: &[&[&str]]) -> Option<Value> {
+    if paths.iter().any(|path| path.is_empty()) {
+        return Some(value);
+    }
+
+    match value {
+        Value::Object(map) => {
+            let selected: Map<String, Value> = map
+                .into_iter()
+                .filter_map(|(key, child)| {
+                    let rest: Vec<&[&str]> = paths
+                        .iter()
+                        .filter(|path| path[0] == key)
+                        .map(|path| &path[1..])
+                        .collect();
+                    if rest.is_empty() {
+                        return None;
+                    }
+                    // an object none of the paths go on into is left out,
+                    // like a missing key, unless it was asked for whole
+                    let whole = rest.iter().any(|path| path.is_empty());
+                    select(child, &rest)
+                        .filter(|child| {
+                            whole || !matches!(child, Value::Object(map) if map.is_empty())
+                        })
+                        .map(|child| (key, child))
+                })
+                .collect();
+            Some(Value::Object(selected))
+        }
+        Value::Array(items) => Some(Value::Array(
+            items
+                .into_iter()
+                .filter_map(|item| select(item, paths))
+                .collect(),
+        )),
+        // the path goes on past a string or number
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn response() -> Value {
+        json!({
+            "text": "hello",
+            "timings": { "navigate_ms": 120, "total_ms": 300 },
+            "results": [
+                { "url": "a", "text": "one", "status": "ok" },
+                { "url": "b", "error": "timeout", "status": "failed" }
+            ],
+            "metadata": {}
+        })
+    }
+
+    #[test]
+    fn empty_fields_keep_everything() {
+        assert_eq!(project(response(), ""), response());
+        assert_eq!(project(response(), " , ,"), response());
+    }
+
+    #[test]
+    fn keeps_top_level_and_nested_paths() {
+        assert_eq!(
+            project(response(), "text, timings.navigate_ms"),
+            json!({ "text": "hello", "timings": { "navigate_ms": 120 } })
+        );
+    }
+
+    #[test]
+    fn a_whole_object_can_be_kept() {
+        assert_eq!(
+            project(response(), "timings,metadata"),
+            json!({
+                "timings": { "navigate_ms": 120, "total_ms": 300 },
+                "metadata": {}
+            })
+        );
+    }
+
+    #[test]
+    fn paths_apply_to_each_array_element() {
+        assert_eq!(
+            project(response(), "results.url,results.text"),
+            json!({ "results": [{ "url": "a", "text": "one" }, { "url": "b" }] })
+        );
+    }
+
+    #[test]
+    fn unknown_paths_are_left_out() {
+        assert_eq!(project(response(), "nope"), json!({}));
+        assert_eq!(project(response(), "timings.nope"), json!({}));
+        assert_eq!(
+            project(response(), "text,timings.nope"),
+            json!({ "text": "hello" })
+        );
+    }
+
+    #[test]
+    fn array_elements_keep_their_places() {
+        assert_eq!(
+            project(response(), "results.error"),
+            json!({ "results": [{}, { "error": "timeout" }] })
+        );
+    }
+
+    #[test]
+    fn paths_past_a_scalar_are_left_out() {
+        assert_eq!(project(response(), "text.length"), json!({}));
+    }
+}
@@ -0,0 +1,146 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http_req::{request::Request, uri::Uri};
+use log::debug;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::io::{self, Write};
+use std::time::Duration;
+use url::Url;
+
+/// Downloading one image to inline it.
+const IMAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An image in the article, for archiving it along with the text.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArticleImage {
+    /// Absolute URL of the image.
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alt: Option<String>,
+    /// The image as a `data:` URL, when inlining was asked for and it fit
+    /// under the size cap.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_url: Option<String>,
+}
+
+/// The `<img>` elements of article HTML in document order, with their
+/// sources resolved against `base`. Each URL is listed once, and sources
+/// that aren't http(s) are left out.
+pub fn article_images(article_html: &str, base: &Url) -> Vec<ArticleImage> {
+    let img = Regex::new(r"(?is)<img\b[^>]*>").unwrap();
+    let mut seen = HashSet::new();
+    img.find_iter(article_html)
+        .filter_map(|tag| {
+            let src = attribute(tag.as_str(), "src")?;
+            let url = base.join(&src).ok()?;
+            if !matches!(url.scheme(), "http" | "https") || !seen.insert(url.to_string()) {
+                return None;
+            }
+            Some(ArticleImage {
+                url: url.into(),
+                alt: attribute(tag.as_str(), "alt").filter(|alt| !alt.is_empty()),
+                data_url: None,
+            })
+        })
+        .collect()
+}
+
+/// Downloads each image and sets its `data_url` when it is an image of at
+/// most `max_bytes`. Images that fail to download are left as URLs.
+pub async fn inline(images: &mut Vec<ArticleImage>, max_bytes: usize) {
+    let urls: Vec<String> = images.iter().map(|image| image.url.clone()).collect();
+    let data_urls = tokio::task::spawn_blocking(move || {
+        urls.iter()
+            .map(|url| match download(url, max_bytes) {
+                Ok(data_url) => data_url,
+                Err(e) => {
+                    debug!("not inlining {}: {}", url, e);
+                    None
+                }
+            })
+            .collect::<Vec<Option<String>>>()
+    })
+    .await
+    .unwrap_or_default();
+    for (image, data_url) in images.iter_mut().zip(data_urls) {
+        image.data_url = data_url;
+    }
+}
+
+/// The image at `url` as a `data:` URL, or `None` when it isn't one. Fails
+/// once the body grows past `max_bytes`, without reading the rest.
+fn download(url: &str, max_bytes: usize) -> anyhow::Result<Option<String>> {
+    let uri = Uri::try_from(url)?;
+    let mut writer = CappedBuffer {
+        bytes: Vec::new(),
+        max_bytes,
+    };
+    let res = Request::new(&uri)
+        .timeout(Some(IMAGE_TIMEOUT))
+        .send(&mut writer)?;
+    let body = writer.bytes;
+    let content_type = res
+        .headers()
+        .get("Content-Type")
+        .map(|value| {
+            value
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        })
+        .unwrap_or_default();
+    if !res.status_code().is_success() || !content_type.starts_with("image/") {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "data:{};base64,{}",
+        content_type,
+        STANDARD.encode(&body)
+    )))
+}
+
+struct CappedBuffer {
+    bytes: Vec<u8>,
+    max_bytes: usize,
+}
+
+impl Write for CappedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.bytes.len() + buf.len() > self.max_bytes {
+            return Err(io::Error::other(format!(
+                "larger than {} bytes",
+                self.max_bytes
+            )));
+        }
+        self.bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The value of attribute `name` in an HTML start tag, entity-decoded for
+/// the few entities serializers emit.
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let attr = Regex::new(&format!(
+        r#"(?i)\s{}\s*=\s*(?:"([^"]*)"|'([^']*)')"#,
+        regex::escape(name)
+    ))
+    .unwrap();
+    let captures = attr.captures(tag)?;
+    let value = captures.get(1).or_else(|| captures.get(2))?.as_str();
+    Some(
+        value
+            .replace("&quot;", "\"")
+            .replace("&#39;", "'")
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&amp;", "&"),
+    )
+}
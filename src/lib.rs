@@ -0,0 +1,45 @@
+//! Scrapes web pages by rendering them in headless Chrome and taking their
+//! text from the printed PDF, from Readability over the rendered HTML or
+//! from the rendered DOM, whichever reads best.
+//!
+//! [`pipeline::scrape`] runs a whole scrape of one URL; [`pipeline::text_to_use`]
+//! and the functions in [`browser`], [`pdf_extract`] and
+//! [`readability_extract`] run its parts on a tab from a
+//! [`browser_pool::BrowserPool`]. [`service::ScrapeService`] adds what a
+//! request goes through around the scrape (validation, caching, coalescing,
+//! post-processing); the HTTP service in `main.rs` is a thin wrapper around
+//! it.
+
+pub mod admission;
+pub mod archive;
+pub mod asset_cache;
+pub mod blocklist;
+pub mod browser;
+pub mod browser_pool;
+pub mod concurrency;
+pub mod config;
+pub mod container;
+pub mod crawl;
+pub mod deterministic;
+pub mod diagnostics;
+pub mod doctor;
+pub mod error;
+pub mod extractor_cache;
+pub mod images;
+pub mod markdown;
+pub mod metadata;
+pub mod metrics;
+pub mod pdf_extract;
+pub mod pipeline;
+pub mod politeness;
+pub mod post_process;
+pub mod proxy;
+pub mod readability_extract;
+pub mod resource_usage;
+pub mod scrape_cache;
+pub mod service;
+pub mod stable_hash;
+pub mod tables;
+pub mod translate;
+pub mod visible_html;
+pub mod wayback;
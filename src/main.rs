@@ -1,76 +1,1147 @@
+mod cli;
+mod fields;
+mod systemd;
+
+use anyhow::anyhow;
 use axum::{
-    extract::{Json, Query},
-    http::{Response, StatusCode},
-    response::IntoResponse,
-    routing::{get, post},
+    extract::{DefaultBodyLimit, Json, Query, State},
+    http::{header, HeaderMap, HeaderValue, Response, StatusCode},
+    middleware,
+    response::{Html, IntoResponse},
+    routing::{delete, get, post},
     Router,
 };
-use headless_chrome::{types::PrintToPdfOptions, Browser, LaunchOptions, browser::Tab};
-use html2text;
-use pdfium_render::prelude::*;
-use readah::readability::Readability;
+use clap::Parser;
+use cli::{Cli, Command};
+use headless_chrome::protocol::cdp::{Emulation, Network, Page};
+use headless_chrome::{browser::Tab, types::PrintToPdfOptions};
+use log::{debug, error};
+use scrape_web_by_virtual_printing::browser::{
+    navigate, on_tab, print_page, Rendering, WaitStrategy,
+};
+use scrape_web_by_virtual_printing::browser_pool::WINDOW_SIZE;
+use scrape_web_by_virtual_printing::concurrency::{limit_concurrency, ConcurrencyLimit};
+use scrape_web_by_virtual_printing::config::{Config, Timeouts};
+use scrape_web_by_virtual_printing::crawl::{self, CrawlScope};
+use scrape_web_by_virtual_printing::error::ScrapeError;
+use scrape_web_by_virtual_printing::images::ArticleImage;
+use scrape_web_by_virtual_printing::metadata::PageMetadata;
+use scrape_web_by_virtual_printing::metrics::{self, track_requests};
+use scrape_web_by_virtual_printing::pdf_extract::{bind_pdfium, PageText, PaperPreset};
+use scrape_web_by_virtual_printing::pipeline::{
+    report_error, run_blocking_phase, ExtractionMode, OutputFormat, ScrapeOptions, Timings,
+    EXTRACTOR_VERSION,
+};
+use scrape_web_by_virtual_printing::proxy::Proxy;
+use scrape_web_by_virtual_printing::resource_usage::ResourceUsage;
+use scrape_web_by_virtual_printing::service::{
+    normalize_url, Failure, LanguageAction, LanguageFilter, Processed, RequestOptions,
+    ScrapeService,
+};
+use scrape_web_by_virtual_printing::stable_hash::stable_hash;
+use scrape_web_by_virtual_printing::tables::Table;
+use scrape_web_by_virtual_printing::wayback::ArchivedSnapshot;
+use scrape_web_by_virtual_printing::{deterministic, doctor};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
 use std::time::Duration;
 use std::{fmt, str::FromStr};
-use std::{net::SocketAddr, path::PathBuf};
+use tokio::sync::Semaphore;
+use tower_http::{decompression::RequestDecompressionLayer, timeout::RequestBodyTimeoutLayer};
 use url::Url;
 
+/// Largest request body accepted; a scrape request is a few hundred bytes.
+const MAX_REQUEST_BODY_BYTES: usize = 1024 * 1024;
+/// Largest batch or crawl request body, which can list many URLs.
+const MAX_BATCH_BODY_BYTES: usize = 16 * 1024 * 1024;
+/// Read buffer size for a connection, which caps the size of request headers.
+const MAX_REQUEST_HEADER_BYTES: usize = 64 * 1024;
+/// Time a client gets to send its headers, and separately its body, before
+/// the connection is dropped.
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[tokio::main]
 async fn main() {
-    // let addr = SocketAddr::from(([10, 0, 0, 75], 5000));
-    let addr = SocketAddr::from(([10, 0, 0, 29], 3000));
-    let app = Router::new().route("/api", post(handle_post));
+    // reads SENTRY_DSN from the environment; panics are reported as well
+    let _sentry = sentry::init(sentry::ClientOptions {
+        release: sentry::release_name!(),
+        ..Default::default()
+    });
 
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    // RUST_LOG picks the level; request arrivals are logged at debug
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    // `doctor` checks the deployment and `scrape` scrapes one page instead
+    // of serving
+    match Cli::parse().command {
+        Some(Command::Doctor) => {
+            let healthy = doctor::run().await;
+            std::process::exit(if healthy { 0 } else { 1 });
+        }
+        Some(Command::Scrape(args)) => {
+            let scraped = cli::scrape(args).await;
+            std::process::exit(if scraped { 0 } else { 1 });
+        }
+        Some(Command::Serve) | None => {}
+    }
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("loading the configuration failed: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let addr = config.bind_addr;
+    let concurrency = Arc::new(ConcurrencyLimit::new(config.concurrency.clone()));
+    let service = match ScrapeService::new(config) {
+        Ok(service) => service,
+        Err(e) => {
+            error!("starting the scrape service failed: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let app = Router::new()
+        .route("/api", get(handle_get).post(handle_post))
+        .route(
+            "/api/batch",
+            post(handle_batch).layer(DefaultBodyLimit::max(MAX_BATCH_BODY_BYTES)),
+        )
+        .route(
+            "/api/crawl",
+            post(handle_crawl).layer(DefaultBodyLimit::max(MAX_BATCH_BODY_BYTES)),
+        )
+        .route("/api/screenshot", post(handle_screenshot))
+        .route("/api/pdf", get(handle_pdf_get).post(handle_pdf_post))
+        .route_layer(middleware::from_fn_with_state(
+            concurrency.clone(),
+            limit_concurrency,
+        ))
+        .route("/api/cache", delete(handle_cache_delete))
+        .route("/api/diagnostics", get(handle_diagnostics))
+        .route("/healthz", get(handle_healthz))
+        .route("/readyz", get(handle_readyz))
+        .route("/metrics", get(handle_metrics))
+        .route("/", get(playground))
+        .layer(middleware::from_fn(track_requests))
+        .layer(RequestDecompressionLayer::new())
+        .layer(DefaultBodyLimit::max(MAX_REQUEST_BODY_BYTES))
+        .layer(RequestBodyTimeoutLayer::new(REQUEST_READ_TIMEOUT))
+        .with_state(AppState {
+            service,
+            concurrency,
+        });
+
+    let builder = match systemd::activated_listener() {
+        Some(listener) => {
+            listener.set_nonblocking(true).unwrap();
+            axum::Server::from_tcp(listener).unwrap()
+        }
+        None => axum::Server::bind(&addr),
+    };
+    let server = builder
+        .http1_header_read_timeout(REQUEST_READ_TIMEOUT)
+        .http1_max_buf_size(MAX_REQUEST_HEADER_BYTES)
+        .serve(app.into_make_service());
+
+    systemd::notify("READY=1");
+    server.await.unwrap();
+}
+
+/// Static page for trying out `/api` from a browser.
+async fn playground() -> Html<&'static str> {
+    Html(include_str!("playground.html"))
+}
+
+#[derive(Clone)]
+struct AppState {
+    service: ScrapeService,
+    concurrency: Arc<ConcurrencyLimit>,
 }
 
-async fn handle_post(data: Json<Data>) -> impl IntoResponse {
-    let options = LaunchOptions {
-        headless: true,
-        window_size: Some((820, 1180)),
-        path: Some(
-            PathBuf::from_str("/home/jaykchen/projects/scrape-web-by-virtual-printing/chrome/linux-114.0.5735.133/chrome-linux64/chrome").unwrap(),
-        ),
+async fn handle_post(State(state): State<AppState>, data: Json<Data>) -> axum::response::Response {
+    debug!("received data: {:?}", data.url);
+    scrape_response(
+        &state,
+        &data.url,
+        &data.options,
+        data.fields.as_deref(),
+        None,
+    )
+    .await
+}
+
+/// `GET /api?url=...&format=html&mode=readability`: like `POST /api` with
+/// just those options, answering `If-None-Match` with 304 when the result
+/// is unchanged, so polling clients don't download the same text again.
+async fn handle_get(
+    State(state): State<AppState>,
+    Query(query): Query<ScrapeQuery>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    debug!("received data: {:?}", query.url);
+    let options = RequestOptions {
+        scrape: ScrapeOptions {
+            format: query.format,
+            mode: query.mode,
+            metadata: query.metadata,
+            ..Default::default()
+        },
         ..Default::default()
     };
+    let if_none_match = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok());
+    scrape_response(
+        &state,
+        &query.url,
+        &options,
+        query.fields.as_deref(),
+        if_none_match,
+    )
+    .await
+}
 
-    let browser = Browser::new(options).unwrap();
-    println!("Received data: {:?}", data.url);
+/// Scrapes `url` and answers with the text, or with JSON when translating
+/// or projecting `fields`. Successful responses carry a strong `ETag` of
+/// the body, and are answered with 304 when it's in `if_none_match`.
+async fn scrape_response(
+    state: &AppState,
+    url: &str,
+    options: &RequestOptions,
+    fields: Option<&str>,
+    if_none_match: Option<&str>,
+) -> axum::response::Response {
+    let mut response = match state.service.process(url, options).await {
+        Ok(processed) => {
+            let json = processed.translated_text.is_some()
+                || processed.metadata.is_some()
+                || processed.images.is_some()
+                || processed.tables.is_some()
+                || processed.archived.is_some()
+                || processed.archive_url.is_some()
+                || processed.pages.is_some()
+                || fields.is_some();
+            let (content_type, body) = if json {
+                let body = serde_json::to_value(JsonResponse {
+                    text: processed.text,
+                    translated_text: processed.translated_text,
+                    metadata: processed.metadata,
+                    images: processed.images,
+                    tables: processed.tables,
+                    archived: processed.archived,
+                    archive_url: processed.archive_url,
+                    pages: processed.pages,
+                    flagged_categories: processed.flagged_categories.clone(),
+                    timings: processed.timings.clone(),
+                    usage: processed.usage.clone(),
+                })
+                .unwrap();
+                let body = match fields {
+                    Some(fields) => fields::project(body, fields),
+                    None => body,
+                };
+                ("application/json", serde_json::to_vec(&body).unwrap())
+            } else if options.scrape.format == OutputFormat::Markdown
+                && options.scrape.mode != ExtractionMode::RawHtml
+            {
+                ("text/markdown; charset=utf-8", processed.text.into_bytes())
+            } else {
+                ("text/plain; charset=utf-8", processed.text.into_bytes())
+            };
 
-    if let Err(_) = Url::from_str(&data.url) {
-        return Response::builder()
-            .status(StatusCode::OK)
-            .body("parse target url failure".to_string())
-            .unwrap();
-    } else {
-        match text_to_use(&data.url, &browser).await {
-            Ok(res) => {
-                return Response::builder()
-                    .status(StatusCode::OK)
-                    .body(res)
-                    .unwrap();
+            let etag = content_etag(&body);
+            let mut response = if if_none_match.map_or(false, |tags| etag_matches(tags, &etag)) {
+                StatusCode::NOT_MODIFIED.into_response()
+            } else {
+                ([(header::CONTENT_TYPE, content_type)], body).into_response()
+            };
+            response
+                .headers_mut()
+                .insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+            insert_server_timing(&mut response, &processed.timings);
+            insert_usage(&mut response, processed.usage.as_ref());
+            if !processed.flagged_categories.is_empty() {
+                if let Ok(value) = HeaderValue::from_str(&processed.flagged_categories.join(",")) {
+                    response.headers_mut().insert("x-flagged-categories", value);
+                }
             }
-            Err(_) => {
-                return Response::builder()
-                    .status(StatusCode::OK)
-                    .body("failed to get text from webpage".to_string())
-                    .unwrap();
+            response
+        }
+        Err(Failure::LowQuality(body)) => {
+            let timings = body.timings.clone();
+            let usage = body.usage.clone();
+            let mut response = (StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response();
+            insert_server_timing(&mut response, &timings);
+            insert_usage(&mut response, usage.as_ref());
+            response
+        }
+        Err(Failure::Error(e)) => e.into_response(),
+    };
+    response.headers_mut().insert(
+        "x-extractor-version",
+        HeaderValue::from_static(EXTRACTOR_VERSION),
+    );
+    response
+}
+
+/// A strong entity tag for a response body: a hash of its bytes.
+fn content_etag(body: &[u8]) -> String {
+    format!("\"{:016x}\"", stable_hash(body))
+}
+
+/// Whether an `If-None-Match` header value names `etag`. The comparison is
+/// weak, as the header requires, so `W/` prefixes are ignored.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.strip_prefix("W/").unwrap_or(tag).to_string();
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || (!tag.is_empty() && opaque(tag) == opaque(etag)))
+}
+
+/// Scrapes every URL in the batch with the same options, at most
+/// `batch_concurrency` at a time, and reports each URL's outcome separately
+/// so one bad URL doesn't fail the rest.
+async fn handle_batch(
+    State(state): State<AppState>,
+    data: Json<BatchData>,
+) -> axum::response::Response {
+    let Json(BatchData {
+        urls,
+        options,
+        fields,
+        language_filter,
+    }) = data;
+    debug!("received batch of {} urls", urls.len());
+    if !language_filter
+        .as_ref()
+        .map_or(true, LanguageFilter::is_valid)
+    {
+        return ScrapeError::InvalidLanguage.into_response();
+    }
+
+    let limit = Arc::new(Semaphore::new(
+        state.service.config.batch_concurrency.max(1),
+    ));
+    let options = Arc::new(options);
+    let handles: Vec<_> = urls
+        .iter()
+        .cloned()
+        .map(|url| {
+            let state = state.clone();
+            let options = options.clone();
+            let limit = limit.clone();
+            tokio::spawn(async move {
+                let _permit = limit.acquire_owned().await.unwrap();
+                state.service.process(&url, &options).await
+            })
+        })
+        .collect();
+
+    let mut results = Vec::with_capacity(urls.len());
+    for (url, handle) in urls.into_iter().zip(handles) {
+        let res = handle
+            .await
+            .unwrap_or_else(|_| Err(ScrapeError::ExtractionFailed.into()));
+        let mut item = BatchItem::new(url, res);
+        if let Some(filter) = &language_filter {
+            item.filter_language(filter, &options);
+        }
+        results.push(item);
+    }
+
+    let mut body = serde_json::to_value(BatchResponse { results }).unwrap();
+    if let Some(fields) = &fields {
+        // paths name parts of each result
+        body["results"] = fields::project(body["results"].take(), fields);
+    }
+    ([("x-extractor-version", EXTRACTOR_VERSION)], Json(body)).into_response()
+}
+
+/// Crawls the site from `url`: scrapes it, then the pages it links to (and,
+/// with `sitemap`, those in its sitemap), a level at a time, until
+/// `max_depth` link hops or `max_pages` pages. Pages go through the same
+/// pipeline and options as `/api`, at most `batch_concurrency` at a time,
+/// and are reported like `/api/batch` results.
+async fn handle_crawl(
+    State(state): State<AppState>,
+    Json(data): Json<CrawlData>,
+) -> axum::response::Response {
+    debug!("received crawl from {:?}", data.url);
+
+    let seed = match Url::from_str(&data.url) {
+        Ok(seed) => seed,
+        Err(_) => return ScrapeError::InvalidUrl.into_response(),
+    };
+    let scope = match CrawlScope::new(&seed, &data.include, &data.exclude) {
+        Ok(scope) => scope,
+        Err(_) => return ScrapeError::InvalidCrawlPattern.into_response(),
+    };
+    if !data
+        .language_filter
+        .as_ref()
+        .map_or(true, LanguageFilter::is_valid)
+    {
+        return ScrapeError::InvalidLanguage.into_response();
+    }
+    let max_pages = data.max_pages.min(state.service.config.crawl_max_pages);
+    let mut options = data.options;
+    options.scrape.collect_links = true;
+    let options = Arc::new(options);
+    let limit = Arc::new(Semaphore::new(
+        state.service.config.batch_concurrency.max(1),
+    ));
+
+    let mut seen = HashSet::from([normalize_url(&seed)]);
+    // sitemap pages count as linked from the seed
+    let mut next = Vec::new();
+    if data.sitemap {
+        let sitemap_seed = seed.clone();
+        match tokio::task::spawn_blocking(move || crawl::sitemap_urls(&sitemap_seed)).await {
+            Ok(Ok(urls)) => next.extend(
+                urls.into_iter()
+                    .filter(|url| scope.allows(url) && seen.insert(normalize_url(url))),
+            ),
+            Ok(Err(e)) => debug!("no sitemap for {}: {}", seed, e),
+            Err(_) => {}
+        }
+    }
+
+    let mut level = vec![seed];
+    let mut results = Vec::new();
+    for depth in 0..=data.max_depth {
+        level.truncate(max_pages - results.len());
+        if level.is_empty() {
+            break;
+        }
+
+        let handles: Vec<_> = level
+            .iter()
+            .map(|url| {
+                let state = state.clone();
+                let options = options.clone();
+                let limit = limit.clone();
+                let url = url.to_string();
+                tokio::spawn(async move {
+                    let _permit = limit.acquire_owned().await.unwrap();
+                    state.service.process(&url, &options).await
+                })
+            })
+            .collect();
+
+        for (url, handle) in std::mem::take(&mut level).into_iter().zip(handles) {
+            let res = handle
+                .await
+                .unwrap_or_else(|_| Err(ScrapeError::ExtractionFailed.into()));
+            if let Ok(processed) = &res {
+                let links = processed
+                    .links
+                    .iter()
+                    .filter_map(|link| Url::parse(link).ok())
+                    .filter(|link| scope.allows(link) && seen.insert(normalize_url(link)));
+                next.extend(links);
+            }
+            let mut item = BatchItem::new(url.to_string(), res);
+            if let Some(filter) = &data.language_filter {
+                item.filter_language(filter, &options);
             }
+            results.push(CrawlItem { depth, item });
         }
+        level.append(&mut next);
+    }
+
+    (
+        [("x-extractor-version", EXTRACTOR_VERSION)],
+        Json(CrawlResponse { results }),
+    )
+        .into_response()
+}
+
+/// `DELETE /api/cache`: drops every cached scrape, or only those of one page
+/// with `?url=...`.
+async fn handle_cache_delete(
+    State(state): State<AppState>,
+    Query(params): Query<Params>,
+) -> axum::response::Response {
+    let removed = match params.url {
+        Some(url) => match Url::from_str(&url) {
+            Ok(url) => state
+                .service
+                .cache
+                .remove(Some(&format!("{} ", normalize_url(&url)))),
+            Err(_) => return ScrapeError::InvalidUrl.into_response(),
+        },
+        None => state.service.cache.remove(None),
+    };
+    Json(serde_json::json!({ "removed": removed })).into_response()
+}
+
+/// `GET /api/diagnostics`: the diagnostics bundles of recent failed
+/// scrapes, newest first, or only those of one page with `?url=...`. Needs
+/// `Authorization: Bearer <diagnostics.token>`.
+async fn handle_diagnostics(
+    State(state): State<AppState>,
+    Query(params): Query<Params>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let token = state.service.config.diagnostics.token.as_deref();
+    if !bearer_matches(&headers, token) {
+        return ScrapeError::Unauthorized.into_response();
+    }
+    let bundles = match params.url {
+        Some(url) => match Url::from_str(&url) {
+            Ok(url) => state.service.diagnostics.get(Some(url.as_str())),
+            Err(_) => return ScrapeError::InvalidUrl.into_response(),
+        },
+        None => state.service.diagnostics.get(None),
+    };
+    Json(serde_json::json!({ "bundles": bundles })).into_response()
+}
+
+/// Whether the request's `Authorization` header carries `token`; never when
+/// there's no token to compare with.
+fn bearer_matches(headers: &HeaderMap, token: Option<&str>) -> bool {
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match (given, token) {
+        (Some(given), Some(token)) if !token.is_empty() => given.trim() == token,
+        _ => false,
+    }
+}
+
+/// `GET /metrics`: request, scrape latency, phase timing, cache, browser
+/// restart, extractor and canary counters in the Prometheus text format.
+async fn handle_metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(),
+    )
+}
+
+/// `GET /healthz`: the service's health, answered with 200 as long as the
+/// process serves requests; for liveness probes.
+async fn handle_healthz(State(state): State<AppState>) -> Json<HealthResponse> {
+    Json(health(&state).await)
+}
+
+/// `GET /readyz`: the same report, answered with 503 while Chrome can't be
+/// launched, pdfium can't be bound or the request queue is full, so load
+/// balancers and readiness probes route around the instance.
+async fn handle_readyz(State(state): State<AppState>) -> axum::response::Response {
+    let health = health(&state).await;
+    let status = if health.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(health)).into_response()
+}
+
+async fn health(state: &AppState) -> HealthResponse {
+    let chrome_error = state.service.pool.launch_health().await;
+    let pdfium_path = state.service.config.pdfium_path.clone();
+    let pdfium_error = tokio::task::spawn_blocking(move || {
+        bind_pdfium(pdfium_path.as_deref())
+            .err()
+            .map(|e| e.to_string())
+    })
+    .await
+    .unwrap_or_else(|e| Some(e.to_string()));
+    let (browsers, active_tabs) = state.service.pool.status();
+    let (in_flight_requests, queued_requests) = state.concurrency.status();
+
+    HealthResponse {
+        ready: chrome_error.is_none()
+            && pdfium_error.is_none()
+            && !state.concurrency.is_saturated(),
+        chrome: ComponentHealth::from(chrome_error),
+        pdfium: ComponentHealth::from(pdfium_error),
+        browsers,
+        active_tabs,
+        in_flight_requests,
+        queued_requests,
+    }
+}
+
+/// Navigates to the URL and answers with a screenshot of it as
+/// `image/png`, `image/jpeg` or `image/webp`.
+async fn handle_screenshot(
+    State(state): State<AppState>,
+    Json(data): Json<ScreenshotData>,
+) -> axum::response::Response {
+    debug!("received screenshot request: {:?}", data.url);
+
+    let deadline = state.service.config.deadline(data.timeout_ms);
+    match tokio::time::timeout(deadline, screenshot_response(&state, &data)).await {
+        Ok(response) => response,
+        Err(_) => ScrapeError::Timeout { phase: "request" }.into_response(),
+    }
+}
+
+/// Checks out a tab and captures the screenshot; dropped with its tab when
+/// the request's deadline passes.
+async fn screenshot_response(state: &AppState, data: &ScreenshotData) -> axum::response::Response {
+    let lease = match state
+        .service
+        .checkout(
+            &data.url,
+            &data.host_overrides,
+            data.proxy.as_ref(),
+            &data.cookies,
+            &data.headers,
+            Rendering {
+                deterministic: data.deterministic,
+                javascript: data.javascript != Some(false),
+            },
+        )
+        .await
+    {
+        Ok(lease) => lease,
+        Err(e) => return e.into_response(),
+    };
+
+    let mut timings = Timings::default();
+    match capture_screenshot(
+        data,
+        &lease.tab,
+        &state.service.config.timeouts,
+        &mut timings,
+    )
+    .await
+    {
+        Ok(image) => {
+            let mut response =
+                ([(header::CONTENT_TYPE, data.format.mime_type())], image).into_response();
+            insert_server_timing(&mut response, &timings);
+            response
+        }
+        Err(e) => {
+            report_error(&e, "screenshot", &data.url);
+            ScrapeError::from_pipeline(&e, ScrapeError::RenderFailed).into_response()
+        }
+    }
+}
+
+/// `GET /api/pdf?url=...&paper=a4&landscape=true`: the page as printed,
+/// before any text is extracted from it.
+async fn handle_pdf_get(
+    State(state): State<AppState>,
+    Query(data): Query<PdfData>,
+) -> axum::response::Response {
+    pdf_response(&state, data).await
+}
+
+/// `POST /api/pdf` with the same fields as the `GET` query, as JSON.
+async fn handle_pdf_post(
+    State(state): State<AppState>,
+    Json(data): Json<PdfData>,
+) -> axum::response::Response {
+    pdf_response(&state, data).await
+}
+
+async fn pdf_response(state: &AppState, data: PdfData) -> axum::response::Response {
+    debug!("received pdf request: {:?}", data.url);
+
+    let deadline = state.service.config.deadline(data.timeout_ms);
+    match tokio::time::timeout(deadline, render_pdf(state, &data)).await {
+        Ok(response) => response,
+        Err(_) => ScrapeError::Timeout { phase: "request" }.into_response(),
+    }
+}
+
+/// Checks out a tab and prints the page; dropped with its tab when the
+/// request's deadline passes.
+async fn render_pdf(state: &AppState, data: &PdfData) -> axum::response::Response {
+    let lease = match state
+        .service
+        .checkout(
+            &data.url,
+            &data.host_overrides,
+            data.proxy.as_ref(),
+            &data.cookies,
+            &data.headers,
+            Rendering {
+                deterministic: data.deterministic,
+                javascript: data.javascript != Some(false),
+            },
+        )
+        .await
+    {
+        Ok(lease) => lease,
+        Err(e) => return e.into_response(),
+    };
+
+    let mut timings = Timings::default();
+    let pdf_options = data.print_options();
+    match print_page(
+        &data.url,
+        &lease.tab,
+        &data.wait,
+        pdf_options,
+        &state.service.config.timeouts,
+        &mut timings,
+    )
+    .await
+    {
+        Ok(mut pdf) => {
+            if data.deterministic {
+                deterministic::pin_pdf(&mut pdf);
+            }
+            let mut response = ([(header::CONTENT_TYPE, "application/pdf")], pdf).into_response();
+            insert_server_timing(&mut response, &timings);
+            response
+        }
+        Err(e) => {
+            report_error(&e, "print_to_pdf", &data.url);
+            ScrapeError::from_pipeline(&e, ScrapeError::RenderFailed).into_response()
+        }
+    }
+}
+
+/// Adds the Chrome CPU time and peak memory of the scrape as
+/// `x-chrome-cpu-ms` and `x-chrome-peak-rss-bytes`, plus
+/// `x-chrome-usage-shared: true` when other requests used the browser too.
+fn insert_usage<B>(response: &mut Response<B>, usage: Option<&ResourceUsage>) {
+    if let Some(usage) = usage {
+        let headers = response.headers_mut();
+        headers.insert("x-chrome-cpu-ms", HeaderValue::from(usage.cpu_ms));
+        headers.insert(
+            "x-chrome-peak-rss-bytes",
+            HeaderValue::from(usage.peak_rss_bytes),
+        );
+        if usage.shared {
+            headers.insert("x-chrome-usage-shared", HeaderValue::from_static("true"));
+        }
+    }
+}
+
+fn insert_server_timing<B>(response: &mut Response<B>, timings: &Timings) {
+    if let Ok(value) = HeaderValue::from_str(&timings.server_timing()) {
+        response.headers_mut().insert("server-timing", value);
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Data {
     url: String,
+    /// Comma-separated dot paths of the JSON response to return, e.g.
+    /// `text,timings.navigate_ms`; errors are always returned whole.
+    fields: Option<String>,
+    #[serde(flatten)]
+    options: RequestOptions,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct BatchData {
+    urls: Vec<String>,
+    /// Like `Data::fields`, for each entry of `results`.
+    fields: Option<String>,
+    /// Discard or flag pages not written in one of the allowed languages.
+    #[serde(default)]
+    language_filter: Option<LanguageFilter>,
+    /// Applied to every URL in the batch.
+    #[serde(flatten)]
+    options: RequestOptions,
+}
+
+#[derive(Debug, Deserialize)]
+struct CrawlData {
+    /// Where the crawl starts; only pages on this URL's host are visited.
+    url: String,
+    /// Link hops to follow from `url`; 0 scrapes just `url`.
+    #[serde(default = "default_max_depth")]
+    max_depth: usize,
+    /// Pages scraped at most, capped at the configured `crawl_max_pages`.
+    #[serde(default = "default_max_pages")]
+    max_pages: usize,
+    /// Regexes matched against discovered URLs: only those matching one of
+    /// `include` (when any are given) and none of `exclude` are visited.
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    /// Also visit the pages listed in the site's `/sitemap.xml`.
+    #[serde(default)]
+    sitemap: bool,
+    /// Like `BatchData::language_filter`. Links of discarded pages are still
+    /// followed.
+    #[serde(default)]
+    language_filter: Option<LanguageFilter>,
+    /// Applied to every page of the crawl.
+    #[serde(flatten)]
+    options: RequestOptions,
+}
+
+fn default_max_depth() -> usize {
+    1
+}
+
+fn default_max_pages() -> usize {
+    20
+}
+
+#[derive(Debug, Deserialize)]
+struct ScreenshotData {
+    url: String,
+    #[serde(default)]
+    format: ImageFormat,
+    /// Compression quality from 0 to 100, for JPEG and WebP.
+    quality: Option<u32>,
+    /// Capture the whole scrollable page instead of just the viewport.
+    #[serde(default)]
+    full_page: bool,
+    /// Viewport size in CSS pixels; defaults to the pooled browsers' window.
+    width: Option<u32>,
+    height: Option<u32>,
+    /// Device pixels per CSS pixel, e.g. 2 for a retina-like capture.
+    device_scale: Option<f64>,
+    #[serde(default)]
+    wait: WaitStrategy,
+    #[serde(default)]
+    host_overrides: BTreeMap<String, IpAddr>,
+    proxy: Option<Proxy>,
+    #[serde(default)]
+    cookies: Vec<Network::CookieParam>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    /// Like `ScrapeOptions::deterministic`.
+    #[serde(default)]
+    deterministic: bool,
+    /// Like `ScrapeOptions::javascript`.
+    javascript: Option<bool>,
+    /// Like `ScrapeOptions::timeout_ms`.
+    timeout_ms: Option<u64>,
+}
+
+/// Print settings for `/api/pdf`. Sizes are in inches; explicit
+/// `paper_width`/`paper_height` win over `paper`, and a per-side margin wins
+/// over `margin`.
+#[derive(Debug, Deserialize)]
+struct PdfData {
+    url: String,
+    #[serde(default)]
+    paper: PaperPreset,
+    paper_width: Option<f64>,
+    paper_height: Option<f64>,
+    margin: Option<f64>,
+    margin_top: Option<f64>,
+    margin_bottom: Option<f64>,
+    margin_left: Option<f64>,
+    margin_right: Option<f64>,
+    /// Pages to print, e.g. `1-5, 8`; all of them without it.
+    page_ranges: Option<String>,
+    #[serde(default)]
+    landscape: bool,
+    #[serde(default)]
+    print_background: bool,
+    #[serde(default)]
+    prefer_css_page_size: bool,
+    /// JSON body only, like `host_overrides`.
+    #[serde(default)]
+    wait: WaitStrategy,
+    /// JSON body only; maps can't be passed in a query string.
+    #[serde(default)]
+    host_overrides: BTreeMap<String, IpAddr>,
+    proxy: Option<Proxy>,
+    /// JSON body only, like `host_overrides`.
+    #[serde(default)]
+    cookies: Vec<Network::CookieParam>,
+    /// JSON body only, like `host_overrides`.
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    /// Like `ScrapeOptions::deterministic`; the print date and document ID
+    /// in the PDF are pinned too, so the bytes match across prints.
+    #[serde(default)]
+    deterministic: bool,
+    /// Like `ScrapeOptions::javascript`.
+    javascript: Option<bool>,
+    /// Like `ScrapeOptions::timeout_ms`.
+    timeout_ms: Option<u64>,
+}
+
+impl PdfData {
+    fn print_options(&self) -> PrintToPdfOptions {
+        let (paper_width, paper_height) = self.paper.size_inches();
+        let margin = self.margin.unwrap_or(0.1);
+        PrintToPdfOptions {
+            landscape: Some(self.landscape),
+            display_header_footer: Some(false),
+            print_background: Some(self.print_background),
+            paper_width: Some(self.paper_width.unwrap_or(paper_width)),
+            paper_height: Some(self.paper_height.unwrap_or(paper_height)),
+            margin_top: Some(self.margin_top.unwrap_or(margin)),
+            margin_bottom: Some(self.margin_bottom.unwrap_or(margin)),
+            margin_left: Some(self.margin_left.unwrap_or(margin)),
+            margin_right: Some(self.margin_right.unwrap_or(margin)),
+            page_ranges: self.page_ranges.clone(),
+            ignore_invalid_page_ranges: Some(true),
+            prefer_css_page_size: Some(self.prefer_css_page_size),
+            transfer_mode: None,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ImageFormat {
+    #[default]
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl ImageFormat {
+    fn mime_type(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Webp => "image/webp",
+        }
+    }
+
+    fn cdp_format(self) -> Page::CaptureScreenshotFormatOption {
+        match self {
+            ImageFormat::Png => Page::CaptureScreenshotFormatOption::Png,
+            ImageFormat::Jpeg => Page::CaptureScreenshotFormatOption::Jpeg,
+            ImageFormat::Webp => Page::CaptureScreenshotFormatOption::Webp,
+        }
+    }
+}
+
+/// A successful `/api` result as JSON, answered instead of plain text when
+/// translating, returning metadata, images, tables or pages, scraping or
+/// saving an archived snapshot, or when `fields` is given.
 #[derive(Debug, serde::Serialize)]
-struct MyResponse {
+struct JsonResponse {
     text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    translated_text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<PageMetadata>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    images: Option<Vec<ArticleImage>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tables: Option<Vec<Table>>,
+    /// Set when the text is from a Wayback Machine snapshot.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    archived: Option<ArchivedSnapshot>,
+    /// Set when `save_to_archive` queued the page for the Wayback Machine.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    archive_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pages: Option<Vec<PageText>>,
+    /// Blocklist categories the page's domain is flagged under.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    flagged_categories: Vec<String>,
+    timings: Timings,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<ResourceUsage>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct BatchResponse {
+    results: Vec<BatchItem>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct CrawlResponse {
+    /// In the order the pages were visited.
+    results: Vec<CrawlItem>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct CrawlItem {
+    /// Link hops from the seed URL.
+    depth: usize,
+    #[serde(flatten)]
+    item: BatchItem,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct BatchItem {
+    url: String,
+    /// `"ok"`, `"error"`, or `"filtered"` when a `language_filter`
+    /// discarded the page.
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    translated_text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<PageMetadata>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    images: Option<Vec<ArticleImage>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tables: Option<Vec<Table>>,
+    /// Set when the text is from a Wayback Machine snapshot.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    archived: Option<ArchivedSnapshot>,
+    /// Set when `save_to_archive` queued the page for the Wayback Machine.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    archive_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pages: Option<Vec<PageText>>,
+    /// Blocklist categories the page's domain is flagged under.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    flagged_categories: Vec<String>,
+    /// ISO 639-3 code of the detected language, with a `language_filter`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<String>,
+    /// Set when a `language_filter` flags instead of discarding.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language_allowed: Option<bool>,
+    /// A `ScrapeError` code, `low_quality_extraction` or
+    /// `language_not_allowed`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timings: Option<Timings>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<ResourceUsage>,
+}
+
+impl BatchItem {
+    fn new(url: String, res: Result<Processed, Failure>) -> Self {
+        match res {
+            Ok(processed) => BatchItem {
+                url,
+                status: "ok",
+                text: Some(processed.text),
+                translated_text: processed.translated_text,
+                metadata: processed.metadata,
+                images: processed.images,
+                tables: processed.tables,
+                archived: processed.archived,
+                archive_url: processed.archive_url,
+                pages: processed.pages,
+                flagged_categories: processed.flagged_categories,
+                language: None,
+                language_allowed: None,
+                error: None,
+                message: None,
+                timings: Some(processed.timings),
+                usage: processed.usage,
+            },
+            Err(Failure::LowQuality(rejection)) => BatchItem {
+                url,
+                status: "error",
+                text: rejection.text,
+                translated_text: None,
+                metadata: None,
+                images: None,
+                tables: None,
+                archived: None,
+                archive_url: None,
+                pages: None,
+                flagged_categories: Vec::new(),
+                language: None,
+                language_allowed: None,
+                error: Some(rejection.error.to_string()),
+                message: None,
+                timings: Some(rejection.timings),
+                usage: rejection.usage,
+            },
+            Err(Failure::Error(e)) => BatchItem {
+                url,
+                status: "error",
+                text: None,
+                translated_text: None,
+                metadata: None,
+                images: None,
+                tables: None,
+                archived: None,
+                archive_url: None,
+                pages: None,
+                flagged_categories: Vec::new(),
+                language: None,
+                language_allowed: None,
+                error: Some(e.code().to_string()),
+                message: Some(e.to_string()),
+                timings: None,
+                usage: None,
+            },
+        }
+    }
+
+    /// Detects the language of a successful item and discards or flags it
+    /// when `filter` doesn't allow it.
+    fn filter_language(&mut self, filter: &LanguageFilter, options: &RequestOptions) {
+        let (language, allowed) = match &self.text {
+            Some(text) if self.status == "ok" => filter.detect(options, text),
+            _ => return,
+        };
+        self.language = language;
+
+        match filter.action {
+            LanguageAction::Flag => self.language_allowed = Some(allowed),
+            LanguageAction::Discard if !allowed => {
+                self.status = "filtered";
+                self.error = Some("language_not_allowed".to_string());
+                self.text = None;
+                self.translated_text = None;
+                self.metadata = None;
+                self.images = None;
+                self.tables = None;
+                self.pages = None;
+            }
+            LanguageAction::Discard => {}
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct HealthResponse {
+    /// Whether the instance should get traffic; `/readyz` answers 503 when not.
+    ready: bool,
+    chrome: ComponentHealth,
+    pdfium: ComponentHealth,
+    /// Chrome processes in the pool.
+    browsers: usize,
+    /// Tabs checked out of the pool.
+    active_tabs: usize,
+    /// API requests being worked on.
+    in_flight_requests: usize,
+    /// API requests waiting for a slot.
+    queued_requests: usize,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct ComponentHealth {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl From<Option<String>> for ComponentHealth {
+    fn from(error: Option<String>) -> Self {
+        ComponentHealth {
+            ok: error.is_none(),
+            error,
+        }
+    }
+}
+
+/// Query string of `GET /api`. Other options need `POST /api`, as maps and
+/// flattened numbers can't be read from a query string.
+#[derive(Debug, Deserialize)]
+struct ScrapeQuery {
+    url: String,
+    #[serde(default)]
+    format: OutputFormat,
+    #[serde(default)]
+    mode: ExtractionMode,
+    #[serde(default)]
+    metadata: bool,
+    fields: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -95,81 +1166,117 @@ where
     }
 }
 
-async fn get_webpage_text_headless(url: &str, tab: &Tab) -> anyhow::Result<String> {
-    tab.navigate_to(url)?;
-    tab.wait_for_element_with_custom_timeout("body", Duration::from_secs(7))?;
-
-    let pdf_options: Option<PrintToPdfOptions> = Some(PrintToPdfOptions {
-        landscape: Some(false),
-        display_header_footer: Some(false),
-        print_background: Some(false),
-        paper_width: Some(11.0),
-        paper_height: Some(17.0),
-        margin_top: Some(0.1),
-        margin_bottom: Some(0.1),
-        margin_left: Some(0.1),
-        margin_right: Some(0.1),
-        ignore_invalid_page_ranges: Some(true),
-        prefer_css_page_size: Some(false),
-        transfer_mode: None,
-        ..Default::default()
-    });
-
-    let pdf_data = tab.print_to_pdf(pdf_options)?;
-
-    let pdf_as_vec = pdf_data.to_vec();
-    let text = Pdfium::new(
-        Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path(
-            "/home/jaykchen/pdfium/lib/",
-            // "/Users/jaykchen/Downloads/pdfium-mac-arm64/lib/libpdfium.dylib",
-        ))
-        .or_else(|_| Pdfium::bind_to_system_library())?,
-    )
-    .load_pdf_from_byte_vec(pdf_as_vec, Some(""))?
-    .pages()
-    .iter()
-    .map(|page| page.text().unwrap().all())
-    .collect::<Vec<String>>()
-    .join(" ");
-
-    Ok(text)
-}
-
-pub async fn get_html_headless(url: &str, tab: &Tab) -> anyhow::Result<String> {
-    tab.navigate_to(url)?;
-    tab.wait_for_element_with_custom_timeout("body", Duration::from_secs(7))?;
-    let text = tab.get_content()?;
-    Ok(text)
-}
+/// Applies the requested viewport, loads the page and captures it, clipped
+/// to the full document size when `full_page` is set.
+async fn capture_screenshot(
+    data: &ScreenshotData,
+    tab: &Arc<Tab>,
+    timeouts: &Timeouts,
+    timings: &mut Timings,
+) -> anyhow::Result<Vec<u8>> {
+    if data.width.is_some() || data.height.is_some() || data.device_scale.is_some() {
+        let metrics = Emulation::SetDeviceMetricsOverride {
+            width: data.width.unwrap_or(WINDOW_SIZE.0),
+            height: data.height.unwrap_or(WINDOW_SIZE.1),
+            // 0 keeps the browser's own scale factor
+            device_scale_factor: data.device_scale.unwrap_or(0.0),
+            mobile: false,
+            scale: None,
+            screen_width: None,
+            screen_height: None,
+            position_x: None,
+            position_y: None,
+            dont_set_visible_size: None,
+            screen_orientation: None,
+            viewport: None,
+            display_feature: None,
+        };
+        on_tab(tab, move |tab| {
+            tab.call_method(metrics)?;
+            Ok(())
+        })
+        .await?;
+    }
 
-pub async fn extract_article_text_from_html(url: &str, html_str: String) -> anyhow::Result<String> {
-    let parsed_url = Url::parse(url)?;
-    let scheme = parsed_url.scheme();
-    let host = parsed_url.host_str().unwrap_or("");
-    let base_url = Url::parse(&format!("{}://{}", scheme, host))?;
+    navigate(&data.url, tab, &data.wait, timeouts, timings).await?;
 
-    let res = Readability::extract(&html_str, Some(base_url)).await?;
-    let output = html2text::from_read(res.to_string().as_bytes(), 80);
+    let clip = if data.full_page {
+        let dimension = |js: &'static str| async move {
+            on_tab(tab, move |tab| Ok(tab.evaluate(js, false)?.value))
+                .await?
+                .and_then(|v| v.as_f64())
+                .ok_or_else(|| anyhow!("could not measure the page"))
+        };
+        Some(Page::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: dimension("document.documentElement.scrollWidth").await?,
+            height: dimension("document.documentElement.scrollHeight").await?,
+            scale: 1.0,
+        })
+    } else {
+        None
+    };
 
-    Ok(output)
+    let shot_tab = tab.clone();
+    let format = data.format.cdp_format();
+    let quality = match data.format {
+        ImageFormat::Png => None,
+        _ => data.quality.map(|q| q.min(100)),
+    };
+    run_blocking_phase("screenshot", timeouts.screenshot(), timings, move || {
+        shot_tab.capture_screenshot(format, quality, clip, true)
+    })
+    .await
 }
 
-pub async fn text_to_use(url: &str, browser: &Browser) -> anyhow::Result<String> {
-    let tab = browser.wait_for_initial_tab().unwrap();
-
-    let pdf_text = get_webpage_text_headless(url, &tab).await?;
-    let html_str = get_html_headless(url, &tab).await?;
-    let readah_text = extract_article_text_from_html(url, html_str).await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let readah_text_len = readah_text.split_whitespace().count();
-    let pdf_text_len = pdf_text.split_whitespace().count();
+    #[test]
+    fn etags_are_quoted_and_follow_the_body() {
+        let etag = content_etag(b"some text");
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+        assert_eq!(etag, content_etag(b"some text"));
+        assert_ne!(etag, content_etag(b"other text"));
+    }
 
-    let lots_of_text_on_page = pdf_text_len > 999;
-    let readah_sees_lots_of_texts = readah_text_len > 500;
+    #[test]
+    fn strong_and_weak_tags_match_weakly() {
+        assert!(etag_matches("\"abc\"", "\"abc\""));
+        assert!(etag_matches("W/\"abc\"", "\"abc\""));
+        assert!(etag_matches("\"abc\"", "W/\"abc\""));
+        assert!(!etag_matches("\"abd\"", "\"abc\""));
+        assert!(!etag_matches("abc", "\"abc\""));
+    }
 
-    if lots_of_text_on_page && readah_sees_lots_of_texts {
-        return Ok(readah_text.to_string());
+    #[test]
+    fn any_tag_of_a_list_or_a_star_matches() {
+        assert!(etag_matches("\"x\", W/\"abc\" ,\"y\"", "\"abc\""));
+        assert!(etag_matches("*", "\"abc\""));
+        assert!(!etag_matches("\"x\", \"y\"", "\"abc\""));
     }
 
-    Ok(pdf_text.to_string())
+    #[test]
+    fn an_empty_header_matches_nothing() {
+        assert!(!etag_matches("", "\"abc\""));
+        assert!(!etag_matches(" , ", "\"abc\""));
+        assert!(!etag_matches("", ""));
+    }
+    #[test]
+    fn diagnostics_need_the_configured_bearer_token() {
+        let mut headers = HeaderMap::new();
+        assert!(!bearer_matches(&headers, Some("secret")));
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer secret"),
+        );
+        assert!(bearer_matches(&headers, Some("secret")));
+        assert!(!bearer_matches(&headers, Some("other")));
+        assert!(!bearer_matches(&headers, None));
+        assert!(!bearer_matches(&headers, Some("")));
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("secret"));
+        assert!(!bearer_matches(&headers, Some("secret")));
+    }
 }
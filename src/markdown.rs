@@ -0,0 +1,438 @@
+use html5ever::{parse_document, tendril::TendrilSink};
+use markup5ever_rcdom::{Handle, NodeData, RcDom};
+use regex::Regex;
+use std::sync::OnceLock;
+
+/// Elements laid out as their own paragraph.
+const BLOCKS: [&str; 16] = [
+    "p",
+    "div",
+    "section",
+    "article",
+    "header",
+    "footer",
+    "main",
+    "aside",
+    "nav",
+    "figure",
+    "figcaption",
+    "dl",
+    "dt",
+    "dd",
+    "details",
+    "summary",
+];
+
+/// Converts article HTML, such as Readability's output, to Markdown.
+/// Headings, lists (nested and numbered), code blocks, block quotes,
+/// tables, links, images and emphasis are kept; other elements contribute
+/// their text. The HTML is parsed here rather than in a browser tab, so the
+/// page it came from never sees it.
+pub fn html_to_markdown(html: &str) -> String {
+    let dom = parse_document(RcDom::default(), Default::default()).one(html);
+    let markdown = match find_element(&dom.document, "body") {
+        Some(body) => convert(&body, 0),
+        None => String::new(),
+    };
+
+    static TRAILING_SPACE: OnceLock<Regex> = OnceLock::new();
+    static BLANK_LINES: OnceLock<Regex> = OnceLock::new();
+    let markdown = TRAILING_SPACE
+        .get_or_init(|| Regex::new(r"[ \t]+\n").unwrap())
+        .replace_all(&markdown, "\n");
+    let markdown = BLANK_LINES
+        .get_or_init(|| Regex::new(r"\n{3,}").unwrap())
+        .replace_all(&markdown, "\n\n");
+    markdown.trim().to_string()
+}
+
+fn convert(node: &Handle, depth: usize) -> String {
+    let tag = match &node.data {
+        NodeData::Text { contents } => {
+            return escape_text(&collapse_whitespace(&contents.borrow()))
+        }
+        NodeData::Element { name, .. } => name.local.to_ascii_lowercase(),
+        _ => return String::new(),
+    };
+
+    match tag.as_str() {
+        "script" | "style" | "noscript" | "template" => String::new(),
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            let level = usize::from(tag.as_bytes()[1] - b'0');
+            format!(
+                "\n\n{} {}\n\n",
+                "#".repeat(level),
+                inner(node, depth).trim()
+            )
+        }
+        "br" => "\n".to_string(),
+        "hr" => "\n\n---\n\n".to_string(),
+        "strong" | "b" => wrap("**", inner(node, depth).trim()),
+        "em" | "i" => wrap("*", inner(node, depth).trim()),
+        "code" => inline_code(&text_content(node)),
+        "pre" => {
+            let class = find_element(node, "code")
+                .and_then(|code| attribute(&code, "class"))
+                .or_else(|| attribute(node, "class"))
+                .unwrap_or_default();
+            let lang = class
+                .split_whitespace()
+                .find_map(|class| class.strip_prefix("language-"))
+                .unwrap_or_default();
+            let code = text_content(node);
+            let fence = "`".repeat(longest_run(&code, '`').max(2) + 1);
+            format!(
+                "\n\n{}{}\n{}\n{}\n\n",
+                fence,
+                lang,
+                code.strip_suffix('\n').unwrap_or(&code),
+                fence
+            )
+        }
+        "a" => {
+            let text = inner(node, depth).trim().to_string();
+            let href = attribute(node, "href").unwrap_or_default();
+            let script = href
+                .get(..11)
+                .map_or(false, |scheme| scheme.eq_ignore_ascii_case("javascript:"));
+            if text.is_empty() || href.is_empty() || script {
+                text
+            } else {
+                format!("[{}]({})", text, link_destination(&href))
+            }
+        }
+        "img" => match attribute(node, "src") {
+            Some(src) if !src.is_empty() => {
+                let alt = attribute(node, "alt").unwrap_or_default();
+                format!(
+                    "![{}]({})",
+                    escape_text(&collapse_whitespace(&alt)),
+                    link_destination(&src)
+                )
+            }
+            _ => String::new(),
+        },
+        "ul" | "ol" => {
+            let mut n = attribute(node, "start")
+                .and_then(|start| start.trim().parse::<i64>().ok())
+                .unwrap_or(1);
+            let items: Vec<String> = element_children(node)
+                .filter(|child| element_name(child).as_deref() == Some("li"))
+                .map(|li| {
+                    let marker = if tag == "ol" {
+                        n += 1;
+                        format!("{}. ", n - 1)
+                    } else {
+                        "- ".to_string()
+                    };
+                    let indent = " ".repeat(marker.len());
+                    let content = collapse_blank_lines(&inner(&li, depth + 1));
+                    let lines: Vec<String> = content
+                        .trim()
+                        .split('\n')
+                        .enumerate()
+                        .map(|(i, line)| {
+                            if i > 0 && !line.is_empty() {
+                                format!("{}{}", indent, line)
+                            } else {
+                                line.to_string()
+                            }
+                        })
+                        .collect();
+                    marker + &lines.join("\n")
+                })
+                .collect();
+            let gap = if depth > 0 { "\n" } else { "\n\n" };
+            format!("{}{}{}", gap, items.join("\n"), gap)
+        }
+        "blockquote" => {
+            let content = collapse_blank_lines(&inner(node, depth));
+            let lines: Vec<String> = content
+                .trim()
+                .split('\n')
+                .map(|line| {
+                    if line.is_empty() {
+                        ">".to_string()
+                    } else {
+                        format!("> {}", line)
+                    }
+                })
+                .collect();
+            format!("\n\n{}\n\n", lines.join("\n"))
+        }
+        "table" => {
+            let mut rows = Vec::new();
+            collect_rows(node, &mut rows);
+            let rows: Vec<Vec<String>> = rows
+                .iter()
+                .map(|tr| {
+                    element_children(tr)
+                        .map(|cell| {
+                            let text = inner(&cell, depth);
+                            let words: Vec<&str> = text.split_whitespace().collect();
+                            words.join(" ").replace('|', "\\|")
+                        })
+                        .collect::<Vec<String>>()
+                })
+                .filter(|cells| !cells.is_empty())
+                .collect();
+            let line = |cells: &[String]| format!("| {} |", cells.join(" | "));
+            match rows.split_first() {
+                Some((header, body)) => {
+                    let rule = vec!["---".to_string(); header.len()];
+                    let mut lines = vec![line(header), line(&rule)];
+                    lines.extend(body.iter().map(|cells| line(cells)));
+                    format!("\n\n{}\n\n", lines.join("\n"))
+                }
+                None => String::new(),
+            }
+        }
+        tag if BLOCKS.contains(&tag) => format!("\n\n{}\n\n", inner(node, depth).trim()),
+        _ => inner(node, depth),
+    }
+}
+
+fn inner(node: &Handle, depth: usize) -> String {
+    node.children
+        .borrow()
+        .iter()
+        .map(|child| convert(child, depth))
+        .collect()
+}
+
+fn wrap(marker: &str, content: &str) -> String {
+    if content.is_empty() {
+        String::new()
+    } else {
+        format!("{}{}{}", marker, content, marker)
+    }
+}
+
+/// Inline code in a backtick fence longer than any run of backticks in it,
+/// padded when it starts or ends with one.
+fn inline_code(code: &str) -> String {
+    if code.is_empty() {
+        return String::new();
+    }
+    let fence = "`".repeat(longest_run(code, '`') + 1);
+    let pad = if code.starts_with('`') || code.ends_with('`') {
+        " "
+    } else {
+        ""
+    };
+    format!("{}{}{}{}{}", fence, pad, code, pad, fence)
+}
+
+/// The length of the longest run of `c` in `text`.
+fn longest_run(text: &str, c: char) -> usize {
+    text.split(|other| other != c)
+        .map(|run| run.chars().count())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Backslash-escapes the characters that would turn prose into Markdown
+/// syntax: emphasis, headings, links, code and block quotes.
+fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '`' | '*' | '_' | '#' | '[' | ']' | '<' | '>') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// `href` as a link destination: spaces and the characters that would end
+/// it early are percent-encoded.
+fn link_destination(href: &str) -> String {
+    href.trim()
+        .replace(' ', "%20")
+        .replace('(', "%28")
+        .replace(')', "%29")
+        .replace('<', "%3C")
+        .replace('>', "%3E")
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut in_space = false;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !in_space {
+                collapsed.push(' ');
+            }
+            in_space = true;
+        } else {
+            collapsed.push(c);
+            in_space = false;
+        }
+    }
+    collapsed
+}
+
+fn collapse_blank_lines(text: &str) -> String {
+    let mut collapsed = text.to_string();
+    while collapsed.contains("\n\n\n") {
+        collapsed = collapsed.replace("\n\n\n", "\n\n");
+    }
+    collapsed
+}
+
+/// Every text node under `node`, as written.
+fn text_content(node: &Handle) -> String {
+    let mut text = String::new();
+    if let NodeData::Text { contents } = &node.data {
+        text.push_str(&contents.borrow());
+    }
+    for child in node.children.borrow().iter() {
+        text.push_str(&text_content(child));
+    }
+    text
+}
+
+fn element_name(node: &Handle) -> Option<String> {
+    match &node.data {
+        NodeData::Element { name, .. } => Some(name.local.to_ascii_lowercase()),
+        _ => None,
+    }
+}
+
+fn element_children(node: &Handle) -> impl Iterator<Item = Handle> {
+    let children: Vec<Handle> = node
+        .children
+        .borrow()
+        .iter()
+        .filter(|child| element_name(child).is_some())
+        .cloned()
+        .collect();
+    children.into_iter()
+}
+
+fn attribute(node: &Handle, attribute: &str) -> Option<String> {
+    match &node.data {
+        NodeData::Element { attrs, .. } => attrs
+            .borrow()
+            .iter()
+            .find(|attr| attr.name.local.eq_ignore_ascii_case(attribute))
+            .map(|attr| attr.value.to_string()),
+        _ => None,
+    }
+}
+
+/// The first element named `tag` under `node`, in document order.
+fn find_element(node: &Handle, tag: &str) -> Option<Handle> {
+    node.children.borrow().iter().find_map(|child| {
+        if element_name(child).as_deref() == Some(tag) {
+            Some(child.clone())
+        } else {
+            find_element(child, tag)
+        }
+    })
+}
+
+/// Every `<tr>` under `node`, in document order.
+fn collect_rows(node: &Handle, rows: &mut Vec<Handle>) {
+    for child in node.children.borrow().iter() {
+        if element_name(child).as_deref() == Some("tr") {
+            rows.push(child.clone());
+        }
+        collect_rows(child, rows);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headings_and_paragraphs_are_separated_by_blank_lines() {
+        assert_eq!(
+            html_to_markdown("<h1>Title</h1><p>Some <em>text</em>.</p><h3>Sub</h3>"),
+            "# Title\n\nSome *text*.\n\n### Sub"
+        );
+    }
+
+    #[test]
+    fn nested_lists_are_indented_under_their_item() {
+        assert_eq!(
+            html_to_markdown("<ul><li>One</li><li>Two<ul><li>Inner</li></ul></li></ul>"),
+            "- One\n- Two\n  - Inner"
+        );
+    }
+
+    #[test]
+    fn numbered_lists_count_from_their_start() {
+        assert_eq!(
+            html_to_markdown("<ol><li>A</li><li>B</li></ol>"),
+            "1. A\n2. B"
+        );
+        assert_eq!(
+            html_to_markdown("<ol start=\"3\"><li>C</li><li>D</li></ol>"),
+            "3. C\n4. D"
+        );
+        assert_eq!(
+            html_to_markdown("<ol start=\"0\"><li>Zero</li><li>One</li></ol>"),
+            "0. Zero\n1. One"
+        );
+    }
+
+    #[test]
+    fn code_blocks_keep_their_language_and_outgrow_backtick_runs() {
+        assert_eq!(
+            html_to_markdown("<pre><code class=\"language-rust\">let a = `b`;\n</code></pre>"),
+            "```rust\nlet a = `b`;\n```"
+        );
+        assert_eq!(
+            html_to_markdown("<pre>a\n```\nb</pre>"),
+            "````\na\n```\nb\n````"
+        );
+    }
+
+    #[test]
+    fn inline_code_outgrows_backtick_runs() {
+        assert_eq!(
+            html_to_markdown("<p>Use <code>a`b</code> or <code>`x</code></p>"),
+            "Use ``a`b`` or `` `x ``"
+        );
+    }
+
+    #[test]
+    fn links_and_images_survive_brackets_parens_and_spaces() {
+        assert_eq!(
+            html_to_markdown(
+                "<p><a href=\"https://e.com/a (b)\">the [docs]</a> and \
+                 <a href=\"javascript:alert(1)\">click</a> \
+                 <img src=\"a b.png\" alt=\"x]y\"></p>"
+            ),
+            r"[the \[docs\]](https://e.com/a%20%28b%29) and click ![x\]y](a%20b.png)"
+        );
+    }
+
+    #[test]
+    fn table_cells_escape_pipes() {
+        assert_eq!(
+            html_to_markdown(
+                "<table><tr><th>a|b</th><th>c</th></tr><tr><td>1</td><td>2</td></tr></table>"
+            ),
+            "| a\\|b | c |\n| --- | --- |\n| 1 | 2 |"
+        );
+    }
+
+    #[test]
+    fn blockquotes_quote_every_line() {
+        assert_eq!(
+            html_to_markdown("<blockquote><p>One</p><p>Two</p></blockquote>"),
+            "> One\n>\n> Two"
+        );
+    }
+
+    #[test]
+    fn prose_that_looks_like_markdown_is_escaped() {
+        assert_eq!(
+            html_to_markdown("<p>*not* _emph_ #tag [x] 2 &lt; 3</p>"),
+            r"\*not\* \_emph\_ \#tag \[x\] 2 \< 3"
+        );
+    }
+}
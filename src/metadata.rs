@@ -0,0 +1,93 @@
+use headless_chrome::browser::Tab;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Reads the metadata from the rendered document as a JSON string. Author
+/// and publish date come from the first JSON-LD article that has them,
+/// falling back to the plain meta tags.
+const READ_METADATA: &str = r#"
+    (() => {
+        const meta = (selector) => {
+            const el = document.querySelector(selector);
+            return el ? el.getAttribute("content") : null;
+        };
+        const prefixed = (prefix) => {
+            const tags = {};
+            const selector = `meta[property^="${prefix}:"], meta[name^="${prefix}:"]`;
+            for (const el of document.querySelectorAll(selector)) {
+                const key = (el.getAttribute("property") || el.getAttribute("name")).slice(prefix.length + 1);
+                if (key && !(key in tags)) {
+                    tags[key] = el.getAttribute("content") || "";
+                }
+            }
+            return tags;
+        };
+
+        let author = null;
+        let published = null;
+        const visit = (node) => {
+            if (!node || typeof node !== "object") {
+                return;
+            }
+            if (Array.isArray(node)) {
+                node.forEach(visit);
+                return;
+            }
+            visit(node["@graph"]);
+            const types = [].concat(node["@type"] || []);
+            if (!types.some((type) => /Article|BlogPosting|Report/.test(type))) {
+                return;
+            }
+            if (!author && node.author) {
+                const names = [].concat(node.author)
+                    .map((a) => (typeof a === "string" ? a : a && a.name))
+                    .filter(Boolean);
+                author = names.length ? names.join(", ") : null;
+            }
+            published = published || node.datePublished || null;
+        };
+        for (const script of document.querySelectorAll('script[type="application/ld+json"]')) {
+            try {
+                visit(JSON.parse(script.textContent));
+            } catch (e) {}
+        }
+
+        const canonical = document.querySelector('link[rel="canonical"]');
+        return JSON.stringify({
+            title: document.title || null,
+            description: meta('meta[name="description"]'),
+            canonical_url: canonical ? canonical.href : null,
+            author: author || meta('meta[name="author"]'),
+            published_at: published || meta('meta[property="article:published_time"]'),
+            open_graph: prefixed("og"),
+            twitter: prefixed("twitter"),
+        });
+    })()
+"#;
+
+/// What the page says about itself in its `<head>`, for consumers that
+/// index or cite the article.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PageMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// The `rel="canonical"` link, resolved against the page.
+    pub canonical_url: Option<String>,
+    pub author: Option<String>,
+    /// As the page gives it, usually ISO 8601.
+    pub published_at: Option<String>,
+    /// `og:*` tags without the prefix, e.g. `title` or `image`.
+    #[serde(default)]
+    pub open_graph: BTreeMap<String, String>,
+    /// `twitter:*` card tags without the prefix.
+    #[serde(default)]
+    pub twitter: BTreeMap<String, String>,
+}
+
+/// The metadata of the page the tab shows, or `None` when it can't be read.
+pub fn page_metadata(tab: &Tab) -> Option<PageMetadata> {
+    tab.evaluate(READ_METADATA, false)
+        .ok()
+        .and_then(|r| r.value)
+        .and_then(|v| v.as_str().and_then(|s| serde_json::from_str(s).ok()))
+}
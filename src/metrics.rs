@@ -0,0 +1,112 @@
+use axum::{extract::MatchedPath, http::Request, middleware::Next, response::Response};
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, Encoder, HistogramVec,
+    IntCounter, IntCounterVec, TextEncoder,
+};
+use std::sync::OnceLock;
+
+/// Upper bounds of the phase and scrape duration buckets, in seconds.
+const PHASE_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// The service's Prometheus metrics, registered in the default registry the
+/// first time they're used.
+pub struct Metrics {
+    /// By route and response status.
+    pub requests: IntCounterVec,
+    /// By pipeline phase (`navigate`, `print_to_pdf`, `pdf_parse`,
+    /// `readability`, ...), as added to `Timings`.
+    pub phase_seconds: HistogramVec,
+    /// Scrape cache lookups by `hit` / `miss`; the hit ratio is
+    /// `rate(..{result="hit"}) / rate(..)`.
+    pub cache_lookups: IntCounterVec,
+    /// Browsers dropped from the pool because they crashed or stopped
+    /// answering, to be replaced by a fresh launch.
+    pub browser_restarts: IntCounter,
+    /// Extraction paths that won in `auto` mode, by `extractor` and by
+    /// `source`: `compared` when the heuristic picked it among all paths,
+    /// `remembered` when the domain's last winner was good enough again.
+    pub extractor_wins: IntCounterVec,
+    /// End-to-end time of a scrape request, cache hits included, by
+    /// `outcome` (`ok` or the error code) and output `format`.
+    pub scrape_seconds: HistogramVec,
+    /// Canary extractor runs by `extractor` and `outcome`: `agree`,
+    /// `disagree` with the served text, or `failed`.
+    pub canary_runs: IntCounterVec,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(|| Metrics {
+        requests: register_int_counter_vec!(
+            "scraper_requests_total",
+            "HTTP requests by route and status",
+            &["route", "status"]
+        )
+        .unwrap(),
+        phase_seconds: register_histogram_vec!(
+            "scraper_phase_duration_seconds",
+            "Time spent in each scrape phase",
+            &["phase"],
+            PHASE_BUCKETS.to_vec()
+        )
+        .unwrap(),
+        cache_lookups: register_int_counter_vec!(
+            "scraper_cache_lookups_total",
+            "Scrape cache lookups by result",
+            &["result"]
+        )
+        .unwrap(),
+        browser_restarts: register_int_counter!(
+            "scraper_browser_restarts_total",
+            "Pooled browsers replaced after crashing or failing a health check"
+        )
+        .unwrap(),
+        extractor_wins: register_int_counter_vec!(
+            "scraper_extractor_wins_total",
+            "Extraction paths chosen in auto mode",
+            &["extractor", "source"]
+        )
+        .unwrap(),
+        scrape_seconds: register_histogram_vec!(
+            "scraper_scrape_duration_seconds",
+            "Time from accepting a scrape request to its result",
+            &["outcome", "format"],
+            PHASE_BUCKETS.to_vec()
+        )
+        .unwrap(),
+        canary_runs: register_int_counter_vec!(
+            "scraper_canary_runs_total",
+            "Canary extractor runs compared with the served text",
+            &["extractor", "outcome"]
+        )
+        .unwrap(),
+    })
+}
+
+/// Everything in the default registry, in the Prometheus text format.
+pub fn render() -> String {
+    // registered on first use; make them show up before any request does
+    metrics();
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+/// Middleware counting responses by route and status.
+pub async fn track_requests<B>(req: Request<B>, next: Next<B>) -> Response {
+    // the route pattern, not the path, so unknown paths don't each get a
+    // series
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched".to_string(), |path| path.as_str().to_string());
+    let response = next.run(req).await;
+    metrics()
+        .requests
+        .with_label_values(&[&route, response.status().as_str()])
+        .inc();
+    response
+}
@@ -0,0 +1,241 @@
+use crate::browser::print_page;
+use crate::config::Config;
+use crate::pipeline::{run_blocking_phase, ScrapeOptions, Timings};
+use headless_chrome::{browser::Tab, types::PrintToPdfOptions};
+use pdfium_render::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Paper size a page is printed on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaperPreset {
+    /// 11x17 inches; tall pages mean fewer page breaks cutting through text.
+    #[default]
+    LongForm,
+    A4,
+    Letter,
+    Legal,
+}
+
+impl PaperPreset {
+    /// Width and height in inches, as `PrintToPdfOptions` expects.
+    pub fn size_inches(self) -> (f64, f64) {
+        match self {
+            PaperPreset::LongForm => (11.0, 17.0),
+            PaperPreset::A4 => (8.27, 11.69),
+            PaperPreset::Letter => (8.5, 11.0),
+            PaperPreset::Legal => (8.5, 14.0),
+        }
+    }
+}
+
+/// The text of one printed page, numbered from 1 in the printed PDF (which
+/// only holds `page_ranges`, when given).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageText {
+    pub page: usize,
+    pub text: String,
+}
+
+/// The pages' text as one string, the way the PDF path returns it.
+pub fn join_pages(pages: &[PageText]) -> String {
+    pages
+        .iter()
+        .map(|page| page.text.as_str())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Prints the page to PDF and extracts the text of each printed page.
+pub async fn get_webpage_text_headless(
+    url: &str,
+    tab: &Arc<Tab>,
+    options: &ScrapeOptions,
+    config: &Config,
+    timings: &mut Timings,
+) -> anyhow::Result<Vec<PageText>> {
+    let timeouts = &config.timeouts;
+
+    let (paper_width, paper_height) = options.paper.size_inches();
+    let pdf_options = PrintToPdfOptions {
+        landscape: Some(false),
+        display_header_footer: Some(false),
+        print_background: Some(false),
+        paper_width: Some(paper_width),
+        paper_height: Some(paper_height),
+        margin_top: Some(0.1),
+        margin_bottom: Some(0.1),
+        margin_left: Some(0.1),
+        margin_right: Some(0.1),
+        page_ranges: options.print_page_ranges(),
+        ignore_invalid_page_ranges: Some(true),
+        prefer_css_page_size: Some(options.prefer_css_page_size),
+        transfer_mode: None,
+        ..Default::default()
+    };
+    let pdf_as_vec = print_page(url, tab, &options.wait, pdf_options, timeouts, timings).await?;
+
+    let pdfium_path = config.pdfium_path.clone();
+    let options = options.clone();
+    run_blocking_phase("pdf_parse", timeouts.pdf_parse(), timings, move || {
+        bind_pdfium(pdfium_path.as_deref())?
+            .load_pdf_from_byte_vec(pdf_as_vec, Some(""))?
+            .pages()
+            .iter()
+            .take(options.max_pdf_pages.unwrap_or(usize::MAX))
+            .enumerate()
+            .map(|(i, page)| -> anyhow::Result<PageText> {
+                Ok(PageText {
+                    page: i + 1,
+                    text: pdf_page_text(&page, &options)?,
+                })
+            })
+            .collect()
+    })
+    .await
+}
+
+/// Loads pdfium from `path`, falling back to the system library.
+pub fn bind_pdfium(path: Option<&Path>) -> Result<Pdfium, PdfiumError> {
+    let bindings = match path {
+        Some(path) => Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path(path))
+            .or_else(|_| Pdfium::bind_to_system_library())?,
+        None => Pdfium::bind_to_system_library()?,
+    };
+    Ok(Pdfium::new(bindings))
+}
+
+/// Text of one printed page. With `min_font_size` set, characters rendered
+/// smaller than that (fine print, text-based tracking pixels) are dropped;
+/// with `mark_headings`, lines set noticeably larger than the page's body
+/// text are prefixed with Markdown heading markers.
+fn pdf_page_text(page: &PdfPage, options: &ScrapeOptions) -> anyhow::Result<String> {
+    let text = page.text()?;
+    if options.min_font_size.is_none() && !options.mark_headings {
+        return Ok(text.all());
+    }
+
+    let chars: Vec<(char, f32)> = text
+        .chars()
+        .iter()
+        .filter_map(|c| c.unicode_char().map(|ch| (ch, c.scaled_font_size().value)))
+        .filter(|(ch, size)| {
+            ch.is_whitespace() || options.min_font_size.map_or(true, |min| *size >= min)
+        })
+        .collect();
+    Ok(sized_text(&chars, options.mark_headings))
+}
+
+/// Joins characters and their font sizes into trimmed, non-empty lines,
+/// marking headings when `mark_headings` is set.
+fn sized_text(chars: &[(char, f32)], mark_headings: bool) -> String {
+    let mut sizes: Vec<f32> = chars
+        .iter()
+        .filter(|(ch, _)| !ch.is_whitespace())
+        .map(|(_, size)| *size)
+        .collect();
+    sizes.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let body_size = sizes.get(sizes.len() / 2).copied().unwrap_or(0.0);
+
+    let mut lines = Vec::new();
+    for line in chars.split(|(ch, _)| *ch == '\n') {
+        let line_text: String = line
+            .iter()
+            .map(|(ch, _)| *ch)
+            .filter(|ch| *ch != '\r')
+            .collect();
+        if line_text.trim().is_empty() {
+            continue;
+        }
+
+        let glyph_sizes: Vec<f32> = line
+            .iter()
+            .filter(|(ch, _)| !ch.is_whitespace())
+            .map(|(_, size)| *size)
+            .collect();
+        let line_size = glyph_sizes.iter().sum::<f32>() / glyph_sizes.len() as f32;
+
+        let marker = if mark_headings {
+            heading_marker(line_size, body_size)
+        } else {
+            ""
+        };
+        lines.push(format!("{}{}", marker, line_text.trim()));
+    }
+
+    lines.join("\n")
+}
+
+/// The Markdown heading marker for a line set at `line_size` on a page whose
+/// median glyph is `body_size`: `# ` from 1.6 times the body size, `## `
+/// from 1.25 times, nothing below that or without a body size.
+fn heading_marker(line_size: f32, body_size: f32) -> &'static str {
+    if body_size <= 0.0 {
+        ""
+    } else if line_size >= body_size * 1.6 {
+        "# "
+    } else if line_size >= body_size * 1.25 {
+        "## "
+    } else {
+        ""
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lines of text, each set at one font size.
+    fn chars(lines: &[(&str, f32)]) -> Vec<(char, f32)> {
+        let mut chars = Vec::new();
+        for (i, (line, size)) in lines.iter().enumerate() {
+            if i > 0 {
+                chars.push(('\n', *size));
+            }
+            chars.extend(line.chars().map(|ch| (ch, *size)));
+        }
+        chars
+    }
+
+    #[test]
+    fn headings_are_classified_by_size_relative_to_the_body() {
+        assert_eq!(heading_marker(16.5, 10.0), "# ");
+        assert_eq!(heading_marker(15.9, 10.0), "## ");
+        assert_eq!(heading_marker(12.5, 10.0), "## ");
+        assert_eq!(heading_marker(12.4, 10.0), "");
+        assert_eq!(heading_marker(8.0, 10.0), "");
+        assert_eq!(heading_marker(24.0, 0.0), "");
+    }
+
+    #[test]
+    fn larger_lines_are_marked_as_headings() {
+        let page = chars(&[
+            ("Title", 24.0),
+            ("The body of the page runs on.", 10.0),
+            ("Section", 14.0),
+            ("  More body text, trimmed.  ", 10.0),
+        ]);
+        assert_eq!(
+            sized_text(&page, true),
+            "# Title\nThe body of the page runs on.\n## Section\nMore body text, trimmed."
+        );
+        assert_eq!(
+            sized_text(&page, false),
+            "Title\nThe body of the page runs on.\nSection\nMore body text, trimmed."
+        );
+    }
+
+    #[test]
+    fn blank_lines_and_carriage_returns_are_dropped() {
+        let page = chars(&[
+            ("first\r", 10.0),
+            ("   ", 10.0),
+            ("", 10.0),
+            ("second", 10.0),
+        ]);
+        assert_eq!(sized_text(&page, true), "first\nsecond");
+        assert_eq!(sized_text(&[], true), "");
+    }
+}
@@ -0,0 +1,1299 @@
+use crate::browser::{
+    get_html_headless, get_inner_text_headless, handle_popups, has_credentials, navigate, on_tab,
+    open_tab, page_links, Rendering, WaitStrategy,
+};
+use crate::browser_pool::{BrowserPool, TabLease};
+use crate::config::Config;
+use crate::diagnostics::{DiagnosticsStore, TabRecorder};
+use crate::error::{PhaseTimeout, ScrapeError};
+use crate::extractor_cache::{Extractor, ExtractorCache};
+use crate::images::ArticleImage;
+use crate::markdown;
+use crate::metadata::{self, PageMetadata};
+use crate::metrics::metrics;
+use crate::pdf_extract::{get_webpage_text_headless, join_pages, PageText, PaperPreset};
+use crate::proxy::Proxy;
+use crate::readability_extract::{
+    extract_article_html, extract_article_text_from_html, page_images,
+};
+use crate::resource_usage::{ResourceUsage, UsageSampler};
+use crate::stable_hash::{stable_hash, StableHasher};
+use crate::tables::{self, Table};
+use anyhow::anyhow;
+use headless_chrome::protocol::cdp::Network;
+use headless_chrome::{browser::Tab, Browser};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use url::Url;
+use whatlang::Lang;
+
+/// Identifies the extraction logic that produced a result. Bump it whenever
+/// a change to the pipeline (paths, selection heuristic, clean-up passes)
+/// can change the text returned for the same page.
+pub const EXTRACTOR_VERSION: &str = "5";
+
+/// How long a scrape that ran out of time waits for its diagnostics bundle
+/// before answering; the bundle is still stored when it takes longer.
+const TIMEOUT_BUNDLE_WAIT: Duration = Duration::from_secs(2);
+
+/// The recorder of a scrape's tab and the browser it's in, once there is a
+/// tab.
+type Recording = Mutex<Option<(TabRecorder, Browser)>>;
+
+/// Runs the scrape under the request's overall deadline. When it expires
+/// the scrape is dropped mid-flight, which closes its tab(s) and returns the
+/// browser slot, so a page that never finishes loading can't pin it. When
+/// extraction fails or the deadline passes, what the tab recorded goes into
+/// `diagnostics`.
+pub async fn scrape(
+    url: String,
+    scrape_options: ScrapeOptions,
+    config: Arc<Config>,
+    pool: Arc<BrowserPool>,
+    extractors: Arc<ExtractorCache>,
+    diagnostics: Arc<DiagnosticsStore>,
+) -> Result<Scraped, ScrapeError> {
+    let deadline = config.deadline(scrape_options.timeout_ms);
+
+    let recording = Recording::default();
+    let scraping = run_scrape(
+        &url,
+        &scrape_options,
+        &config,
+        &pool,
+        &extractors,
+        &diagnostics,
+        &recording,
+    );
+    tokio::pin!(scraping);
+    match tokio::time::timeout(deadline, &mut scraping).await {
+        Ok(res) => {
+            if let Ok(scraped) = &res {
+                spawn_canary(&url, &scrape_options, &config, &pool, &scraped.text);
+            }
+            res
+        }
+        Err(_) => {
+            warn!("scrape of {} gave up after {:?}", url, deadline);
+            // `scraping` still holds the tab open while the bundle is made
+            let recorded = recording.lock().unwrap().take();
+            if let Some((recorder, browser)) = recorded {
+                let err = anyhow!("the scrape gave up after {:?}", deadline);
+                let (url, diagnostics) = (url.clone(), diagnostics.clone());
+                let bundling = tokio::task::spawn_blocking(move || {
+                    diagnostics.insert(recorder.bundle(&url, &err, &browser))
+                });
+                let _ = tokio::time::timeout(TIMEOUT_BUNDLE_WAIT, bundling).await;
+            }
+            Err(ScrapeError::Timeout { phase: "request" })
+        }
+    }
+}
+
+/// Runs `config.canary.extractor` in a tab of its own next to an `auto`
+/// text scrape of one of the canary's URLs, and logs both texts when they
+/// disagree. The result is served without waiting for it; the canary tab
+/// does count against the pool.
+fn spawn_canary(
+    url: &str,
+    options: &ScrapeOptions,
+    config: &Arc<Config>,
+    pool: &Arc<BrowserPool>,
+    served: &str,
+) {
+    if options.mode != ExtractionMode::Auto
+        || options.format != OutputFormat::Text
+        || options.pages
+        || !in_canary(url, config.canary.percent)
+    {
+        return;
+    }
+
+    let (url, options, served) = (url.to_string(), options.clone(), served.to_string());
+    let (config, pool) = (config.clone(), pool.clone());
+    tokio::spawn(async move {
+        let extractor = config.canary.extractor;
+        let canary = async {
+            let lease = open_tab(
+                &pool,
+                &config,
+                &url,
+                &options.host_overrides,
+                options.proxy.as_ref(),
+                &options.cookies,
+                &options.headers,
+                options.rendering(),
+            )
+            .await
+            .map_err(|e| anyhow!("{}", e))?;
+            let mut timings = Timings::default();
+            extract_with(extractor, &url, &lease, &options, &config, &mut timings).await
+        };
+        let outcome = match tokio::time::timeout(config.deadline(options.timeout_ms), canary).await
+        {
+            Ok(Ok(text)) => {
+                let similarity = text_similarity(&served, &text);
+                if similarity >= config.canary.min_similarity {
+                    "agree"
+                } else {
+                    info!(
+                        "canary {} disagrees on {} (similarity {:.2})\n--- served ---\n{}\n--- canary ---\n{}",
+                        extractor.name(),
+                        url,
+                        similarity,
+                        served,
+                        text
+                    );
+                    "disagree"
+                }
+            }
+            Ok(Err(e)) => {
+                debug!("canary {} failed on {}: {}", extractor.name(), url, e);
+                "failed"
+            }
+            Err(_) => {
+                debug!("canary {} timed out on {}", extractor.name(), url);
+                "failed"
+            }
+        };
+        metrics()
+            .canary_runs
+            .with_label_values(&[extractor.name(), outcome])
+            .inc();
+    });
+}
+
+/// Whether `url` is among the `percent` of URLs the canary runs on. Picked
+/// by a stable hash of the URL rather than at random, so the same pages are
+/// compared across requests and restarts.
+fn in_canary(url: &str, percent: f64) -> bool {
+    ((stable_hash(url) % 10_000) as f64) < percent * 100.0
+}
+
+/// How much two texts agree: the Jaccard similarity of their word
+/// shingles. Texts too short to shingle only agree with each other.
+fn text_similarity(a: &str, b: &str) -> f64 {
+    let (a, b) = (shingle_hashes(a), shingle_hashes(b));
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    jaccard(&a, &b)
+}
+
+async fn run_scrape(
+    url: &str,
+    scrape_options: &ScrapeOptions,
+    config: &Config,
+    pool: &Arc<BrowserPool>,
+    extractors: &ExtractorCache,
+    diagnostics: &Arc<DiagnosticsStore>,
+    recording: &Recording,
+) -> Result<Scraped, ScrapeError> {
+    let lease = open_tab(
+        pool,
+        config,
+        url,
+        &scrape_options.host_overrides,
+        scrape_options.proxy.as_ref(),
+        &scrape_options.cookies,
+        &scrape_options.headers,
+        scrape_options.rendering(),
+    )
+    .await?;
+    // a logged-in page's HTML and console stay out of the bundles, the same
+    // way its result stays out of the cache
+    if !has_credentials(&scrape_options.cookies, &scrape_options.headers) {
+        let (store, tab) = (diagnostics.clone(), lease.tab.clone());
+        let recorder = tokio::task::spawn_blocking(move || store.record(&tab))
+            .await
+            .ok()
+            .flatten();
+        *recording.lock().unwrap() = recorder.map(|recorder| (recorder, lease.browser.clone()));
+    }
+
+    let sampler = match lease.browser.get_process_id() {
+        Some(pid) => UsageSampler::start(pid).await,
+        None => None,
+    };
+    let mut timings = Timings::default();
+    let mut pages = None;
+    let res = match (scrape_options.mode, scrape_options.format) {
+        (ExtractionMode::RawHtml, _) => {
+            raw_html(url, &lease, scrape_options, config, &mut timings).await
+        }
+        (_, OutputFormat::Html) => {
+            article_html_to_use(url, &lease, scrape_options, config, &mut timings).await
+        }
+        (_, OutputFormat::Markdown) => {
+            article_html_to_use(url, &lease, scrape_options, config, &mut timings)
+                .await
+                .map(|html| markdown::html_to_markdown(&html))
+        }
+        (_, OutputFormat::Text) if scrape_options.pages => {
+            pdf_pages(url, &lease, scrape_options, config, &mut timings)
+                .await
+                .map(|extracted| {
+                    let text = join_pages(&extracted);
+                    pages = Some(extracted);
+                    text
+                })
+        }
+        (ExtractionMode::Auto, OutputFormat::Text) if scrape_options.hedged => {
+            hedged_text(url, &lease, pool, scrape_options, config)
+                .await
+                .map(|(text, hedged_timings)| {
+                    timings = hedged_timings;
+                    text
+                })
+        }
+        (_, OutputFormat::Text) => {
+            text_to_use(
+                url,
+                &lease,
+                scrape_options,
+                config,
+                extractors,
+                &mut timings,
+            )
+            .await
+        }
+    };
+    let links = match &res {
+        Ok(_) if scrape_options.collect_links => page_links(&lease.tab).await,
+        _ => Vec::new(),
+    };
+    let metadata = match &res {
+        Ok(_) if scrape_options.metadata => {
+            on_tab(&lease.tab, |tab| Ok(metadata::page_metadata(tab)))
+                .await
+                .ok()
+                .flatten()
+        }
+        _ => None,
+    };
+    let images = match &res {
+        Ok(_) if scrape_options.images => page_images(url, &lease.tab).await,
+        _ => None,
+    };
+    let tables = match &res {
+        Ok(_) if scrape_options.tables => on_tab(&lease.tab, |tab| Ok(tables::page_tables(tab)))
+            .await
+            .ok()
+            .flatten(),
+        _ => None,
+    };
+    let usage = match sampler {
+        Some(sampler) => Some(sampler.finish(!lease.had_browser_alone()).await),
+        None => None,
+    };
+    match res {
+        Ok(text) if text.trim().is_empty() => Err(ScrapeError::ExtractionEmpty),
+        Ok(text) => Ok(Scraped {
+            text,
+            timings,
+            usage,
+            links,
+            metadata,
+            images,
+            tables,
+            pages,
+        }),
+        Err(e) => {
+            report_error(&e, "extraction", url);
+            let err = ScrapeError::from_pipeline(&e, ScrapeError::ExtractionFailed);
+            let recorded = recording.lock().unwrap().take();
+            if let Some((recorder, browser)) = recorded {
+                let (url, diagnostics) = (url.to_string(), diagnostics.clone());
+                let _ = tokio::task::spawn_blocking(move || {
+                    diagnostics.insert(recorder.bundle(&url, &e, &browser))
+                })
+                .await;
+            }
+            Err(err)
+        }
+    }
+}
+
+/// A finished scrape, shared between all requests waiting on it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scraped {
+    pub text: String,
+    pub timings: Timings,
+    /// Missing where Chrome's process can't be inspected.
+    pub usage: Option<ResourceUsage>,
+    /// Absolute URLs the rendered page links to, when `collect_links` asked
+    /// for them.
+    #[serde(default)]
+    pub links: Vec<String>,
+    /// Set when `metadata` asked for it and the page could be read.
+    #[serde(default)]
+    pub metadata: Option<PageMetadata>,
+    /// Set when `images` asked for them and Readability found an article.
+    #[serde(default)]
+    pub images: Option<Vec<ArticleImage>>,
+    /// Set when `tables` asked for them and the page could be read.
+    #[serde(default)]
+    pub tables: Option<Vec<Table>>,
+    /// Set when `pages` asked for the text page by page.
+    #[serde(default)]
+    pub pages: Option<Vec<PageText>>,
+}
+
+/// Wall-clock milliseconds spent in each phase of a request. Phases that
+/// run more than once (e.g. navigating again after following a popup) are
+/// summed; phases that didn't run stay at zero.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Timings {
+    pub navigate_ms: u64,
+    pub wait_ms: u64,
+    pub print_ms: u64,
+    pub pdf_parse_ms: u64,
+    pub readability_ms: u64,
+    pub post_process_ms: u64,
+}
+
+impl Timings {
+    pub fn add(&mut self, phase: &str, elapsed: Duration) {
+        metrics()
+            .phase_seconds
+            .with_label_values(&[phase])
+            .observe(elapsed.as_secs_f64());
+        let ms = elapsed.as_millis() as u64;
+        match phase {
+            "navigate" => self.navigate_ms += ms,
+            "wait" => self.wait_ms += ms,
+            "print_to_pdf" => self.print_ms += ms,
+            "pdf_parse" => self.pdf_parse_ms += ms,
+            "readability" => self.readability_ms += ms,
+            "post_process" => self.post_process_ms += ms,
+            _ => {}
+        }
+    }
+
+    /// The timings as a `Server-Timing` header value, which browser dev
+    /// tools and most HTTP clients can display.
+    pub fn server_timing(&self) -> String {
+        [
+            ("navigate", self.navigate_ms),
+            ("wait", self.wait_ms),
+            ("print", self.print_ms),
+            ("pdf_parse", self.pdf_parse_ms),
+            ("readability", self.readability_ms),
+            ("post_process", self.post_process_ms),
+        ]
+        .iter()
+        .map(|(name, ms)| format!("{};dur={}", name, ms))
+        .collect::<Vec<String>>()
+        .join(", ")
+    }
+}
+
+/// Sends `err` to the error reporting backend (a no-op unless `SENTRY_DSN`
+/// is set), tagged with the pipeline phase and target URL.
+pub fn report_error(err: &anyhow::Error, phase: &str, url: &str) {
+    sentry::with_scope(
+        |scope| {
+            scope.set_tag("phase", phase);
+            scope.set_tag("url", url);
+        },
+        || sentry::integrations::anyhow::capture_anyhow(err),
+    );
+}
+
+/// Request options that change what gets scraped, as opposed to how the
+/// result is post-processed. Requests only share a scrape when the ones in
+/// `output_hash` match.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScrapeOptions {
+    #[serde(default)]
+    pub format: OutputFormat,
+    /// Which extraction pipeline produces the result.
+    #[serde(default)]
+    pub mode: ExtractionMode,
+    /// Hostname to IP mappings applied inside Chrome, like `/etc/hosts`, so
+    /// pre-production deployments can be scraped under their real names.
+    #[serde(default)]
+    pub host_overrides: BTreeMap<String, IpAddr>,
+    /// Proxy URL (`http://`, `https://`, `socks4://` or `socks5://`, with
+    /// `user:pass@` for HTTP proxies) overriding the configured one, e.g.
+    /// to rotate through a proxy list.
+    #[serde(default)]
+    pub proxy: Option<Proxy>,
+    /// When the page opens popups or new tabs, scrape the newest one instead
+    /// of the (usually empty) opener page.
+    #[serde(default)]
+    pub follow_popups: bool,
+    /// Paper size the page is printed on before PDF text extraction.
+    #[serde(default)]
+    pub paper: PaperPreset,
+    /// Let the page's `@page { size }` rule override `paper`. Documentation
+    /// sites with print stylesheets often extract better this way.
+    #[serde(default)]
+    pub prefer_css_page_size: bool,
+    /// Drop PDF text rendered below this font size, in points.
+    #[serde(default)]
+    pub min_font_size: Option<f32>,
+    /// Mark lines printed larger than the body text as Markdown headings.
+    #[serde(default)]
+    pub mark_headings: bool,
+    /// Printed pages to extract text from, e.g. `1-5, 8`, so very long
+    /// pages don't make enormous PDFs.
+    #[serde(default)]
+    pub page_ranges: Option<String>,
+    /// Extract text from at most this many printed pages (of `page_ranges`,
+    /// when given).
+    #[serde(default)]
+    pub max_pdf_pages: Option<usize>,
+    /// What to wait for after navigating before extracting.
+    #[serde(default)]
+    pub wait: WaitStrategy,
+    /// Cookies set before navigating, in CDP's `Network.CookieParam` shape
+    /// (`name`, `value`, `domain`, `path`, `secure`, `httpOnly`, ...), for
+    /// pages behind a login.
+    #[serde(default)]
+    pub cookies: Vec<Network::CookieParam>,
+    /// Extra HTTP headers sent with every request the page makes, e.g.
+    /// `Authorization` or `User-Agent`.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Overall deadline for the scrape in milliseconds, including waiting
+    /// for a browser. Capped at the configured `request_timeout_ms`.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Set by `/api/crawl` to get the page's links along with its text.
+    #[serde(skip)]
+    pub collect_links: bool,
+    /// Also return the page's title, description, canonical URL, author,
+    /// publish date and Open Graph / Twitter card tags.
+    #[serde(default)]
+    pub metadata: bool,
+    /// Also return the images of the article, with absolute URLs.
+    #[serde(default)]
+    pub images: bool,
+    /// Also return the page's `<table>`s as rows of cell text, which the
+    /// text itself can't keep apart.
+    #[serde(default)]
+    pub tables: bool,
+    /// Also return the text of each printed page, numbered, as the PDF
+    /// path extracted it before post-processing. Takes the PDF path
+    /// whatever `mode` says; only used with `format: "text"`.
+    #[serde(default)]
+    pub pages: bool,
+    /// Render with a frozen clock, seeded `Math.random`, fixed viewport,
+    /// locale and timezone and no animations, so scraping unchanged content
+    /// again gives the same text.
+    #[serde(default)]
+    pub deterministic: bool,
+    /// `false` loads the page without running its scripts: much faster for
+    /// server-rendered sites, and client-side paywalls never run.
+    #[serde(default)]
+    pub javascript: Option<bool>,
+    /// In `auto` mode, run the PDF path and the DOM path side by side in two tabs
+    /// and answer with the first result that's good enough, instead of
+    /// running the paths one after another and comparing them.
+    #[serde(default)]
+    pub hedged: bool,
+}
+
+impl ScrapeOptions {
+    /// How the tab should render the page.
+    pub fn rendering(&self) -> Rendering {
+        Rendering {
+            deterministic: self.deterministic,
+            javascript: self.javascript != Some(false),
+        }
+    }
+
+    /// A hash of the options that change what the scrape returns, for
+    /// keying shared and cached scrapes. `wait`, `timeout_ms` and `hedged`
+    /// only change how the scrape gets there, so they're left out.
+    pub fn output_hash(&self) -> u64 {
+        let mut hasher = StableHasher::default();
+        self.format.hash(&mut hasher);
+        self.mode.hash(&mut hasher);
+        self.host_overrides.hash(&mut hasher);
+        self.proxy.hash(&mut hasher);
+        self.follow_popups.hash(&mut hasher);
+        self.paper.hash(&mut hasher);
+        self.prefer_css_page_size.hash(&mut hasher);
+        self.min_font_size.map(f32::to_bits).hash(&mut hasher);
+        self.mark_headings.hash(&mut hasher);
+        self.page_ranges.hash(&mut hasher);
+        self.max_pdf_pages.hash(&mut hasher);
+        // CookieParam has no Hash; its Debug form names every field
+        format!("{:?}", self.cookies).hash(&mut hasher);
+        self.headers.hash(&mut hasher);
+        self.collect_links.hash(&mut hasher);
+        self.metadata.hash(&mut hasher);
+        self.images.hash(&mut hasher);
+        self.tables.hash(&mut hasher);
+        self.pages.hash(&mut hasher);
+        self.deterministic.hash(&mut hasher);
+        self.javascript.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// The pages Chrome prints: `page_ranges`, or the first `max_pdf_pages`.
+    pub fn print_page_ranges(&self) -> Option<String> {
+        self.page_ranges
+            .clone()
+            .or_else(|| self.max_pdf_pages.map(|n| format!("1-{}", n.max(1))))
+    }
+}
+
+/// How `text_to_use` gets text out of the page. Callers that know which
+/// path works for their pages can skip the others.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExtractionMode {
+    /// Run the paths and pick the best result (or reuse the path that won
+    /// for the domain recently).
+    #[default]
+    Auto,
+    /// Only the printed-PDF text.
+    Pdf,
+    /// Only Readability over the rendered HTML.
+    Readability,
+    /// Only the rendered page's visible text, as selecting all of it would
+    /// copy it; for JS-heavy pages that lose content when printed.
+    InnerText,
+    /// The rendered DOM as HTML, untouched by extraction or clean-up.
+    RawHtml,
+}
+
+/// What a scrape returns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Plain text from the extraction path `mode` selects.
+    #[default]
+    Text,
+    /// The Readability article DOM, for consumers that render previews.
+    Html,
+    /// The Readability article as Markdown, keeping headings, lists, code
+    /// blocks and links.
+    Markdown,
+}
+
+impl OutputFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputFormat::Text => "text",
+            OutputFormat::Html => "html",
+            OutputFormat::Markdown => "markdown",
+        }
+    }
+}
+
+/// Runs a blocking CDP or pdfium call on the blocking thread pool and gives
+/// up after `limit`, recording how long it took. The call itself can't be
+/// interrupted; on timeout its result is discarded and the phase fails.
+pub async fn run_blocking_phase<T, F>(
+    phase: &'static str,
+    limit: Duration,
+    timings: &mut Timings,
+    f: F,
+) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+{
+    let started = Instant::now();
+    let res = match tokio::time::timeout(limit, tokio::task::spawn_blocking(f)).await {
+        Ok(joined) => joined?,
+        Err(_) => Err(PhaseTimeout { phase, limit }.into()),
+    };
+    timings.add(phase, started.elapsed());
+    res
+}
+
+/// Async counterpart of `run_blocking_phase`.
+pub async fn run_phase<T>(
+    phase: &'static str,
+    limit: Duration,
+    timings: &mut Timings,
+    fut: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    let started = Instant::now();
+    let res = tokio::time::timeout(limit, fut)
+        .await
+        .unwrap_or_else(|_| Err(PhaseTimeout { phase, limit }.into()));
+    timings.add(phase, started.elapsed());
+    res
+}
+
+/// The Readability article of the page as HTML, following a popup like
+/// `extract_with`.
+pub async fn article_html_to_use(
+    url: &str,
+    lease: &TabLease,
+    options: &ScrapeOptions,
+    config: &Config,
+    timings: &mut Timings,
+) -> anyhow::Result<String> {
+    let tab = &lease.tab;
+    let timeouts = &config.timeouts;
+
+    let mut url = url.to_string();
+    let mut html_str = get_html_headless(&url, tab, &options.wait, timeouts, timings).await?;
+    if let Some(popup_url) = handle_popups(&lease.browser, tab, &url, options.follow_popups).await {
+        url = popup_url;
+        html_str = get_html_headless(&url, tab, &options.wait, timeouts, timings).await?;
+    }
+
+    run_phase(
+        "readability",
+        timeouts.readability(),
+        timings,
+        extract_article_html(&url, html_str),
+    )
+    .await
+}
+
+/// Lines with at least this many words count as prose.
+const PROSE_LINE_WORDS: usize = 8;
+
+/// Share of words (0.0 to 1.0) that sit on prose-length lines. Navigation,
+/// link lists, cookie banners and other boilerplate are mostly short lines,
+/// so thin or junk-heavy extractions score low.
+pub fn text_quality_score(text: &str) -> f64 {
+    let mut words = 0;
+    let mut prose_words = 0;
+    for line in text.lines() {
+        let line_words = line.split_whitespace().count();
+        words += line_words;
+        if line_words >= PROSE_LINE_WORDS {
+            prose_words += line_words;
+        }
+    }
+
+    if words == 0 {
+        return 0.0;
+    }
+    prose_words as f64 / words as f64
+}
+
+/// Words per shingle when comparing paragraphs.
+const SHINGLE_SIZE: usize = 4;
+/// Jaccard similarity of shingle sets above which a paragraph is dropped.
+const DUPLICATE_THRESHOLD: f64 = 0.8;
+
+/// Removes paragraphs whose shingled content nearly matches a paragraph seen
+/// earlier in the text. Paragraphs are blank-line separated blocks, or lines
+/// when the text has no blank lines (as with PDF output). Paragraphs too
+/// short to shingle are always kept.
+pub fn suppress_duplicate_paragraphs(text: &str) -> String {
+    let separator = if text.contains("\n\n") { "\n\n" } else { "\n" };
+
+    let mut seen: Vec<HashSet<u64>> = Vec::new();
+    let mut kept: Vec<&str> = Vec::new();
+
+    for paragraph in text.split(separator) {
+        let shingles = shingle_hashes(paragraph);
+        if !shingles.is_empty() {
+            let is_duplicate = seen
+                .iter()
+                .any(|other| jaccard(&shingles, other) >= DUPLICATE_THRESHOLD);
+            if is_duplicate {
+                continue;
+            }
+            seen.push(shingles);
+        }
+        kept.push(paragraph);
+    }
+
+    kept.join(separator)
+}
+
+fn shingle_hashes(paragraph: &str) -> HashSet<u64> {
+    let words: Vec<String> = paragraph
+        .split_whitespace()
+        .map(|w| {
+            w.chars()
+                .filter(|c| c.is_alphanumeric())
+                .collect::<String>()
+                .to_lowercase()
+        })
+        .filter(|w| !w.is_empty())
+        .collect();
+
+    words
+        .windows(SHINGLE_SIZE)
+        .map(|shingle| {
+            let mut hasher = DefaultHasher::new();
+            shingle.hash(&mut hasher);
+            hasher.finish()
+        })
+        .collect()
+}
+
+fn jaccard(a: &HashSet<u64>, b: &HashSet<u64>) -> f64 {
+    let intersection = a.intersection(b).count();
+    let union = a.len() + b.len() - intersection;
+    intersection as f64 / union as f64
+}
+
+/// A run of consecutive sentences detected as the same language. `lang` is
+/// `None` when no sentence in the run could be detected reliably.
+#[derive(Debug)]
+pub struct LanguageSegment {
+    pub lang: Option<Lang>,
+    pub text: String,
+}
+
+/// Splits `text` into sentences, detects each sentence's language and merges
+/// neighbours of the same language into segments. Sentences too short to
+/// detect reliably stay with the segment they appear in.
+pub fn segment_by_language(text: &str) -> Vec<LanguageSegment> {
+    let mut segments: Vec<LanguageSegment> = Vec::new();
+
+    for sentence in split_sentences(text) {
+        let lang = whatlang::detect(sentence)
+            .filter(|info| info.is_reliable())
+            .map(|info| info.lang());
+
+        match (segments.last_mut(), lang) {
+            (Some(last), None) => last.text.push_str(sentence),
+            (Some(last), Some(lang)) if last.lang.is_none() || last.lang == Some(lang) => {
+                last.lang = Some(lang);
+                last.text.push_str(sentence);
+            }
+            _ => segments.push(LanguageSegment {
+                lang,
+                text: sentence.to_string(),
+            }),
+        }
+    }
+
+    segments
+}
+
+/// Keeps only the segments in the language that covers most of the text.
+pub fn dominant_language_text(text: &str) -> String {
+    let segments = segment_by_language(text);
+
+    let mut chars_per_lang: HashMap<Lang, usize> = HashMap::new();
+    for segment in &segments {
+        if let Some(lang) = segment.lang {
+            *chars_per_lang.entry(lang).or_default() += segment.text.chars().count();
+        }
+    }
+
+    let dominant = match chars_per_lang.into_iter().max_by_key(|(_, count)| *count) {
+        Some((lang, _)) => lang,
+        None => return text.to_string(),
+    };
+
+    segments
+        .into_iter()
+        .filter(|segment| segment.lang == Some(dominant))
+        .map(|segment| segment.text)
+        .collect::<Vec<String>>()
+        .concat()
+}
+
+/// Splits after sentence-ending punctuation (Latin, Arabic and CJK) and line
+/// breaks, keeping the terminators and whitespace so the pieces concatenate
+/// back to the original text.
+fn split_sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut at_boundary = false;
+
+    for (i, c) in text.char_indices() {
+        let terminator = matches!(c, '.' | '!' | '?' | '\n' | '؟' | '。' | '！' | '？');
+        // runs of terminators ("...", "?!") end one sentence together
+        if at_boundary && !c.is_whitespace() && !terminator {
+            sentences.push(&text[start..i]);
+            start = i;
+            at_boundary = false;
+        }
+        if terminator {
+            at_boundary = true;
+        }
+    }
+    if start < text.len() {
+        sentences.push(&text[start..]);
+    }
+
+    sentences
+}
+
+/// A single path's result is only trusted without comparing it to the
+/// other paths when it has at least this many words...
+const GOOD_ENOUGH_MIN_WORDS: usize = 150;
+/// ...and at least this `text_quality_score`.
+const GOOD_ENOUGH_MIN_QUALITY: f64 = 0.5;
+
+fn good_enough(text: &str) -> bool {
+    text.split_whitespace().count() >= GOOD_ENOUGH_MIN_WORDS
+        && text_quality_score(text) >= GOOD_ENOUGH_MIN_QUALITY
+}
+
+/// Gets the page's text along the path `options.mode` selects, loading
+/// `url` in the leased tab. In `auto` mode the path that won for the domain
+/// recently is tried first and recorded in `extractors`. Time spent in each
+/// phase is added to `timings`.
+pub async fn text_to_use(
+    url: &str,
+    lease: &TabLease,
+    options: &ScrapeOptions,
+    config: &Config,
+    extractors: &ExtractorCache,
+    timings: &mut Timings,
+) -> anyhow::Result<String> {
+    match options.mode {
+        ExtractionMode::Auto => auto_text(url, lease, options, config, extractors, timings).await,
+        ExtractionMode::Pdf => {
+            extract_with(Extractor::Pdf, url, lease, options, config, timings).await
+        }
+        ExtractionMode::Readability => {
+            extract_with(Extractor::Readability, url, lease, options, config, timings).await
+        }
+        ExtractionMode::InnerText => {
+            extract_with(Extractor::InnerText, url, lease, options, config, timings).await
+        }
+        ExtractionMode::RawHtml => raw_html(url, lease, options, config, timings).await,
+    }
+}
+
+async fn auto_text(
+    url: &str,
+    lease: &TabLease,
+    options: &ScrapeOptions,
+    config: &Config,
+    extractors: &ExtractorCache,
+    timings: &mut Timings,
+) -> anyhow::Result<String> {
+    let domain = Url::from_str(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_lowercase()))
+        .unwrap_or_default();
+
+    // try the path that won for this domain recently on its own, and only
+    // fall back to running (and comparing) all of them when it falls short
+    if let Some(extractor) = extractors.get(&domain) {
+        match extract_with(extractor, url, lease, options, config, timings).await {
+            Ok(text) if good_enough(&text) => {
+                metrics()
+                    .extractor_wins
+                    .with_label_values(&[extractor.name(), "remembered"])
+                    .inc();
+                return Ok(text);
+            }
+            _ => info!(
+                "remembered extractor {:?} fell short for {}, trying all",
+                extractor, domain
+            ),
+        }
+    }
+
+    let (extractor, text) = compare_extractors(url, lease, options, config, timings).await?;
+    extractors.record(&domain, extractor);
+    metrics()
+        .extractor_wins
+        .with_label_values(&[extractor.name(), "compared"])
+        .inc();
+    Ok(text)
+}
+
+/// Runs the PDF path in `lease` and the DOM path in a second tab at the
+/// same time. The first result that's `good_enough` wins and the other path
+/// is cancelled (its tab is closed when its lease drops); when neither is,
+/// the one with more words is used. Returns the winner's timings.
+async fn hedged_text(
+    url: &str,
+    lease: &TabLease,
+    pool: &Arc<BrowserPool>,
+    options: &ScrapeOptions,
+    config: &Config,
+) -> anyhow::Result<(String, Timings)> {
+    let pdf = async {
+        let mut timings = Timings::default();
+        let text = extract_with(Extractor::Pdf, url, lease, options, config, &mut timings).await?;
+        Ok::<_, anyhow::Error>((text, timings))
+    };
+    let dom = async {
+        let mut timings = Timings::default();
+        let dom_lease = open_tab(
+            pool,
+            config,
+            url,
+            &options.host_overrides,
+            options.proxy.as_ref(),
+            &options.cookies,
+            &options.headers,
+            options.rendering(),
+        )
+        .await
+        .map_err(|e| anyhow!("{}", e))?;
+        let text = dom_text(url, &dom_lease, options, config, &mut timings).await?;
+        Ok::<_, anyhow::Error>((text, timings))
+    };
+    tokio::pin!(pdf, dom);
+
+    let mut pdf_res = None;
+    let mut dom_res = None;
+    while pdf_res.is_none() || dom_res.is_none() {
+        let finished = tokio::select! {
+            res = &mut pdf, if pdf_res.is_none() => pdf_res.insert(res),
+            res = &mut dom, if dom_res.is_none() => dom_res.insert(res),
+        };
+        if let Ok(result) = finished {
+            if good_enough(&result.0) {
+                return Ok(result.clone());
+            }
+        }
+    }
+
+    match (pdf_res.unwrap(), dom_res.unwrap()) {
+        (Ok(pdf), Ok(dom)) => {
+            if dom.0.split_whitespace().count() > pdf.0.split_whitespace().count() {
+                Ok(dom)
+            } else {
+                Ok(pdf)
+            }
+        }
+        (Ok(only), Err(_)) | (Err(_), Ok(only)) => Ok(only),
+        (Err(e), Err(_)) => Err(e),
+    }
+}
+
+/// The DOM half of `hedged_text`: Readability when it finds a good article,
+/// otherwise the page's visible text.
+async fn dom_text(
+    url: &str,
+    lease: &TabLease,
+    options: &ScrapeOptions,
+    config: &Config,
+    timings: &mut Timings,
+) -> anyhow::Result<String> {
+    let tab = &lease.tab;
+
+    let mut url = url.to_string();
+    let mut html_str =
+        get_html_headless(&url, tab, &options.wait, &config.timeouts, timings).await?;
+    if let Some(popup_url) = handle_popups(&lease.browser, tab, &url, options.follow_popups).await {
+        url = popup_url;
+        html_str = get_html_headless(&url, tab, &options.wait, &config.timeouts, timings).await?;
+    }
+
+    let inner_text = get_inner_text_headless(tab).await?;
+    let readah_text = run_phase(
+        "readability",
+        config.timeouts.readability(),
+        timings,
+        extract_article_text_from_html(&url, html_str),
+    )
+    .await?;
+
+    if good_enough(&readah_text) {
+        Ok(readah_text)
+    } else {
+        Ok(inner_text)
+    }
+}
+
+/// The page's rendered DOM serialized as HTML, with nothing pruned.
+async fn raw_html(
+    url: &str,
+    lease: &TabLease,
+    options: &ScrapeOptions,
+    config: &Config,
+    timings: &mut Timings,
+) -> anyhow::Result<String> {
+    let tab = &lease.tab;
+
+    navigate(url, tab, &options.wait, &config.timeouts, timings).await?;
+    if let Some(popup_url) = handle_popups(&lease.browser, tab, url, options.follow_popups).await {
+        navigate(&popup_url, tab, &options.wait, &config.timeouts, timings).await?;
+    }
+
+    on_tab(tab, |tab| tab.get_content()).await
+}
+
+/// Runs a single extraction path, following a popup the page opened when
+/// the request asks for it.
+async fn extract_with(
+    extractor: Extractor,
+    url: &str,
+    lease: &TabLease,
+    options: &ScrapeOptions,
+    config: &Config,
+    timings: &mut Timings,
+) -> anyhow::Result<String> {
+    let tab = &lease.tab;
+
+    let mut url = url.to_string();
+    let mut text = extract_once(extractor, &url, tab, options, config, timings).await?;
+    if let Some(popup_url) = handle_popups(&lease.browser, tab, &url, options.follow_popups).await {
+        url = popup_url;
+        text = extract_once(extractor, &url, tab, options, config, timings).await?;
+    }
+
+    Ok(text)
+}
+
+async fn extract_once(
+    extractor: Extractor,
+    url: &str,
+    tab: &Arc<Tab>,
+    options: &ScrapeOptions,
+    config: &Config,
+    timings: &mut Timings,
+) -> anyhow::Result<String> {
+    match extractor {
+        Extractor::Pdf => get_webpage_text_headless(url, tab, options, config, timings)
+            .await
+            .map(|pages| join_pages(&pages)),
+        Extractor::InnerText => {
+            navigate(url, tab, &options.wait, &config.timeouts, timings).await?;
+            get_inner_text_headless(tab).await
+        }
+        Extractor::Readability => {
+            let html_str =
+                get_html_headless(url, tab, &options.wait, &config.timeouts, timings).await?;
+            run_phase(
+                "readability",
+                config.timeouts.readability(),
+                timings,
+                extract_article_text_from_html(url, html_str),
+            )
+            .await
+        }
+    }
+}
+
+/// The PDF path's text page by page, following a popup like `extract_with`.
+async fn pdf_pages(
+    url: &str,
+    lease: &TabLease,
+    options: &ScrapeOptions,
+    config: &Config,
+    timings: &mut Timings,
+) -> anyhow::Result<Vec<PageText>> {
+    let tab = &lease.tab;
+
+    let mut pages = get_webpage_text_headless(url, tab, options, config, timings).await?;
+    if let Some(popup_url) = handle_popups(&lease.browser, tab, url, options.follow_popups).await {
+        pages = get_webpage_text_headless(&popup_url, tab, options, config, timings).await?;
+    }
+
+    Ok(pages)
+}
+
+/// Runs the PDF and Readability paths and picks the best result, returning
+/// which path it came from. The innerText path is only run when asked for,
+/// since a raw word count can't tell its boilerplate from an article.
+async fn compare_extractors(
+    url: &str,
+    lease: &TabLease,
+    options: &ScrapeOptions,
+    config: &Config,
+    timings: &mut Timings,
+) -> anyhow::Result<(Extractor, String)> {
+    let tab = &lease.tab;
+
+    let mut url = url.to_string();
+    let mut pdf_text =
+        join_pages(&get_webpage_text_headless(&url, tab, options, config, timings).await?);
+    if let Some(popup_url) = handle_popups(&lease.browser, tab, &url, options.follow_popups).await {
+        url = popup_url;
+        pdf_text =
+            join_pages(&get_webpage_text_headless(&url, tab, options, config, timings).await?);
+    }
+    let url = url.as_str();
+
+    let html_str = get_html_headless(url, tab, &options.wait, &config.timeouts, timings).await?;
+    let readah_text = run_phase(
+        "readability",
+        config.timeouts.readability(),
+        timings,
+        extract_article_text_from_html(url, html_str),
+    )
+    .await?;
+
+    let readah_text_len = readah_text.split_whitespace().count();
+    let pdf_text_len = pdf_text.split_whitespace().count();
+
+    let lots_of_text_on_page = pdf_text_len > 999;
+    let readah_sees_lots_of_texts = readah_text_len > 500;
+
+    if lots_of_text_on_page && readah_sees_lots_of_texts {
+        return Ok((Extractor::Readability, readah_text.to_string()));
+    }
+
+    Ok((Extractor::Pdf, pdf_text.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROSE: &str = "This line has more than enough words to count as prose.";
+
+    #[test]
+    fn quality_of_empty_text_is_zero() {
+        assert_eq!(text_quality_score(""), 0.0);
+        assert_eq!(text_quality_score(" \n\n \t"), 0.0);
+    }
+
+    #[test]
+    fn quality_is_the_share_of_words_on_prose_lines() {
+        assert_eq!(text_quality_score(PROSE), 1.0);
+        assert_eq!(text_quality_score("Home\nAbout\nContact us"), 0.0);
+
+        let mixed = format!("{}\nHome about contact", PROSE);
+        let prose_words = PROSE.split_whitespace().count() as f64;
+        assert_eq!(
+            text_quality_score(&mixed),
+            prose_words / (prose_words + 3.0)
+        );
+    }
+
+    #[test]
+    fn prose_lines_start_at_the_word_threshold() {
+        let short = "word ".repeat(PROSE_LINE_WORDS - 1);
+        let long = "word ".repeat(PROSE_LINE_WORDS);
+        assert_eq!(text_quality_score(&short), 0.0);
+        assert_eq!(text_quality_score(&long), 1.0);
+    }
+
+    #[test]
+    fn duplicate_paragraphs_are_dropped_after_the_first() {
+        let text = format!(
+            "{}\n\nSomething else entirely said here today.\n\n{}",
+            PROSE, PROSE
+        );
+        assert_eq!(
+            suppress_duplicate_paragraphs(&text),
+            format!("{}\n\nSomething else entirely said here today.", PROSE)
+        );
+    }
+
+    #[test]
+    fn near_duplicates_ignore_case_and_punctuation() {
+        let text = format!("{}\n{}", PROSE, PROSE.to_uppercase().replace('.', "!"));
+        assert_eq!(suppress_duplicate_paragraphs(&text), PROSE);
+    }
+
+    #[test]
+    fn paragraphs_too_short_to_shingle_are_kept() {
+        let text = "Read more\n\nRead more\n\nRead more";
+        assert_eq!(suppress_duplicate_paragraphs(text), text);
+        assert_eq!(suppress_duplicate_paragraphs(""), "");
+    }
+
+    const ENGLISH: &str = "The quick brown fox jumps over the lazy dog while the farmer \
+        watches quietly from the old wooden porch of his house. ";
+    const GERMAN: &str = "Der schnelle braune Fuchs springt über den faulen Hund, während \
+        der Bauer von der alten Holzveranda seines Hauses ruhig zuschaut. ";
+
+    #[test]
+    fn sentences_split_after_terminators_and_keep_them() {
+        assert_eq!(
+            split_sentences("Hi. There!\nNext？End"),
+            vec!["Hi. ", "There!\n", "Next？", "End"]
+        );
+        assert_eq!(
+            split_sentences("Wait... what?! No."),
+            vec!["Wait... ", "what?! ", "No."]
+        );
+        assert_eq!(split_sentences("..."), vec!["..."]);
+        assert!(split_sentences("").is_empty());
+    }
+
+    #[test]
+    fn empty_text_has_no_segments() {
+        assert!(segment_by_language("").is_empty());
+        assert_eq!(dominant_language_text(""), "");
+    }
+
+    #[test]
+    fn segments_follow_language_changes() {
+        let text = format!("{}{}{}", ENGLISH, ENGLISH, GERMAN);
+        let segments = segment_by_language(&text);
+        let langs: Vec<Option<Lang>> = segments.iter().map(|segment| segment.lang).collect();
+        assert_eq!(langs, vec![Some(Lang::Eng), Some(Lang::Deu)]);
+        assert_eq!(segments[0].text, format!("{}{}", ENGLISH, ENGLISH));
+    }
+
+    #[test]
+    fn short_sentences_stay_with_their_segment() {
+        let text = format!("OK. {}Yes. {}", ENGLISH, GERMAN);
+        let segments = segment_by_language(&text);
+        assert_eq!(segments[0].lang, Some(Lang::Eng));
+        assert_eq!(segments[0].text, format!("OK. {}Yes. ", ENGLISH));
+        let joined: String = segments
+            .iter()
+            .map(|segment| segment.text.as_str())
+            .collect();
+        assert_eq!(joined, text);
+    }
+
+    #[test]
+    fn dominant_language_keeps_the_larger_share() {
+        let text = format!("{}{}{}", ENGLISH, GERMAN, ENGLISH);
+        assert_eq!(
+            dominant_language_text(&text),
+            format!("{}{}", ENGLISH, ENGLISH)
+        );
+        // nothing detectable: the text is kept as it is
+        assert_eq!(dominant_language_text("OK. Yes."), "OK. Yes.");
+    }
+
+    #[test]
+    fn output_hash_ignores_how_the_scrape_gets_there() {
+        let options = ScrapeOptions::default();
+        let patient = ScrapeOptions {
+            timeout_ms: Some(60_000),
+            wait: WaitStrategy::Delay { ms: 500 },
+            hedged: true,
+            ..Default::default()
+        };
+        assert_eq!(options.output_hash(), patient.output_hash());
+
+        let markdown = ScrapeOptions {
+            format: OutputFormat::Markdown,
+            ..Default::default()
+        };
+        assert_ne!(options.output_hash(), markdown.output_hash());
+        let headed = ScrapeOptions {
+            headers: BTreeMap::from([("Authorization".to_string(), "token".to_string())]),
+            ..Default::default()
+        };
+        assert_ne!(options.output_hash(), headed.output_hash());
+    }
+
+    #[test]
+    fn text_similarity_compares_word_shingles() {
+        let article = "The quick brown fox jumps over the lazy dog near the river bank.";
+        assert_eq!(text_similarity(article, article), 1.0);
+        assert_eq!(text_similarity(article, &article.to_uppercase()), 1.0);
+        assert_eq!(text_similarity(article, "Home About Contact"), 0.0);
+        assert_eq!(text_similarity("Home", "About"), 1.0);
+        let longer = format!("{} Then it rested in the shade for a while.", article);
+        let similarity = text_similarity(article, &longer);
+        assert!(similarity > 0.0 && similarity < 1.0, "{}", similarity);
+    }
+
+    #[test]
+    fn canary_percent_picks_a_stable_share_of_urls() {
+        let urls: Vec<String> = (0..1000)
+            .map(|i| format!("https://example.com/{}", i))
+            .collect();
+        let picked = |percent| urls.iter().filter(|url| in_canary(url, percent)).count();
+        assert_eq!(picked(0.0), 0);
+        assert_eq!(picked(100.0), 1000);
+        let some = picked(10.0);
+        assert!((50..150).contains(&some), "{}", some);
+        assert_eq!(picked(10.0), some);
+    }
+}
@@ -0,0 +1,322 @@
+use crate::config::PolitenessSettings;
+use http_req::{request::Request, uri::Uri};
+use log::warn;
+use regex::Regex;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// Hosts and domains remembered at once; beyond this a map is cleared.
+const MAX_HOSTS: usize = 10_000;
+
+/// Fetching one robots.txt, which is plain HTTP without a browser.
+const ROBOTS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Opt-in courtesy towards scraped sites: URLs their robots.txt disallows
+/// are refused, and scrapes of one domain start at least `min_delay_ms`
+/// apart however many requests are after it at once.
+pub struct Politeness {
+    settings: PolitenessSettings,
+    /// Parsed robots.txt and when it was fetched, by origin.
+    robots: Mutex<HashMap<String, (Arc<Robots>, Instant)>>,
+    /// When the next scrape of each domain may start.
+    next_start: Mutex<HashMap<String, Instant>>,
+}
+
+impl Politeness {
+    pub fn new(settings: PolitenessSettings) -> Self {
+        Politeness {
+            settings,
+            robots: Mutex::new(HashMap::new()),
+            next_start: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether robots.txt lets us scrape `url`; always true unless
+    /// `respect_robots_txt` is on, and for URLs that aren't http(s), which
+    /// have no robots.txt.
+    pub async fn allowed(&self, url: &Url) -> bool {
+        if !self.settings.respect_robots_txt || !matches!(url.scheme(), "http" | "https") {
+            return true;
+        }
+
+        let origin = url.origin().ascii_serialization();
+        let cached = self
+            .robots
+            .lock()
+            .unwrap()
+            .get(&origin)
+            .filter(|(_, fetched)| fetched.elapsed() < self.settings.robots_ttl())
+            .map(|(robots, _)| robots.clone());
+        let robots = match cached {
+            Some(robots) => robots,
+            None => {
+                let robots_url = match url.join("/robots.txt") {
+                    Ok(robots_url) => robots_url,
+                    Err(_) => return true,
+                };
+                let user_agent = self.settings.user_agent.clone();
+                let robots = tokio::task::spawn_blocking(move || {
+                    Robots::parse(&fetch_robots(&robots_url), &user_agent)
+                })
+                .await
+                .map(Arc::new)
+                .unwrap_or_default();
+
+                let mut cache = self.robots.lock().unwrap();
+                if cache.len() >= MAX_HOSTS {
+                    cache.clear();
+                }
+                cache.insert(origin, (robots.clone(), Instant::now()));
+                robots
+            }
+        };
+
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        robots.allows(&path)
+    }
+
+    /// Waits until a scrape of `domain` may start, and books that start so
+    /// the next caller waits `min_delay_ms` longer.
+    pub async fn wait_turn(&self, domain: &str) {
+        let delay = self.settings.min_delay();
+        if delay.is_zero() {
+            return;
+        }
+
+        let start = {
+            let mut next_start = self.next_start.lock().unwrap();
+            let now = Instant::now();
+            if next_start.len() >= MAX_HOSTS {
+                next_start.retain(|_, at| *at > now);
+            }
+            let start = next_start.get(domain).map_or(now, |at| (*at).max(now));
+            next_start.insert(domain.to_string(), start + delay);
+            start
+        };
+        tokio::time::sleep_until(start.into()).await;
+    }
+}
+
+/// The body of a robots.txt, or an empty one (allowing everything) when the
+/// site has none or it can't be fetched.
+fn fetch_robots(url: &Url) -> String {
+    let fetched = Uri::try_from(url.as_str())
+        .map_err(anyhow::Error::from)
+        .and_then(|uri| {
+            let mut writer = Vec::new();
+            let res = Request::new(&uri)
+                .timeout(Some(ROBOTS_TIMEOUT))
+                .send(&mut writer)?;
+            Ok((res.status_code(), writer))
+        });
+    match fetched {
+        Ok((status, body)) if status.is_success() => String::from_utf8_lossy(&body).into_owned(),
+        Ok(_) => String::new(),
+        Err(e) => {
+            warn!("couldn't fetch {}, treating it as empty: {}", url, e);
+            String::new()
+        }
+    }
+}
+
+/// The rules of one robots.txt that apply to our user agent.
+#[derive(Default)]
+struct Robots {
+    /// `(allow, pattern length, pattern)`
+    rules: Vec<(bool, usize, Regex)>,
+}
+
+impl Robots {
+    /// Reads the groups for `user_agent` (matched case-insensitively on
+    /// its product token), or the `*` groups when none name it.
+    fn parse(txt: &str, user_agent: &str) -> Self {
+        let user_agent = product_token(user_agent);
+        let mut groups: Vec<(Vec<String>, Vec<(bool, String)>)> = Vec::new();
+        let mut in_rules = false;
+
+        for line in txt.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim().to_ascii_lowercase(), value.trim()),
+                None => continue,
+            };
+            match key.as_str() {
+                "user-agent" => {
+                    if in_rules || groups.is_empty() {
+                        groups.push((Vec::new(), Vec::new()));
+                        in_rules = false;
+                    }
+                    groups
+                        .last_mut()
+                        .unwrap()
+                        .0
+                        .push(value.to_ascii_lowercase());
+                }
+                "allow" | "disallow" if !groups.is_empty() => {
+                    in_rules = true;
+                    // an empty disallow allows everything
+                    if !value.is_empty() {
+                        groups
+                            .last_mut()
+                            .unwrap()
+                            .1
+                            .push((key == "allow", value.to_string()));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let named = |agent: &String| {
+            let agent = product_token(agent);
+            !agent.is_empty() && agent != "*" && agent == user_agent
+        };
+        let matching: Vec<&(Vec<String>, Vec<(bool, String)>)> =
+            if groups.iter().any(|(agents, _)| agents.iter().any(named)) {
+                groups
+                    .iter()
+                    .filter(|(agents, _)| agents.iter().any(named))
+                    .collect()
+            } else {
+                groups
+                    .iter()
+                    .filter(|(agents, _)| agents.iter().any(|agent| agent == "*"))
+                    .collect()
+            };
+
+        Robots {
+            rules: matching
+                .into_iter()
+                .flat_map(|(_, rules)| rules)
+                .filter_map(|(allow, pattern)| {
+                    Some((*allow, pattern.len(), pattern_regex(pattern)?))
+                })
+                .collect(),
+        }
+    }
+
+    /// The longest matching rule decides; on a tie, allowing wins.
+    fn allows(&self, path: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|(_, _, pattern)| pattern.is_match(path))
+            .max_by_key(|(allow, len, _)| (*len, *allow))
+            .map_or(true, |(allow, _, _)| *allow)
+    }
+}
+
+/// The product token of a user agent (`MyBot` in `MyBot/2.1 (+https://..)`),
+/// lowercased, which is what robots.txt groups name.
+fn product_token(user_agent: &str) -> String {
+    user_agent
+        .split(|c: char| c == '/' || c.is_whitespace())
+        .find(|token| !token.is_empty())
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+/// A robots.txt path pattern as a regex: `*` matches anything and a
+/// trailing `$` anchors the end.
+fn pattern_regex(pattern: &str) -> Option<Regex> {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let body = pattern
+        .split('*')
+        .map(regex::escape)
+        .collect::<Vec<String>>()
+        .join(".*");
+    Regex::new(&format!("^{}{}", body, if anchored { "$" } else { "" })).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AGENT: &str = "scrape-web-by-virtual-printing";
+
+    #[test]
+    fn longest_match_wins_and_allow_wins_ties() {
+        let robots = Robots::parse(
+            "User-agent: *\nDisallow: /private\nAllow: /private/open\nAllow: /same\nDisallow: /same\n",
+            AGENT,
+        );
+        assert!(!robots.allows("/private/secret"));
+        assert!(robots.allows("/private/open/page"));
+        assert!(robots.allows("/same"));
+        assert!(robots.allows("/public"));
+    }
+
+    #[test]
+    fn empty_disallow_allows_everything() {
+        let robots = Robots::parse("User-agent: *\nDisallow:\n", AGENT);
+        assert!(robots.allows("/anything"));
+    }
+
+    #[test]
+    fn empty_robots_allows_everything() {
+        assert!(Robots::parse("", AGENT).allows("/"));
+    }
+
+    #[test]
+    fn wildcards_and_end_anchors() {
+        let robots = Robots::parse(
+            "User-agent: *\nDisallow: /*.pdf$\nDisallow: /search*q=\n",
+            AGENT,
+        );
+        assert!(!robots.allows("/files/report.pdf"));
+        assert!(robots.allows("/files/report.pdf?download=1"));
+        assert!(!robots.allows("/search?q=rust"));
+        assert!(robots.allows("/search"));
+    }
+
+    #[test]
+    fn pattern_regex_escapes_everything_but_wildcards() {
+        let regex = pattern_regex("/a.b*c$").unwrap();
+        assert!(regex.is_match("/a.bxyzc"));
+        assert!(!regex.is_match("/axbc"));
+        assert!(!regex.is_match("/a.bc/d"));
+        assert!(pattern_regex("/plain").unwrap().is_match("/plain/and/more"));
+    }
+
+    #[test]
+    fn named_group_replaces_the_star_group() {
+        let txt = "User-agent: *\nDisallow: /\n\nUser-agent: Scrape-Web-By-Virtual-Printing\nDisallow: /admin\n";
+        let robots = Robots::parse(
+            txt,
+            "Scrape-Web-By-Virtual-Printing/1.0 (+https://example.com)",
+        );
+        assert!(robots.allows("/articles"));
+        assert!(!robots.allows("/admin"));
+    }
+
+    #[test]
+    fn agents_match_on_the_whole_product_token() {
+        let txt = "User-agent: scrape\nDisallow: /\n";
+        assert!(Robots::parse(txt, AGENT).allows("/page"));
+        assert!(!Robots::parse(txt, "scrape/2.0").allows("/page"));
+    }
+
+    #[test]
+    fn empty_agent_names_nobody() {
+        let txt = "User-agent:\nDisallow: /\n\nUser-agent: *\nDisallow: /private\n";
+        let robots = Robots::parse(txt, AGENT);
+        assert!(robots.allows("/page"));
+        assert!(!robots.allows("/private"));
+    }
+
+    #[test]
+    fn agents_listed_together_share_rules() {
+        let txt =
+            "User-agent: otherbot\nUser-agent: scrape-web-by-virtual-printing\nDisallow: /shared\n";
+        let robots = Robots::parse(txt, AGENT);
+        assert!(!robots.allows("/shared"));
+    }
+}
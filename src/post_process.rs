@@ -0,0 +1,236 @@
+use anyhow::Context;
+use regex::Regex;
+use serde::Deserialize;
+use std::{env, fs};
+
+/// Text clean-up rules applied to the final extracted text, for recurring
+/// junk strings that no extraction heuristic catches. Loaded from the JSON
+/// file named by `SCRAPER_POST_PROCESS_RULES`, e.g.
+///
+/// ```json
+/// [
+///   { "action": "drop_lines", "pattern": "^Advertisement$" },
+///   { "domain": "example.com", "action": "replace", "pattern": "\\s*Read more »", "replacement": "" },
+///   { "domain": "example.com", "action": "trim_prefix", "prefix": "Skip to content" }
+/// ]
+/// ```
+///
+/// Rules without a `domain` apply everywhere; a `domain` also matches its
+/// subdomains. Rules run in file order.
+#[derive(Default)]
+pub struct PostProcessRules {
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    domain: Option<String>,
+    action: Action,
+}
+
+enum Action {
+    Replace { pattern: Regex, replacement: String },
+    DropLines { pattern: Regex },
+    KeepLines { pattern: Regex },
+    TrimPrefix { prefix: String },
+    TrimSuffix { suffix: String },
+}
+
+#[derive(Deserialize)]
+struct RuleSpec {
+    #[serde(default)]
+    domain: Option<String>,
+    #[serde(flatten)]
+    action: ActionSpec,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum ActionSpec {
+    Replace { pattern: String, replacement: String },
+    DropLines { pattern: String },
+    KeepLines { pattern: String },
+    TrimPrefix { prefix: String },
+    TrimSuffix { suffix: String },
+}
+
+impl PostProcessRules {
+    /// Loads the rules file if `SCRAPER_POST_PROCESS_RULES` is set, otherwise
+    /// returns an empty rule set.
+    pub fn from_env() -> anyhow::Result<Self> {
+        match env::var("SCRAPER_POST_PROCESS_RULES") {
+            Ok(path) => {
+                let json = fs::read_to_string(&path)
+                    .with_context(|| format!("reading post-process rules from {}", path))?;
+                Self::from_json(&json)
+            }
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        let specs: Vec<RuleSpec> = serde_json::from_str(json)?;
+        let rules = specs
+            .into_iter()
+            .map(|spec| {
+                let action = match spec.action {
+                    ActionSpec::Replace {
+                        pattern,
+                        replacement,
+                    } => Action::Replace {
+                        pattern: Regex::new(&pattern)?,
+                        replacement,
+                    },
+                    ActionSpec::DropLines { pattern } => Action::DropLines {
+                        pattern: Regex::new(&pattern)?,
+                    },
+                    ActionSpec::KeepLines { pattern } => Action::KeepLines {
+                        pattern: Regex::new(&pattern)?,
+                    },
+                    ActionSpec::TrimPrefix { prefix } => Action::TrimPrefix { prefix },
+                    ActionSpec::TrimSuffix { suffix } => Action::TrimSuffix { suffix },
+                };
+                Ok(Rule {
+                    domain: spec.domain.map(|d| d.to_lowercase()),
+                    action,
+                })
+            })
+            .collect::<anyhow::Result<Vec<Rule>>>()?;
+
+        Ok(PostProcessRules { rules })
+    }
+
+    /// Applies every rule matching `host` to `text`, in order.
+    pub fn apply(&self, host: &str, text: &str) -> String {
+        let host = host.to_lowercase();
+        let mut text = text.to_string();
+
+        for rule in self.rules.iter().filter(|rule| rule.matches(&host)) {
+            text = match &rule.action {
+                Action::Replace {
+                    pattern,
+                    replacement,
+                } => pattern.replace_all(&text, replacement.as_str()).into_owned(),
+                Action::DropLines { pattern } => text
+                    .lines()
+                    .filter(|line| !pattern.is_match(line))
+                    .collect::<Vec<&str>>()
+                    .join("\n"),
+                Action::KeepLines { pattern } => text
+                    .lines()
+                    .filter(|line| pattern.is_match(line))
+                    .collect::<Vec<&str>>()
+                    .join("\n"),
+                Action::TrimPrefix { prefix } => text
+                    .trim_start()
+                    .strip_prefix(prefix.as_str())
+                    .map(|rest| rest.to_string())
+                    .unwrap_or(text),
+                Action::TrimSuffix { suffix } => text
+                    .trim_end()
+                    .strip_suffix(suffix.as_str())
+                    .map(|rest| rest.to_string())
+                    .unwrap_or(text),
+            };
+        }
+
+        text
+    }
+}
+
+impl Rule {
+    fn matches(&self, host: &str) -> bool {
+        match &self.domain {
+            None => true,
+            Some(domain) => {
+                host == domain
+                    || host
+                        .strip_suffix(domain.as_str())
+                        .map_or(false, |sub| sub.ends_with('.'))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(json: &str) -> PostProcessRules {
+        PostProcessRules::from_json(json).unwrap()
+    }
+
+    #[test]
+    fn no_rules_leave_text_alone() {
+        let rules = PostProcessRules::default();
+        assert_eq!(rules.apply("example.com", "Some text\n"), "Some text\n");
+        assert_eq!(rules.apply("", ""), "");
+    }
+
+    #[test]
+    fn empty_text_stays_empty() {
+        let rules = rules(
+            r#"[
+                { "action": "drop_lines", "pattern": "^Ad$" },
+                { "action": "trim_prefix", "prefix": "Menu" }
+            ]"#,
+        );
+        assert_eq!(rules.apply("example.com", ""), "");
+    }
+
+    #[test]
+    fn domains_match_themselves_and_subdomains_only() {
+        let rules = rules(
+            r#"[{ "domain": "Example.com", "action": "replace", "pattern": "x", "replacement": "y" }]"#,
+        );
+        assert_eq!(rules.apply("example.com", "x"), "y");
+        assert_eq!(rules.apply("news.EXAMPLE.com", "x"), "y");
+        assert_eq!(rules.apply("badexample.com", "x"), "x");
+        assert_eq!(rules.apply("example.com.evil.net", "x"), "x");
+        assert_eq!(rules.apply("", "x"), "x");
+    }
+
+    #[test]
+    fn line_rules_drop_and_keep_lines() {
+        let drop = rules(r#"[{ "action": "drop_lines", "pattern": "^Advertisement$" }]"#);
+        assert_eq!(
+            drop.apply("example.com", "First\nAdvertisement\nSecond"),
+            "First\nSecond"
+        );
+        let keep = rules(r#"[{ "action": "keep_lines", "pattern": "\\S" }]"#);
+        assert_eq!(keep.apply("example.com", "First\n\n  \nSecond"), "First\nSecond");
+    }
+
+    #[test]
+    fn trims_only_matching_prefixes_and_suffixes() {
+        let rules = rules(
+            r#"[
+                { "action": "trim_prefix", "prefix": "Skip to content" },
+                { "action": "trim_suffix", "suffix": "Share this" }
+            ]"#,
+        );
+        assert_eq!(
+            rules.apply("example.com", "  Skip to content\nBody\nShare this\n"),
+            "\nBody\n"
+        );
+        assert_eq!(rules.apply("example.com", "Body"), "Body");
+    }
+
+    #[test]
+    fn rules_run_in_file_order() {
+        let rules = rules(
+            r#"[
+                { "action": "replace", "pattern": "a", "replacement": "b" },
+                { "action": "replace", "pattern": "b", "replacement": "c" }
+            ]"#,
+        );
+        assert_eq!(rules.apply("example.com", "ab"), "cc");
+    }
+
+    #[test]
+    fn invalid_rules_are_errors() {
+        assert!(PostProcessRules::from_json(r#"[{ "action": "drop_lines", "pattern": "(" }]"#).is_err());
+        assert!(PostProcessRules::from_json(r#"[{ "action": "explode" }]"#).is_err());
+        assert!(PostProcessRules::from_json("").is_err());
+        assert_eq!(PostProcessRules::from_json("[]").unwrap().rules.len(), 0);
+    }
+}
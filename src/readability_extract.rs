@@ -0,0 +1,62 @@
+use crate::browser::on_tab;
+use crate::images::{self, ArticleImage};
+use headless_chrome::browser::Tab;
+use readah::readability::Readability;
+use std::collections::HashSet;
+use std::sync::Arc;
+use url::Url;
+
+/// Runs Readability over `html_str` and returns the article as plain text.
+pub async fn extract_article_text_from_html(url: &str, html_str: String) -> anyhow::Result<String> {
+    let article_html = extract_article_html(url, html_str).await?;
+    let output = html2text::from_read(article_html.as_bytes(), 80);
+
+    Ok(output)
+}
+
+/// Runs Readability over `html_str` and returns the article DOM serialized
+/// as HTML. Readability drops scripts and styles and resolves links and
+/// image sources against the page's base URL.
+pub async fn extract_article_html(url: &str, html_str: String) -> anyhow::Result<String> {
+    let base_url = article_base_url(url)?;
+
+    let res = Readability::extract(&html_str, Some(base_url)).await?;
+
+    Ok(res.to_string())
+}
+
+/// The URL Readability resolves the article's links and image sources
+/// against: the scheme and host of the page.
+fn article_base_url(url: &str) -> anyhow::Result<Url> {
+    let parsed_url = Url::parse(url)?;
+    let scheme = parsed_url.scheme();
+    let host = parsed_url.host_str().unwrap_or("");
+    Ok(Url::parse(&format!("{}://{}", scheme, host))?)
+}
+
+/// The images of the article on the page the tab shows, or `None` when
+/// Readability finds no article.
+pub async fn page_images(url: &str, tab: &Arc<Tab>) -> Option<Vec<ArticleImage>> {
+    let html = on_tab(tab, |tab| tab.get_content()).await.ok()?;
+    let article = extract_article_html(url, html).await.ok()?;
+    let base_url = article_base_url(url).ok()?;
+    Some(images::article_images(&article, &base_url))
+}
+
+/// Cleans article HTML with ammonia so it can be embedded in other pages
+/// without XSS risk: scripts, event handlers and `javascript:` URLs are
+/// removed and links get `rel="noopener noreferrer"`. `allowed_tags`
+/// replaces the default tag allowlist when given.
+pub fn sanitize_html(html: &str, allowed_tags: Option<&[String]>) -> String {
+    let mut builder = ammonia::Builder::default();
+    if let Some(tags) = allowed_tags {
+        // ammonia refuses to allow tags whose content it always strips
+        builder.tags(
+            tags.iter()
+                .map(|t| t.as_str())
+                .filter(|t| !matches!(*t, "script" | "style"))
+                .collect::<HashSet<&str>>(),
+        );
+    }
+    builder.clean(html).to_string()
+}
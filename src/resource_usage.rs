@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often the browser's memory is sampled while a scrape runs.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Samples between rescans of `/proc` for the browser's processes, which
+/// cost more than reading the memory of the ones already known.
+const SAMPLES_PER_TREE_SCAN: u32 = 10;
+
+/// `/proc/<pid>/stat` CPU times are in clock ticks, which the kernel always
+/// reports to userspace at 100 Hz (`USER_HZ`).
+const MS_PER_TICK: u64 = 10;
+
+/// Chrome resources used while a scrape ran, read from `/proc` for the
+/// browser process and all of its children (renderers, GPU process, ...).
+/// Pooled browsers serve several requests at once, so this is what the
+/// whole browser used during the request, not just its tab.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    pub cpu_ms: u64,
+    pub peak_rss_bytes: u64,
+    /// Other tabs used the browser while the request ran, so the numbers
+    /// include their work too.
+    #[serde(default)]
+    pub shared: bool,
+}
+
+/// Samples a browser's process tree from `start` until `finish`.
+pub struct UsageSampler {
+    root: u32,
+    start_ticks: u64,
+    peak_rss: Arc<AtomicU64>,
+    done: Arc<AtomicBool>,
+}
+
+impl UsageSampler {
+    /// Starts sampling the process tree under `root` on a blocking thread.
+    /// Returns `None` where `/proc` isn't available.
+    pub async fn start(root: u32) -> Option<Self> {
+        let (pids, start_ticks, rss) = tokio::task::spawn_blocking(move || {
+            let pids = process_tree(root);
+            let start_ticks = cpu_ticks(&pids)?;
+            let rss = rss_bytes(&pids);
+            Some((pids, start_ticks, rss))
+        })
+        .await
+        .ok()??;
+        let peak_rss = Arc::new(AtomicU64::new(rss));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let sampler_peak = peak_rss.clone();
+        let sampler_done = done.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut pids = pids;
+            let mut samples = 0;
+            while !sampler_done.load(Ordering::Relaxed) {
+                std::thread::sleep(SAMPLE_INTERVAL);
+                samples += 1;
+                if samples % SAMPLES_PER_TREE_SCAN == 0 {
+                    pids = process_tree(root);
+                }
+                sampler_peak.fetch_max(rss_bytes(&pids), Ordering::Relaxed);
+            }
+        });
+
+        Some(UsageSampler {
+            root,
+            start_ticks,
+            peak_rss,
+            done,
+        })
+    }
+
+    /// Stops sampling. `shared` says whether other tabs used the browser
+    /// in the meantime.
+    pub async fn finish(self, shared: bool) -> ResourceUsage {
+        self.done.store(true, Ordering::Relaxed);
+
+        let root = self.root;
+        let (rss, end_ticks) = tokio::task::spawn_blocking(move || {
+            let pids = process_tree(root);
+            (rss_bytes(&pids), cpu_ticks(&pids))
+        })
+        .await
+        .unwrap_or((0, None));
+        self.peak_rss.fetch_max(rss, Ordering::Relaxed);
+        let end_ticks = end_ticks.unwrap_or(self.start_ticks);
+
+        ResourceUsage {
+            // children that exited during the scrape take their CPU time
+            // with them, so this can only undercount
+            cpu_ms: end_ticks.saturating_sub(self.start_ticks) * MS_PER_TICK,
+            peak_rss_bytes: self.peak_rss.load(Ordering::Relaxed),
+            shared,
+        }
+    }
+}
+
+impl Drop for UsageSampler {
+    /// Stops the sampling task when the scrape is abandoned before `finish`.
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Relaxed);
+    }
+}
+
+/// `root` and every process descending from it.
+fn process_tree(root: u32) -> Vec<u32> {
+    let parents: Vec<(u32, u32)> = fs::read_dir("/proc")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+        .filter_map(|pid| Some((pid, stat_fields(pid)?.get(1)?.parse().ok()?)))
+        .collect();
+
+    let mut tree = vec![root];
+    let mut i = 0;
+    while i < tree.len() {
+        let parent = tree[i];
+        tree.extend(
+            parents
+                .iter()
+                .filter(|(_, ppid)| *ppid == parent)
+                .map(|(pid, _)| *pid),
+        );
+        i += 1;
+    }
+    tree
+}
+
+/// Fields of `/proc/<pid>/stat` after the command name, so index 0 is the
+/// state, 1 the parent pid, 11 `utime` and 12 `stime`.
+fn stat_fields(pid: u32) -> Option<Vec<String>> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the command name is parenthesized and may itself contain spaces
+    let rest = &stat[stat.rfind(')')? + 1..];
+    Some(rest.split_whitespace().map(|f| f.to_string()).collect())
+}
+
+fn cpu_ticks(pids: &[u32]) -> Option<u64> {
+    let root = stat_fields(*pids.first()?)?;
+    let ticks = |fields: &[String]| -> u64 {
+        let field = |i: usize| fields.get(i).and_then(|f| f.parse::<u64>().ok());
+        field(11).unwrap_or(0) + field(12).unwrap_or(0)
+    };
+
+    Some(
+        ticks(&root)
+            + pids[1..]
+                .iter()
+                .filter_map(|pid| stat_fields(*pid))
+                .map(|fields| ticks(&fields))
+                .sum::<u64>(),
+    )
+}
+
+fn rss_bytes(pids: &[u32]) -> u64 {
+    pids.iter()
+        .filter_map(|pid| fs::read_to_string(format!("/proc/{}/status", pid)).ok())
+        .filter_map(|status| {
+            let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+            let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+            Some(kb * 1024)
+        })
+        .sum()
+}
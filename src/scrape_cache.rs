@@ -0,0 +1,257 @@
+use crate::config::CacheSettings;
+use crate::metrics::metrics;
+use crate::pipeline::Scraped;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// How often a changed cache is written to `persist_path`.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Finished scrapes by URL and scrape options, so asking for the same
+/// article again doesn't render it again. Entries expire after the TTL, and
+/// the least recently used one is dropped when the cache is full.
+pub struct ScrapeCache {
+    settings: CacheSettings,
+    state: Mutex<CacheState>,
+    /// Changed since it was last saved.
+    dirty: AtomicBool,
+}
+
+struct CacheState {
+    entries: HashMap<String, Entry>,
+    /// Bumped on every access; an entry's `last_used` is the value then.
+    clock: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    scraped: Scraped,
+    stored_at: SystemTime,
+    #[serde(skip)]
+    last_used: u64,
+}
+
+impl ScrapeCache {
+    /// An empty cache, or the one saved at `persist_path` if there is one.
+    pub fn load(settings: CacheSettings) -> Self {
+        let mut entries: HashMap<String, Entry> = settings
+            .persist_path
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|bytes| match serde_json::from_slice(&bytes) {
+                Ok(entries) => Some(entries),
+                Err(e) => {
+                    warn!("ignoring unreadable scrape cache: {}", e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        entries.retain(|_, entry| fresh(entry, settings.ttl()));
+        if !entries.is_empty() {
+            info!("loaded {} cached scrapes", entries.len());
+        }
+
+        ScrapeCache {
+            settings,
+            state: Mutex::new(CacheState { entries, clock: 0 }),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Scraped> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        let ttl = self.settings.ttl();
+        let scraped = match state.entries.get_mut(key) {
+            Some(entry) if fresh(entry, ttl) => {
+                entry.last_used = clock;
+                Some(entry.scraped.clone())
+            }
+            _ => None,
+        };
+        metrics()
+            .cache_lookups
+            .with_label_values(&[if scraped.is_some() { "hit" } else { "miss" }])
+            .inc();
+        scraped
+    }
+
+    pub fn insert(&self, key: String, scraped: Scraped) {
+        if self.settings.max_entries == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.entries.len() >= self.settings.max_entries && !state.entries.contains_key(&key) {
+            let ttl = self.settings.ttl();
+            state.entries.retain(|_, entry| fresh(entry, ttl));
+            if state.entries.len() >= self.settings.max_entries {
+                let least_recent = state
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| key.clone());
+                if let Some(least_recent) = least_recent {
+                    state.entries.remove(&least_recent);
+                }
+            }
+        }
+        state.clock += 1;
+        let entry = Entry {
+            scraped,
+            stored_at: SystemTime::now(),
+            last_used: state.clock,
+        };
+        state.entries.insert(key, entry);
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// Drops the entries whose key starts with `prefix`, or all of them
+    /// without one. Returns how many were dropped.
+    pub fn remove(&self, prefix: Option<&str>) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let before = state.entries.len();
+        match prefix {
+            Some(prefix) => state.entries.retain(|key, _| !key.starts_with(prefix)),
+            None => state.entries.clear(),
+        }
+        self.dirty.store(true, Ordering::SeqCst);
+        before - state.entries.len()
+    }
+
+    /// Saves the cache to `persist_path` every `PERSIST_INTERVAL` while it
+    /// keeps changing. Does nothing without a `persist_path`.
+    pub fn spawn_persistence(self: &Arc<Self>) {
+        let path = match &self.settings.persist_path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let cache = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PERSIST_INTERVAL);
+            loop {
+                interval.tick().await;
+                if !cache.dirty.swap(false, Ordering::SeqCst) {
+                    continue;
+                }
+                let entries = cache.state.lock().unwrap().entries.clone();
+                let save_path = path.clone();
+                let saved = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+                    // write then rename, so a crash never leaves half a file
+                    let tmp = save_path.with_extension("tmp");
+                    fs::write(&tmp, serde_json::to_vec(&entries)?)?;
+                    fs::rename(&tmp, &save_path)?;
+                    Ok(())
+                })
+                .await;
+                if let Ok(Err(e)) = saved {
+                    error!("failed to save scrape cache to {}: {}", path.display(), e);
+                }
+            }
+        });
+    }
+}
+
+fn fresh(entry: &Entry, ttl: Duration) -> bool {
+    entry.stored_at.elapsed().map_or(false, |age| age < ttl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_cache(max_entries: usize, ttl_ms: u64) -> ScrapeCache {
+        ScrapeCache::load(CacheSettings {
+            max_entries,
+            ttl_ms,
+            persist_path: None,
+        })
+    }
+
+    fn scraped(text: &str) -> Scraped {
+        Scraped {
+            text: text.to_string(),
+            timings: Default::default(),
+            usage: None,
+            links: Vec::new(),
+            metadata: None,
+            images: None,
+            tables: None,
+            pages: None,
+        }
+    }
+
+    fn text(cache: &ScrapeCache, key: &str) -> Option<String> {
+        cache.get(key).map(|scraped| scraped.text)
+    }
+
+    #[test]
+    fn a_full_cache_drops_the_least_recently_used_entry() {
+        let cache = new_cache(2, 60_000);
+        cache.insert("a".to_string(), scraped("first"));
+        cache.insert("b".to_string(), scraped("second"));
+        assert_eq!(text(&cache, "a").as_deref(), Some("first"));
+
+        cache.insert("c".to_string(), scraped("third"));
+        assert_eq!(text(&cache, "b"), None);
+        assert_eq!(text(&cache, "a").as_deref(), Some("first"));
+        assert_eq!(text(&cache, "c").as_deref(), Some("third"));
+    }
+
+    #[test]
+    fn replacing_an_entry_evicts_nothing() {
+        let cache = new_cache(2, 60_000);
+        cache.insert("a".to_string(), scraped("first"));
+        cache.insert("b".to_string(), scraped("second"));
+        cache.insert("a".to_string(), scraped("again"));
+        assert_eq!(text(&cache, "a").as_deref(), Some("again"));
+        assert_eq!(text(&cache, "b").as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn entries_expire_after_the_ttl() {
+        let cache = new_cache(2, 0);
+        cache.insert("a".to_string(), scraped("first"));
+        assert_eq!(text(&cache, "a"), None);
+
+        // an expired entry makes room before a live one is evicted
+        let cache = new_cache(2, 60_000);
+        cache.insert("b".to_string(), scraped("second"));
+        cache.insert("a".to_string(), scraped("first"));
+        cache
+            .state
+            .lock()
+            .unwrap()
+            .entries
+            .get_mut("a")
+            .unwrap()
+            .stored_at -= Duration::from_secs(120);
+        cache.insert("c".to_string(), scraped("third"));
+        assert_eq!(text(&cache, "a"), None);
+        assert_eq!(text(&cache, "b").as_deref(), Some("second"));
+        assert_eq!(text(&cache, "c").as_deref(), Some("third"));
+    }
+
+    #[test]
+    fn a_zero_sized_cache_keeps_nothing() {
+        let cache = new_cache(0, 60_000);
+        cache.insert("a".to_string(), scraped("first"));
+        assert_eq!(text(&cache, "a"), None);
+    }
+
+    #[test]
+    fn remove_drops_the_entries_under_a_prefix() {
+        let cache = new_cache(3, 60_000);
+        cache.insert("https://a.example/ 1".to_string(), scraped("a1"));
+        cache.insert("https://a.example/ 2".to_string(), scraped("a2"));
+        cache.insert("https://b.example/ 1".to_string(), scraped("b1"));
+        assert_eq!(cache.remove(Some("https://a.example/ ")), 2);
+        assert_eq!(text(&cache, "https://b.example/ 1").as_deref(), Some("b1"));
+        assert_eq!(cache.remove(None), 1);
+    }
+}
@@ -0,0 +1,604 @@
+use crate::admission::AdmissionController;
+use crate::archive::Archiver;
+use crate::blocklist::DomainBlocklist;
+use crate::browser::{has_credentials, open_tab, valid_host_overrides, Rendering};
+use crate::browser_pool::{BrowserPool, TabLease};
+use crate::config::Config;
+use crate::container::ChromeEnvironment;
+use crate::diagnostics::DiagnosticsStore;
+use crate::error::ScrapeError;
+use crate::extractor_cache::ExtractorCache;
+use crate::images::{self, ArticleImage};
+use crate::metadata::PageMetadata;
+use crate::metrics::metrics;
+use crate::pdf_extract::PageText;
+use crate::pipeline::{
+    dominant_language_text, scrape, suppress_duplicate_paragraphs, text_quality_score,
+    ExtractionMode, OutputFormat, ScrapeOptions, Scraped, Timings, EXTRACTOR_VERSION,
+};
+use crate::politeness::Politeness;
+use crate::post_process::PostProcessRules;
+use crate::proxy::Proxy;
+use crate::readability_extract::sanitize_html;
+use crate::resource_usage::ResourceUsage;
+use crate::scrape_cache::ScrapeCache;
+use crate::stable_hash::stable_hash;
+use crate::tables::Table;
+use crate::translate::TranslationBackend;
+use crate::wayback::{self, ArchivedSnapshot};
+use headless_chrome::protocol::cdp::Network;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::broadcast;
+use url::Url;
+use whatlang::Lang;
+
+/// Everything a scrape request goes through besides the browser work in
+/// `pipeline`: validation, blocklists, robots.txt and admission, the cache
+/// and coalescing of identical scrapes, then post-processing, the quality
+/// gate and translation of the result. The HTTP handlers in `main.rs` only
+/// parse requests and shape responses around it.
+#[derive(Clone)]
+pub struct ScrapeService {
+    pub config: Arc<Config>,
+    pub pool: Arc<BrowserPool>,
+    pub extractors: Arc<ExtractorCache>,
+    pub admission: Arc<AdmissionController>,
+    pub cache: Arc<ScrapeCache>,
+    pub politeness: Arc<Politeness>,
+    pub archiver: Arc<Archiver>,
+    pub blocklist: Arc<DomainBlocklist>,
+    pub diagnostics: Arc<DiagnosticsStore>,
+    pub post_process: Arc<PostProcessRules>,
+    /// Scrapes currently running, keyed by normalized URL. Requests for a URL
+    /// that is already being scraped subscribe to the running scrape instead
+    /// of rendering the page again.
+    in_flight: Arc<Mutex<HashMap<String, broadcast::Sender<Result<Scraped, ScrapeError>>>>>,
+}
+
+impl ScrapeService {
+    /// Sets up the browser pool, caches and background workers `config`
+    /// asks for. Must be called inside the Tokio runtime.
+    pub fn new(config: Config) -> anyhow::Result<Self> {
+        let pool = BrowserPool::new(
+            config.chrome_path.clone(),
+            ChromeEnvironment::detect(&config),
+            config.pool.clone(),
+        );
+        // with lazy_start, Chrome is launched by the first request instead
+        if !config.pool.lazy_start {
+            pool.spawn_maintenance();
+        }
+        let cache = Arc::new(ScrapeCache::load(config.cache.clone()));
+        cache.spawn_persistence();
+
+        Ok(ScrapeService {
+            pool,
+            extractors: Arc::new(ExtractorCache::new(config.extractor_cache_ttl())),
+            admission: Arc::new(AdmissionController::new(config.admission.clone())),
+            cache,
+            politeness: Arc::new(Politeness::new(config.politeness.clone())),
+            archiver: Arc::new(Archiver::start(config.archive.clone())),
+            blocklist: Arc::new(DomainBlocklist::load(&config.blocklists)?),
+            diagnostics: Arc::new(DiagnosticsStore::new(config.diagnostics.clone())),
+            post_process: Arc::new(PostProcessRules::from_env()?),
+            in_flight: Default::default(),
+            config: Arc::new(config),
+        })
+    }
+
+    /// Scrapes `url` (sharing any identical scrape in flight) and runs the
+    /// request's post-processing, quality gate and translation over the
+    /// result.
+    pub async fn process(&self, url: &str, options: &RequestOptions) -> Result<Processed, Failure> {
+        let started = Instant::now();
+        let res = self.process_request(url, options).await;
+        let outcome = match &res {
+            Ok(_) => "ok",
+            Err(Failure::Error(err)) => err.code(),
+            Err(Failure::LowQuality(rejection)) => rejection.error,
+        };
+        metrics()
+            .scrape_seconds
+            .with_label_values(&[outcome, options.scrape.format.name()])
+            .observe(started.elapsed().as_secs_f64());
+        res
+    }
+
+    async fn process_request(
+        &self,
+        url: &str,
+        options: &RequestOptions,
+    ) -> Result<Processed, Failure> {
+        let parsed_url = Url::from_str(url).map_err(|_| ScrapeError::InvalidUrl)?;
+
+        if !valid_host_overrides(&options.scrape.host_overrides) {
+            return Err(ScrapeError::InvalidHostOverrides.into());
+        }
+
+        // the page's own host still picks the post-processing rules and is
+        // what the blocklists judge
+        let host = parsed_url.host_str().unwrap_or("").to_string();
+        let flagged_categories = self.blocklist.check(&host)?;
+
+        let cached_scrape = |url: &Url| match (options.cache, cache_key(url, &options.scrape)) {
+            (Some(false), _) | (_, None) => None,
+            (_, Some(key)) => self.cache.get(&key),
+        };
+        // a cached live page needs no probe; a proxied request's probe
+        // would have to bypass its proxy, so it gets none
+        let mut cached = cached_scrape(&parsed_url);
+        let archived = if cached.is_none()
+            && options.archive_fallback
+            && options.scrape.host_overrides.is_empty()
+            && options.scrape.proxy.is_none()
+        {
+            wayback::fallback(
+                &parsed_url,
+                options.archive_timestamp.clone(),
+                probe_headers(&host, &options.scrape),
+            )
+            .await
+        } else {
+            None
+        };
+        let parsed_url = match &archived {
+            Some(snapshot) => {
+                let snapshot_url =
+                    Url::from_str(&snapshot.raw_url).map_err(|_| ScrapeError::InvalidUrl)?;
+                cached = cached_scrape(&snapshot_url);
+                snapshot_url
+            }
+            None => parsed_url,
+        };
+        let Scraped {
+            text: mut res,
+            mut timings,
+            usage,
+            links,
+            metadata,
+            mut images,
+            mut tables,
+            pages,
+        } = match cached {
+            Some(scraped) => scraped,
+            None => self.scrape_coalesced(&parsed_url, &options.scrape).await?,
+        };
+
+        let started = Instant::now();
+        let raw = options.scrape.mode == ExtractionMode::RawHtml;
+        if options.scrape.format == OutputFormat::Text && !raw {
+            res = self.post_process.apply(&host, &res);
+            if options.dedupe_paragraphs {
+                res = suppress_duplicate_paragraphs(&res);
+            }
+            if options.dominant_language_only {
+                res = dominant_language_text(&res);
+            }
+        }
+        if options.scrape.format == OutputFormat::Html && !raw {
+            res = sanitize_html(&res, options.allowed_tags.as_deref());
+        }
+        timings.add("post_process", started.elapsed());
+
+        if let Some(rejection) = quality_gate(options, &res, &timings, usage.as_ref()) {
+            return Err(Failure::LowQuality(rejection));
+        }
+
+        let translated_text = match &options.translate_to {
+            Some(target_lang) => Some(
+                translate(res.clone(), target_lang)
+                    .await
+                    .ok_or(ScrapeError::TranslationFailed)?,
+            ),
+            None => None,
+        };
+
+        if let (Some(images), Some(max_bytes)) = (&mut images, options.inline_images_max_bytes) {
+            images::inline(images, max_bytes).await;
+        }
+        if options.tables_csv {
+            for table in tables.iter_mut().flatten() {
+                table.csv = Some(table.to_csv());
+            }
+        }
+
+        // a snapshot is already in the archive
+        let archive_url = match (&archived, options.save_to_archive) {
+            (None, true) => self.archiver.submit(&parsed_url),
+            _ => None,
+        };
+
+        Ok(Processed {
+            text: res,
+            translated_text,
+            timings,
+            usage,
+            links,
+            metadata,
+            images,
+            tables,
+            archived,
+            archive_url,
+            pages,
+            flagged_categories,
+        })
+    }
+
+    /// Validates a single-page request that works on the tab itself
+    /// (screenshots, PDFs) and checks out a tab for it.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn checkout(
+        &self,
+        url: &str,
+        host_overrides: &BTreeMap<String, IpAddr>,
+        proxy: Option<&Proxy>,
+        cookies: &[Network::CookieParam],
+        headers: &BTreeMap<String, String>,
+        rendering: Rendering,
+    ) -> Result<TabLease, ScrapeError> {
+        let parsed_url = Url::from_str(url).map_err(|_| ScrapeError::InvalidUrl)?;
+        if !valid_host_overrides(host_overrides) {
+            return Err(ScrapeError::InvalidHostOverrides);
+        }
+        // there's no result to flag a screenshot or PDF in
+        self.blocklist.check(parsed_url.host_str().unwrap_or(""))?;
+        if !self.politeness.allowed(&parsed_url).await {
+            return Err(ScrapeError::DisallowedByRobots);
+        }
+        self.politeness.wait_turn(&domain_of(&parsed_url)).await;
+
+        open_tab(
+            &self.pool,
+            &self.config,
+            url,
+            host_overrides,
+            proxy,
+            cookies,
+            headers,
+            rendering,
+        )
+        .await
+    }
+
+    /// Joins the in-flight scrape for `url` if there is one with the same
+    /// options, otherwise starts it. The scrape runs in its own task so it
+    /// finishes (and wakes every waiter) even if the request that started it
+    /// goes away. Only the scrape that starts checks robots.txt and
+    /// admission and waits its turn at the host; requests joining it share
+    /// its result, refusals included.
+    async fn scrape_coalesced(
+        &self,
+        url: &Url,
+        options: &ScrapeOptions,
+    ) -> Result<Scraped, ScrapeError> {
+        let key = format!("{} {:016x}", normalize_url(url), options.output_hash());
+
+        let mut rx = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(tx) => tx.subscribe(),
+                None => {
+                    let (tx, rx) = broadcast::channel(1);
+                    in_flight.insert(key.clone(), tx.clone());
+
+                    let in_flight = self.in_flight.clone();
+                    let domain = domain_of(url);
+                    let cache_entry = cache_key(url, options);
+                    let parsed_url = url.clone();
+                    let url = url.to_string();
+                    let options = options.clone();
+                    let config = self.config.clone();
+                    let pool = self.pool.clone();
+                    let extractors = self.extractors.clone();
+                    let diagnostics = self.diagnostics.clone();
+                    let admission = self.admission.clone();
+                    let politeness = self.politeness.clone();
+                    let cache = self.cache.clone();
+                    tokio::spawn(async move {
+                        let res = async {
+                            if !politeness.allowed(&parsed_url).await {
+                                return Err(ScrapeError::DisallowedByRobots);
+                            }
+                            if let Err(retry_after) = admission.admit(&domain) {
+                                return Err(ScrapeError::Overloaded { retry_after });
+                            }
+                            politeness.wait_turn(&domain).await;
+                            tokio::spawn(scrape(
+                                url,
+                                options,
+                                config,
+                                pool,
+                                extractors,
+                                diagnostics,
+                            ))
+                            .await
+                            .unwrap_or(Err(ScrapeError::ExtractionFailed))
+                        }
+                        .await;
+                        // usage measured while other requests shared the
+                        // browser isn't this domain's to pay for
+                        if let Ok(Scraped {
+                            usage: Some(usage), ..
+                        }) = &res
+                        {
+                            if !usage.shared {
+                                admission.record(&domain, usage);
+                            }
+                        }
+                        if let (Ok(scraped), Some(cache_entry)) = (&res, cache_entry) {
+                            cache.insert(cache_entry, scraped.clone());
+                        }
+                        in_flight.lock().unwrap().remove(&key);
+                        let _ = tx.send(res);
+                    });
+                    rx
+                }
+            }
+        };
+
+        rx.recv()
+            .await
+            .unwrap_or(Err(ScrapeError::ExtractionFailed))
+    }
+}
+
+/// Options of a scrape request: what gets scraped, and what's done with
+/// the result afterwards.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RequestOptions {
+    #[serde(flatten)]
+    pub scrape: ScrapeOptions,
+    /// `false` scrapes the page again even if a cached scrape is fresh; the
+    /// new result still replaces the cached one.
+    pub cache: Option<bool>,
+    /// Drop paragraphs that nearly repeat an earlier one (teasers, pull
+    /// quotes, "related" blurbs).
+    #[serde(default)]
+    pub dedupe_paragraphs: bool,
+    /// On mixed-language pages, keep only the sentences written in the
+    /// page's dominant language.
+    #[serde(default)]
+    pub dominant_language_only: bool,
+    /// Also return the text translated into this language (e.g. `"en"`).
+    #[serde(default)]
+    pub translate_to: Option<String>,
+    /// Tags kept by the HTML sanitizer, replacing ammonia's default
+    /// allowlist. Only used with `format: "html"`.
+    #[serde(default)]
+    pub allowed_tags: Option<Vec<String>>,
+    /// Fail with `low_quality_extraction` when the result has fewer words.
+    #[serde(default)]
+    pub min_words: Option<usize>,
+    /// Fail with `low_quality_extraction` when `text_quality_score` is lower.
+    #[serde(default)]
+    pub min_quality_score: Option<f64>,
+    /// Attach the rejected text to `low_quality_extraction` errors.
+    #[serde(default)]
+    pub include_rejected_text: bool,
+    /// Download the article's images and inline those of at most this many
+    /// bytes as `data:` URLs. Only used with `images`.
+    #[serde(default)]
+    pub inline_images_max_bytes: Option<usize>,
+    /// Add each table's rows as a CSV string. Only used with `tables`.
+    #[serde(default)]
+    pub tables_csv: bool,
+    /// When the page answers 404 or 410, or its host doesn't resolve or
+    /// refuses connections, scrape its latest Wayback Machine snapshot
+    /// instead and say so in `archived`.
+    /// The page is checked with the request's headers and cookies. Not used
+    /// with `host_overrides` or `proxy`, or when the live page is cached.
+    #[serde(default)]
+    pub archive_fallback: bool,
+    /// With `archive_fallback`, the snapshot closest to this time
+    /// (`YYYYMMDDhhmmss` or a prefix of it) instead of the latest.
+    #[serde(default)]
+    pub archive_timestamp: Option<String>,
+    /// Submit the page to the Internet Archive's Save Page Now in the
+    /// background once it's scraped, and return the Wayback Machine URL to
+    /// cite it by in `archive_url`.
+    #[serde(default)]
+    pub save_to_archive: bool,
+}
+
+/// A scrape that made it through post-processing.
+pub struct Processed {
+    pub text: String,
+    pub translated_text: Option<String>,
+    pub timings: Timings,
+    pub usage: Option<ResourceUsage>,
+    /// Only collected for crawls.
+    pub links: Vec<String>,
+    pub metadata: Option<PageMetadata>,
+    pub images: Option<Vec<ArticleImage>>,
+    pub tables: Option<Vec<Table>>,
+    pub archived: Option<ArchivedSnapshot>,
+    pub archive_url: Option<String>,
+    pub pages: Option<Vec<PageText>>,
+    /// Blocklist categories the page's domain is flagged under.
+    pub flagged_categories: Vec<String>,
+}
+
+/// Why a request produced no usable result.
+pub enum Failure {
+    Error(ScrapeError),
+    LowQuality(LowQualityResponse),
+}
+
+impl From<ScrapeError> for Failure {
+    fn from(err: ScrapeError) -> Self {
+        Failure::Error(err)
+    }
+}
+
+/// The body of a `low_quality_extraction` error.
+#[derive(Debug, Serialize)]
+pub struct LowQualityResponse {
+    pub error: &'static str,
+    pub words: usize,
+    pub quality_score: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    pub timings: Timings,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<ResourceUsage>,
+}
+
+/// The languages a batch or crawl keeps, by the language detected in each
+/// page's text, e.g. `{"allowed": ["eng", "deu"], "action": "flag"}`.
+#[derive(Debug, Deserialize)]
+pub struct LanguageFilter {
+    /// ISO 639-3 codes.
+    pub allowed: Vec<String>,
+    #[serde(default)]
+    pub action: LanguageAction,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LanguageAction {
+    /// Report other pages with status `filtered` and without their text.
+    #[default]
+    Discard,
+    /// Keep other pages, with `language_allowed: false`.
+    Flag,
+}
+
+impl LanguageFilter {
+    pub fn is_valid(&self) -> bool {
+        !self.allowed.is_empty()
+            && self
+                .allowed
+                .iter()
+                .all(|code| Lang::from_code(code.to_lowercase()).is_some())
+    }
+
+    /// The ISO 639-3 code of the language `res` is written in, if it can be
+    /// detected, and whether that language is allowed. Pages whose language
+    /// can't be detected aren't.
+    pub fn detect(&self, options: &RequestOptions, res: &str) -> (Option<String>, bool) {
+        let lang = whatlang::detect_lang(&plain_text(options, res));
+        let allowed = lang.map_or(false, |lang| {
+            self.allowed
+                .iter()
+                .any(|code| code.eq_ignore_ascii_case(lang.code()))
+        });
+        (lang.map(|lang| lang.code().to_string()), allowed)
+    }
+}
+
+/// Checks the result against the request's `min_words` / `min_quality_score`
+/// and builds the `low_quality_extraction` error when it falls short, so
+/// thin content fails loudly instead of flowing downstream.
+fn quality_gate(
+    options: &RequestOptions,
+    res: &str,
+    timings: &Timings,
+    usage: Option<&ResourceUsage>,
+) -> Option<LowQualityResponse> {
+    if options.min_words.is_none() && options.min_quality_score.is_none() {
+        return None;
+    }
+
+    let text = plain_text(options, res);
+    let words = text.split_whitespace().count();
+    let quality_score = text_quality_score(&text);
+
+    let too_short = options.min_words.map_or(false, |min| words < min);
+    let too_poor = options
+        .min_quality_score
+        .map_or(false, |min| quality_score < min);
+    if !too_short && !too_poor {
+        return None;
+    }
+
+    Some(LowQualityResponse {
+        error: "low_quality_extraction",
+        words,
+        quality_score,
+        text: options.include_rejected_text.then(|| res.to_string()),
+        timings: timings.clone(),
+        usage: usage.cloned(),
+    })
+}
+
+/// The result as text to measure or detect the language of: HTML results
+/// are converted first.
+fn plain_text(options: &RequestOptions, res: &str) -> String {
+    match options.scrape.format {
+        OutputFormat::Text | OutputFormat::Markdown
+            if options.scrape.mode != ExtractionMode::RawHtml =>
+        {
+            res.to_string()
+        }
+        _ => html2text::from_read(res.as_bytes(), 80),
+    }
+}
+
+/// Runs `text` through the configured translation backend.
+async fn translate(text: String, target_lang: &str) -> Option<String> {
+    let target_lang = target_lang.to_string();
+    tokio::task::spawn_blocking(move || {
+        TranslationBackend::from_env()?.translate(&text, &target_lang)
+    })
+    .await
+    .ok()?
+    .ok()
+}
+
+/// Lowercased host of `url`, the key for per-domain bookkeeping.
+fn domain_of(url: &Url) -> String {
+    url.host_str().unwrap_or("").to_lowercase()
+}
+
+/// Strips the parts of a URL that don't change what gets rendered, so
+/// `https://Example.com/a#top` and `https://example.com/a` share one scrape.
+pub fn normalize_url(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    url.to_string()
+}
+
+/// The request's headers, and its cookies for `host` as a `Cookie` header,
+/// for checking whether the page is gone the way the browser would ask.
+fn probe_headers(host: &str, options: &ScrapeOptions) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = options
+        .headers
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    let cookies: Vec<String> = options
+        .cookies
+        .iter()
+        .filter(|cookie| {
+            cookie.domain.as_deref().map_or(true, |domain| {
+                let domain = domain.trim_start_matches('.');
+                host == domain
+                    || host
+                        .strip_suffix(domain)
+                        .map_or(false, |sub| sub.ends_with('.'))
+            })
+        })
+        .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+        .collect();
+    if !cookies.is_empty() {
+        headers.push(("Cookie".to_string(), cookies.join("; ")));
+    }
+    headers
+}
+
+/// Where a scrape of `url` with `options` is kept in the `ScrapeCache`:
+/// the normalized URL, so all of a URL's entries can be dropped at once,
+/// then a hash of the options that change the result and
+/// `EXTRACTOR_VERSION`. Scrapes with cookies or headers are never cached.
+fn cache_key(url: &Url, options: &ScrapeOptions) -> Option<String> {
+    if has_credentials(&options.cookies, &options.headers) {
+        return None;
+    }
+    let hash = stable_hash(&(options.output_hash(), EXTRACTOR_VERSION));
+    Some(format!("{} {:016x}", normalize_url(url), hash))
+}
@@ -0,0 +1,76 @@
+use log::warn;
+use std::env;
+use std::net::TcpListener;
+
+/// First file descriptor systemd passes to socket-activated services.
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// The listening socket systemd handed over through socket activation
+/// (`LISTEN_FDS`/`LISTEN_PID`), if the service was started that way. Only
+/// the first socket is used.
+#[cfg(unix)]
+pub fn activated_listener() -> Option<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .map_or(false, |pid| pid == std::process::id());
+    let fds = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<i32>().ok())
+        .unwrap_or(0);
+    // don't let Chrome or anything else we spawn think it was activated
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    if !for_us || fds < 1 {
+        return None;
+    }
+    // SAFETY: systemd guarantees fd 3 is an open socket owned by us when
+    // LISTEN_PID names this process.
+    Some(unsafe { TcpListener::from_raw_fd(SD_LISTEN_FDS_START) })
+}
+
+#[cfg(not(unix))]
+pub fn activated_listener() -> Option<TcpListener> {
+    None
+}
+
+/// Sends `state` (e.g. `READY=1`) to the service manager when running under
+/// systemd with `Type=notify`; does nothing otherwise.
+#[cfg(unix)]
+pub fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let socket = match UnixDatagram::unbound() {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("sd_notify: {}", e);
+            return;
+        }
+    };
+
+    let sent = match path.strip_prefix('@') {
+        // abstract socket namespace
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr))
+        }
+        _ => socket.send_to(state.as_bytes(), &path),
+    };
+    if let Err(e) = sent {
+        warn!("sd_notify: {}", e);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) {}
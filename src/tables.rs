@@ -0,0 +1,105 @@
+use headless_chrome::browser::Tab;
+use serde::{Deserialize, Serialize};
+
+/// Reads every `<table>` of the rendered document as rows of cell text,
+/// as a JSON string. A cell spanning columns is followed by empty cells so
+/// the columns stay aligned; tables without text are left out.
+const READ_TABLES: &str = r#"
+    JSON.stringify(
+        Array.from(document.querySelectorAll("table"), (table) =>
+            Array.from(table.rows, (row) =>
+                Array.from(row.cells).flatMap((cell) => [
+                    cell.innerText.replace(/\s+/g, " ").trim(),
+                    ...Array(Math.max(cell.colSpan, 1) - 1).fill(""),
+                ])
+            ).filter((row) => row.length)
+        ).filter((rows) => rows.some((row) => row.some((cell) => cell)))
+    )
+"#;
+
+/// A table of the page, which PDF text extraction would flatten into the
+/// surrounding prose.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Table {
+    /// Cell text by row, header rows included.
+    pub rows: Vec<Vec<String>>,
+    /// The rows as CSV, when `tables_csv` asked for it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub csv: Option<String>,
+}
+
+impl Table {
+    /// The rows as RFC 4180 CSV: fields with commas, quotes or line breaks
+    /// are quoted, and lines end with CRLF.
+    pub fn to_csv(&self) -> String {
+        self.rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|cell| {
+                        if cell.contains([',', '"', '\n', '\r']) {
+                            format!("\"{}\"", cell.replace('"', "\"\""))
+                        } else {
+                            cell.clone()
+                        }
+                    })
+                    .collect::<Vec<String>>()
+                    .join(",")
+                    + "\r\n"
+            })
+            .collect()
+    }
+}
+
+/// The tables of the page the tab shows, or `None` when it can't be read.
+pub fn page_tables(tab: &Tab) -> Option<Vec<Table>> {
+    let rows: Vec<Vec<Vec<String>>> = tab
+        .evaluate(READ_TABLES, false)
+        .ok()
+        .and_then(|r| r.value)
+        .and_then(|v| v.as_str().and_then(|s| serde_json::from_str(s).ok()))?;
+    Some(
+        rows.into_iter()
+            .map(|rows| Table { rows, csv: None })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csv(rows: &[&[&str]]) -> String {
+        Table {
+            rows: rows
+                .iter()
+                .map(|row| row.iter().map(|cell| cell.to_string()).collect())
+                .collect(),
+            csv: None,
+        }
+        .to_csv()
+    }
+
+    #[test]
+    fn plain_cells_are_left_alone() {
+        assert_eq!(
+            csv(&[&["name", "age"], &["Ada", "36"]]),
+            "name,age\r\nAda,36\r\n"
+        );
+    }
+
+    #[test]
+    fn commas_quotes_and_line_breaks_are_quoted() {
+        assert_eq!(
+            csv(&[&["Lovelace, Ada", "say \"hi\"", "two\nlines", "cr\rhere"]]),
+            "\"Lovelace, Ada\",\"say \"\"hi\"\"\",\"two\nlines\",\"cr\rhere\"\r\n"
+        );
+    }
+
+    #[test]
+    fn empty_cells_and_tables_stay_empty() {
+        assert_eq!(csv(&[&["", "x", ""]]), ",x,\r\n");
+        assert_eq!(csv(&[&[]]), "\r\n");
+        assert_eq!(csv(&[]), "");
+    }
+}
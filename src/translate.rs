@@ -0,0 +1,231 @@
+use anyhow::{anyhow, bail};
+use http_req::{
+    request::{Method, Request},
+    uri::Uri,
+};
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+use std::env;
+
+/// Texts longer than this are translated in line-aligned chunks so a single
+/// call stays within the backend's request and context limits.
+const MAX_CHUNK_CHARS: usize = 4000;
+
+/// Where translations are sent. Chosen with `SCRAPER_TRANSLATE_BACKEND`
+/// (`deepl` or `openai`); a local model is any server speaking the
+/// OpenAI-compatible chat completions API.
+pub enum TranslationBackend {
+    DeepL {
+        url: String,
+        api_key: String,
+    },
+    OpenAiCompatible {
+        url: String,
+        api_key: Option<String>,
+        model: String,
+    },
+}
+
+impl TranslationBackend {
+    /// Reads the backend from the environment:
+    ///
+    /// - `SCRAPER_TRANSLATE_BACKEND`: `deepl` or `openai`
+    /// - `SCRAPER_TRANSLATE_URL`: endpoint, defaults to the public API
+    /// - `SCRAPER_TRANSLATE_API_KEY`: required for DeepL
+    /// - `SCRAPER_TRANSLATE_MODEL`: model name for the OpenAI-compatible API
+    pub fn from_env() -> anyhow::Result<Self> {
+        let backend = env::var("SCRAPER_TRANSLATE_BACKEND")
+            .map_err(|_| anyhow!("SCRAPER_TRANSLATE_BACKEND is not set"))?;
+        let url = env::var("SCRAPER_TRANSLATE_URL").ok();
+        let api_key = env::var("SCRAPER_TRANSLATE_API_KEY").ok();
+
+        match backend.as_str() {
+            "deepl" => Ok(TranslationBackend::DeepL {
+                url: url.unwrap_or_else(|| "https://api-free.deepl.com/v2/translate".to_string()),
+                api_key: api_key.ok_or_else(|| anyhow!("SCRAPER_TRANSLATE_API_KEY is not set"))?,
+            }),
+            "openai" => Ok(TranslationBackend::OpenAiCompatible {
+                url: url.unwrap_or_else(|| {
+                    "https://api.openai.com/v1/chat/completions".to_string()
+                }),
+                api_key,
+                model: env::var("SCRAPER_TRANSLATE_MODEL")
+                    .unwrap_or_else(|_| "gpt-3.5-turbo".to_string()),
+            }),
+            other => bail!("unknown translation backend: {}", other),
+        }
+    }
+
+    /// Translates `text` into `target_lang` (an ISO 639-1 code such as `en`).
+    /// This blocks on network I/O; call it from `spawn_blocking`.
+    pub fn translate(&self, text: &str, target_lang: &str) -> anyhow::Result<String> {
+        let mut translated = Vec::new();
+        for chunk in chunk_text(text) {
+            translated.push(match self {
+                TranslationBackend::DeepL { url, api_key } => {
+                    translate_deepl(url, api_key, chunk, target_lang)?
+                }
+                TranslationBackend::OpenAiCompatible {
+                    url,
+                    api_key,
+                    model,
+                } => translate_openai(url, api_key.as_deref(), model, chunk, target_lang)?,
+            });
+        }
+
+        Ok(translated.join("\n"))
+    }
+}
+
+fn translate_deepl(
+    url: &str,
+    api_key: &str,
+    text: &str,
+    target_lang: &str,
+) -> anyhow::Result<String> {
+    let body = json!({
+        "text": [text],
+        "target_lang": target_lang.to_uppercase(),
+    });
+    let auth = format!("DeepL-Auth-Key {}", api_key);
+    let res = post_json(url, Some(&auth), &body)?;
+
+    res["translations"][0]["text"]
+        .as_str()
+        .map(|s| s.to_string())
+        .ok_or_else(|| anyhow!("unexpected DeepL response"))
+}
+
+fn translate_openai(
+    url: &str,
+    api_key: Option<&str>,
+    model: &str,
+    text: &str,
+    target_lang: &str,
+) -> anyhow::Result<String> {
+    let body = json!({
+        "model": model,
+        "temperature": 0,
+        "messages": [
+            {
+                "role": "system",
+                "content": format!(
+                    "Translate the user's text into the language with ISO code '{}'. \
+                     Reply with the translation only.",
+                    target_lang
+                ),
+            },
+            { "role": "user", "content": text },
+        ],
+    });
+    let auth = api_key.map(|key| format!("Bearer {}", key));
+    let res = post_json(url, auth.as_deref(), &body)?;
+
+    res["choices"][0]["message"]["content"]
+        .as_str()
+        .map(|s| s.trim().to_string())
+        .ok_or_else(|| anyhow!("unexpected chat completion response"))
+}
+
+fn post_json(url: &str, authorization: Option<&str>, body: &Value) -> anyhow::Result<Value> {
+    let uri = Uri::try_from(url)?;
+    let body = serde_json::to_vec(body)?;
+    let mut writer = Vec::new();
+
+    let mut req = Request::new(&uri);
+    req.method(Method::POST)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", &body.len())
+        .body(&body);
+    if let Some(auth) = authorization {
+        req.header("Authorization", auth);
+    }
+
+    let res = req.send(&mut writer)?;
+    if !res.status_code().is_success() {
+        bail!(
+            "translation backend returned {}: {}",
+            res.status_code(),
+            String::from_utf8_lossy(&writer)
+        );
+    }
+
+    Ok(serde_json::from_slice(&writer)?)
+}
+
+/// Splits on line boundaries into pieces of at most `MAX_CHUNK_CHARS`
+/// (a single longer line becomes its own chunk).
+fn chunk_text(text: &str) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut end = 0;
+
+    for (i, _) in text.match_indices('\n') {
+        if i - start > MAX_CHUNK_CHARS && end > start {
+            chunks.push(&text[start..end]);
+            start = end + 1;
+        }
+        end = i;
+    }
+    if text.len() - start > MAX_CHUNK_CHARS && end > start {
+        chunks.push(&text[start..end]);
+        start = end + 1;
+    }
+    if start < text.len() {
+        chunks.push(&text[start..]);
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lengths(text: &str) -> Vec<usize> {
+        chunk_text(text).iter().map(|chunk| chunk.len()).collect()
+    }
+
+    #[test]
+    fn empty_text_has_no_chunks() {
+        assert!(chunk_text("").is_empty());
+    }
+
+    #[test]
+    fn text_of_exactly_the_limit_is_one_chunk() {
+        let text = "a".repeat(MAX_CHUNK_CHARS);
+        assert_eq!(lengths(&text), vec![MAX_CHUNK_CHARS]);
+
+        let half = MAX_CHUNK_CHARS / 2;
+        let text = format!("{}\n{}", "a".repeat(half), "b".repeat(MAX_CHUNK_CHARS - half - 1));
+        assert_eq!(lengths(&text), vec![MAX_CHUNK_CHARS]);
+    }
+
+    #[test]
+    fn one_byte_over_the_limit_splits_at_the_last_line_break() {
+        let half = MAX_CHUNK_CHARS / 2;
+        let text = format!("{}\n{}", "a".repeat(half), "b".repeat(MAX_CHUNK_CHARS - half));
+        assert_eq!(lengths(&text), vec![half, MAX_CHUNK_CHARS - half]);
+
+        let text = format!("{}\nb", "a".repeat(MAX_CHUNK_CHARS));
+        assert_eq!(lengths(&text), vec![MAX_CHUNK_CHARS, 1]);
+    }
+
+    #[test]
+    fn a_long_line_is_its_own_chunk() {
+        let long = "a".repeat(MAX_CHUNK_CHARS + 1);
+        assert_eq!(lengths(&long), vec![MAX_CHUNK_CHARS + 1]);
+        assert_eq!(lengths(&format!("x\n{}", long)), vec![1, MAX_CHUNK_CHARS + 1]);
+        assert_eq!(lengths(&format!("{}\nx", long)), vec![MAX_CHUNK_CHARS + 1, 1]);
+    }
+
+    #[test]
+    fn chunks_join_back_into_the_text() {
+        let line = format!("{}\n", "word ".repeat(30));
+        let text = line.repeat(100);
+        let chunks = chunk_text(&text);
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= MAX_CHUNK_CHARS));
+        assert_eq!(chunks.join("\n"), text);
+    }
+}
@@ -0,0 +1,394 @@
+use anyhow::anyhow;
+use headless_chrome::browser::Tab;
+use headless_chrome::protocol::cdp::DOMSnapshot::{self, DocumentSnapshot};
+use std::collections::HashMap;
+
+/// Computed styles asked of the snapshot, in this order.
+const STYLES: [&str; 2] = ["display", "visibility"];
+
+/// Kept whole whatever their styles say: they never render, but JSON-LD
+/// scripts carry metadata and the others are harmless to Readability.
+const UNRENDERED: [&str; 3] = ["script", "style", "template"];
+
+/// Elements serialized without a closing tag.
+const VOID: [&str; 13] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// Elements whose text is serialized unescaped.
+const RAW_TEXT: [&str; 6] = ["script", "style", "xmp", "iframe", "noembed", "noframes"];
+
+const ELEMENT_NODE: u32 = 1;
+const TEXT_NODE: u32 = 3;
+const DOCUMENT_TYPE_NODE: u32 = 10;
+
+/// The HTML of the page in `tab` without the elements the reader can't see
+/// (`display: none`, `visibility: hidden`, `aria-hidden="true"`), so
+/// Readability doesn't pick up collapsed menus, modals or screen-reader junk.
+///
+/// Visibility comes from the computed styles of a `DOMSnapshot`, which the
+/// static markup alone can't tell us, and the HTML is serialized from the
+/// snapshot, so the page itself is left alone. An element that's hidden
+/// but has visible descendants (`visibility: visible` under a
+/// `visibility: hidden` parent) is kept for their sake.
+pub fn visible_html(tab: &Tab) -> anyhow::Result<String> {
+    let snapshot = tab.call_method(DOMSnapshot::CaptureSnapshot {
+        computed_styles: STYLES.iter().map(|style| style.to_string()).collect(),
+        include_paint_order: None,
+        include_dom_rects: None,
+        include_blended_background_colors: None,
+        include_text_color_opacities: None,
+    })?;
+    let document = snapshot
+        .documents
+        .first()
+        .ok_or_else(|| anyhow!("the DOM snapshot has no document"))?;
+    Ok(Snapshot::new(document, &snapshot.strings).html())
+}
+
+/// One snapshot document as a tree.
+struct Snapshot<'a> {
+    node_types: Vec<u32>,
+    names: Vec<String>,
+    values: Vec<Option<&'a str>>,
+    attributes: Vec<Vec<(&'a str, &'a str)>>,
+    parents: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+    /// Nodes Chrome laid out and didn't style invisible.
+    shown: Vec<bool>,
+}
+
+impl<'a> Snapshot<'a> {
+    fn new(document: &'a DocumentSnapshot, strings: &'a [String]) -> Self {
+        let nodes = &document.nodes;
+        let string = |index| usize::try_from(index).ok().and_then(|i| strings.get(i));
+        let count = nodes.node_type.as_ref().map_or(0, Vec::len);
+
+        let mut snapshot = Snapshot {
+            node_types: nodes
+                .node_type
+                .iter()
+                .flatten()
+                .map(|node_type| u32::try_from(*node_type).unwrap_or(0))
+                .collect(),
+            names: (0..count)
+                .map(|i| {
+                    nodes
+                        .node_name
+                        .as_ref()
+                        .and_then(|names| names.get(i))
+                        .and_then(|name| string(*name))
+                        .map_or(String::new(), |name| name.to_ascii_lowercase())
+                })
+                .collect(),
+            values: (0..count)
+                .map(|i| {
+                    nodes
+                        .node_value
+                        .as_ref()
+                        .and_then(|values| values.get(i))
+                        .and_then(|value| string(*value))
+                        .map(String::as_str)
+                })
+                .collect(),
+            attributes: (0..count)
+                .map(|i| {
+                    let flat = nodes.attributes.as_ref().and_then(|all| all.get(i));
+                    flat.map_or(Vec::new(), |flat| {
+                        flat.chunks(2)
+                            .filter_map(|pair| match pair {
+                                [name, value] => {
+                                    Some((string(*name)?.as_str(), string(*value)?.as_str()))
+                                }
+                                _ => None,
+                            })
+                            .collect()
+                    })
+                })
+                .collect(),
+            parents: vec![None; count],
+            children: vec![Vec::new(); count],
+            shown: vec![false; count],
+        };
+
+        // parents come before their children, so pushing in order keeps
+        // siblings in document order
+        for (i, parent) in nodes.parent_index.iter().flatten().enumerate() {
+            if let Ok(parent) = usize::try_from(i64::from(*parent)) {
+                if parent < count && i < count {
+                    snapshot.parents[i] = Some(parent);
+                    snapshot.children[parent].push(i);
+                }
+            }
+        }
+
+        let layout = &document.layout;
+        let mut styles: HashMap<usize, Vec<&str>> = HashMap::new();
+        for (node, node_styles) in layout.node_index.iter().zip(&layout.styles) {
+            if let Ok(node) = usize::try_from(*node) {
+                let values = node_styles
+                    .iter()
+                    .map(|value| string(*value).map_or("", String::as_str))
+                    .collect();
+                styles.insert(node, values);
+            }
+        }
+        for (node, shown) in snapshot.shown.iter_mut().enumerate() {
+            // elements that are `display: none` aren't laid out at all
+            *shown = styles.get(&node).map_or(false, |values| shown_by(values));
+        }
+
+        snapshot
+    }
+
+    fn html(&self) -> String {
+        let mut visible = vec![false; self.names.len()];
+        for node in (0..self.names.len()).rev() {
+            // children come after their parents, so they're settled already
+            visible[node] =
+                self.shown[node] || self.children[node].iter().any(|child| visible[*child]);
+        }
+
+        // node 0 is the document itself
+        let mut html = String::new();
+        for child in self.children.first().into_iter().flatten() {
+            self.write(*child, &visible, false, false, &mut html);
+        }
+        html
+    }
+
+    /// Serializes `node`. Inside `<body>` hidden subtrees are left out;
+    /// `whole` keeps everything under an unrendered element.
+    fn write(&self, node: usize, visible: &[bool], in_body: bool, whole: bool, html: &mut String) {
+        let name = self.names[node].as_str();
+        match self.node_types[node] {
+            ELEMENT_NODE => {
+                let whole = whole || UNRENDERED.contains(&name);
+                let aria_hidden = self.attributes[node].iter().any(|(name, value)| {
+                    name.eq_ignore_ascii_case("aria-hidden") && *value == "true"
+                });
+                if in_body && !whole && (aria_hidden || !visible[node]) {
+                    return;
+                }
+
+                html.push('<');
+                html.push_str(name);
+                for (attribute, value) in &self.attributes[node] {
+                    html.push(' ');
+                    html.push_str(attribute);
+                    html.push_str("=\"");
+                    html.push_str(&escape(value, true));
+                    html.push('"');
+                }
+                html.push('>');
+                if VOID.contains(&name) {
+                    return;
+                }
+                let in_body = in_body || name == "body";
+                for child in &self.children[node] {
+                    self.write(*child, visible, in_body, whole, html);
+                }
+                html.push_str("</");
+                html.push_str(name);
+                html.push('>');
+            }
+            TEXT_NODE => {
+                let text = self.values[node].unwrap_or("");
+                // whitespace between hidden blocks isn't laid out, but
+                // dropping it could glue words together
+                if in_body && !whole && !visible[node] && !text.trim().is_empty() {
+                    return;
+                }
+                let raw = self.parents[node].map_or(false, |parent| {
+                    RAW_TEXT.contains(&self.names[parent].as_str())
+                });
+                if raw {
+                    html.push_str(text);
+                } else {
+                    html.push_str(&escape(text, false));
+                }
+            }
+            DOCUMENT_TYPE_NODE => html.push_str("<!DOCTYPE html>"),
+            // comments, and shadow roots, which the page's own HTML
+            // doesn't include either
+            _ => {}
+        }
+    }
+}
+
+/// Whether a laid out node with these computed `STYLES` shows.
+fn shown_by(values: &[&str]) -> bool {
+    values.first() != Some(&"none") && !matches!(values.get(1), Some(&"hidden") | Some(&"collapse"))
+}
+
+fn escape(text: &str, attribute: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '"' if attribute => escaped.push_str("&quot;"),
+            '<' if !attribute => escaped.push_str("&lt;"),
+            '>' if !attribute => escaped.push_str("&gt;"),
+            '\u{a0}' => escaped.push_str("&nbsp;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A snapshot built node by node; node 0 is the document.
+    struct Tree(Snapshot<'static>);
+
+    impl Tree {
+        /// A document with a doctype, `<html>`, an unrendered `<head>` and
+        /// a `<body>`, returned with the body's index.
+        fn page() -> (Self, usize) {
+            let mut tree = Tree(Snapshot {
+                node_types: vec![9],
+                names: vec!["#document".to_string()],
+                values: vec![None],
+                attributes: vec![Vec::new()],
+                parents: vec![None],
+                children: vec![Vec::new()],
+                shown: vec![false],
+            });
+            tree.add(0, DOCUMENT_TYPE_NODE, "html", None, &[], false);
+            let html = tree.element(0, "html", &[], true);
+            tree.element(html, "head", &[], false);
+            let body = tree.element(html, "body", &[], true);
+            (tree, body)
+        }
+
+        fn add(
+            &mut self,
+            parent: usize,
+            node_type: u32,
+            name: &str,
+            value: Option<&'static str>,
+            attributes: &[(&'static str, &'static str)],
+            shown: bool,
+        ) -> usize {
+            let node = self.0.names.len();
+            self.0.node_types.push(node_type);
+            self.0.names.push(name.to_string());
+            self.0.values.push(value);
+            self.0.attributes.push(attributes.to_vec());
+            self.0.parents.push(Some(parent));
+            self.0.children.push(Vec::new());
+            self.0.shown.push(shown);
+            self.0.children[parent].push(node);
+            node
+        }
+
+        fn element(
+            &mut self,
+            parent: usize,
+            name: &str,
+            attributes: &[(&'static str, &'static str)],
+            shown: bool,
+        ) -> usize {
+            self.add(parent, ELEMENT_NODE, name, None, attributes, shown)
+        }
+
+        fn text(&mut self, parent: usize, text: &'static str, shown: bool) -> usize {
+            self.add(parent, TEXT_NODE, "#text", Some(text), &[], shown)
+        }
+    }
+
+    fn page_html(body: &str) -> String {
+        format!(
+            "<!DOCTYPE html><html><head></head><body>{}</body></html>",
+            body
+        )
+    }
+
+    #[test]
+    fn hidden_and_aria_hidden_elements_are_left_out() {
+        let (mut tree, body) = Tree::page();
+        let p = tree.element(body, "p", &[], true);
+        tree.text(p, "Shown", true);
+        let menu = tree.element(body, "nav", &[], false);
+        tree.text(menu, "Menu", false);
+        let label = tree.element(body, "div", &[("aria-hidden", "true")], true);
+        tree.text(label, "Screen reader only", true);
+        tree.text(body, "Stray hidden text", false);
+
+        assert_eq!(tree.0.html(), page_html("<p>Shown</p>"));
+    }
+
+    #[test]
+    fn hidden_elements_with_visible_descendants_are_kept() {
+        let (mut tree, body) = Tree::page();
+        let hidden = tree.element(body, "div", &[], false);
+        let span = tree.element(hidden, "span", &[], true);
+        tree.text(span, "Visible", true);
+
+        assert_eq!(tree.0.html(), page_html("<div><span>Visible</span></div>"));
+    }
+
+    #[test]
+    fn whitespace_between_hidden_blocks_is_kept() {
+        let (mut tree, body) = Tree::page();
+        tree.text(body, "one", true);
+        let hidden = tree.element(body, "div", &[], false);
+        tree.text(hidden, "Menu", false);
+        tree.text(body, " ", false);
+        tree.text(body, "two", true);
+
+        assert_eq!(tree.0.html(), page_html("one two"));
+    }
+
+    #[test]
+    fn unrendered_elements_are_kept_whole_and_unescaped() {
+        let (mut tree, body) = Tree::page();
+        let script = tree.element(body, "script", &[("type", "application/ld+json")], false);
+        tree.text(script, "{\"a\": \"<b> & c\"}", false);
+
+        assert_eq!(
+            tree.0.html(),
+            page_html("<script type=\"application/ld+json\">{\"a\": \"<b> & c\"}</script>")
+        );
+    }
+
+    #[test]
+    fn text_and_attributes_are_escaped() {
+        let (mut tree, body) = Tree::page();
+        let p = tree.element(body, "p", &[("title", "a \"b\" & <c>")], true);
+        tree.text(p, "1 < 2 & 3 > 2\u{a0}\"x\"", true);
+
+        assert_eq!(
+            tree.0.html(),
+            page_html(
+                "<p title=\"a &quot;b&quot; &amp; <c>\">1 &lt; 2 &amp; 3 &gt; 2&nbsp;\"x\"</p>"
+            )
+        );
+    }
+
+    #[test]
+    fn void_elements_have_no_closing_tag() {
+        let (mut tree, body) = Tree::page();
+        let p = tree.element(body, "p", &[], true);
+        tree.text(p, "a", true);
+        tree.element(p, "br", &[], true);
+        tree.element(p, "img", &[("src", "x.png"), ("alt", "")], true);
+
+        assert_eq!(
+            tree.0.html(),
+            page_html("<p>a<br><img src=\"x.png\" alt=\"\"></p>")
+        );
+    }
+
+    #[test]
+    fn display_none_and_hidden_visibility_hide() {
+        assert!(shown_by(&["block", "visible"]));
+        assert!(shown_by(&["inline"]));
+        assert!(!shown_by(&["none", "visible"]));
+        assert!(!shown_by(&["block", "hidden"]));
+        assert!(!shown_by(&["table-row", "collapse"]));
+    }
+}
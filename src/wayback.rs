@@ -0,0 +1,193 @@
+use anyhow::bail;
+use http_req::{request::Request, uri::Uri};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::io;
+use std::time::Duration;
+use url::Url;
+
+/// Checking the target and asking the Wayback Machine for a snapshot, each
+/// plain HTTP without a browser.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+const AVAILABILITY_API: &str = "https://archive.org/wayback/available";
+
+/// The Internet Archive snapshot a result was scraped from, because the
+/// page itself is gone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedSnapshot {
+    /// The snapshot as the Wayback Machine shows it.
+    pub url: String,
+    /// When the snapshot was taken, e.g. `2013-09-19T04:46:12Z`.
+    pub snapshot_date: String,
+    /// The archived page without the Wayback Machine's banner, which is
+    /// what gets scraped.
+    #[serde(skip)]
+    pub raw_url: String,
+}
+
+/// The snapshot to scrape instead of `url` when `url` is gone: the one
+/// closest to `timestamp`, or the latest. `None` when the page is still
+/// there or the archive has no snapshot of it. `headers` go with the
+/// request checking the page.
+pub async fn fallback(
+    url: &Url,
+    timestamp: Option<String>,
+    headers: Vec<(String, String)>,
+) -> Option<ArchivedSnapshot> {
+    let url = url.clone();
+    tokio::task::spawn_blocking(move || {
+        if !is_gone(&url, &headers) {
+            return None;
+        }
+        match snapshot(&url, timestamp.as_deref()) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                warn!("couldn't look up {} in the Wayback Machine: {}", url, e);
+                None
+            }
+        }
+    })
+    .await
+    .unwrap_or(None)
+}
+
+/// Whether `url` answers 404 or 410, or its host doesn't resolve or refuses
+/// the connection. Other failures (TLS errors, timeouts, dropped
+/// connections) say nothing about the page, so they don't count.
+fn is_gone(url: &Url, headers: &[(String, String)]) -> bool {
+    let uri = match Uri::try_from(url.as_str()) {
+        Ok(uri) => uri,
+        Err(_) => return false,
+    };
+    if url
+        .socket_addrs(|| None)
+        .map_or(true, |addrs| addrs.is_empty())
+    {
+        return true;
+    }
+    let mut request = Request::new(&uri);
+    request.timeout(Some(LOOKUP_TIMEOUT));
+    for (name, value) in headers {
+        request.header(name, value);
+    }
+    match request.send(&mut io::sink()) {
+        Ok(res) => matches!(u16::from(res.status_code()), 404 | 410),
+        Err(http_req::error::Error::IO(e)) => e.kind() == io::ErrorKind::ConnectionRefused,
+        Err(_) => false,
+    }
+}
+
+/// The snapshot of `url` closest to `timestamp` (`YYYYMMDDhhmmss` or a
+/// prefix of it), or the latest one without it; `None` when the archive has
+/// none.
+fn snapshot(url: &Url, timestamp: Option<&str>) -> anyhow::Result<Option<ArchivedSnapshot>> {
+    let mut api = Url::parse(AVAILABILITY_API)?;
+    api.query_pairs_mut().append_pair("url", url.as_str());
+    if let Some(timestamp) = timestamp {
+        api.query_pairs_mut().append_pair("timestamp", timestamp);
+    }
+
+    let uri = Uri::try_from(api.as_str())?;
+    let mut writer = Vec::new();
+    let res = Request::new(&uri)
+        .timeout(Some(LOOKUP_TIMEOUT))
+        .send(&mut writer)?;
+    if !res.status_code().is_success() {
+        bail!("the availability API returned {}", res.status_code());
+    }
+
+    let availability: Availability = serde_json::from_slice(&writer)?;
+    let closest = match availability.archived_snapshots.closest {
+        Some(closest) if closest.available && closest.timestamp.len() == 14 => closest,
+        _ => return Ok(None),
+    };
+    let t = &closest.timestamp;
+    Ok(Some(ArchivedSnapshot {
+        raw_url: format!("https://web.archive.org/web/{}id_/{}", t, url),
+        snapshot_date: format!(
+            "{}-{}-{}T{}:{}:{}Z",
+            &t[0..4],
+            &t[4..6],
+            &t[6..8],
+            &t[8..10],
+            &t[10..12],
+            &t[12..14]
+        ),
+        url: closest.url,
+    }))
+}
+
+#[derive(Deserialize)]
+struct Availability {
+    archived_snapshots: ArchivedSnapshots,
+}
+
+#[derive(Deserialize)]
+struct ArchivedSnapshots {
+    closest: Option<Closest>,
+}
+
+#[derive(Deserialize)]
+struct Closest {
+    available: bool,
+    url: String,
+    timestamp: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// A local URL whose first connection gets `answer`, or is closed
+    /// unanswered without one.
+    fn serve_once(answer: Option<&'static str>) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/page", listener.local_addr().unwrap())).unwrap();
+        thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request);
+                if let Some(answer) = answer {
+                    let _ = stream.write_all(answer.as_bytes());
+                }
+            }
+        });
+        url
+    }
+
+    const OK: &str = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+    const NOT_FOUND: &str =
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    const GONE: &str = "HTTP/1.1 410 Gone\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+    #[test]
+    fn not_found_and_gone_pages_are_gone() {
+        assert!(is_gone(&serve_once(Some(NOT_FOUND)), &[]));
+        assert!(is_gone(&serve_once(Some(GONE)), &[]));
+        assert!(!is_gone(&serve_once(Some(OK)), &[]));
+    }
+
+    #[test]
+    fn refused_connections_and_unknown_hosts_are_gone() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let refused = Url::parse(&format!("http://127.0.0.1:{}/page", port)).unwrap();
+        assert!(is_gone(&refused, &[]));
+
+        let unknown = Url::parse("http://no-such-host.invalid/page").unwrap();
+        assert!(is_gone(&unknown, &[]));
+    }
+
+    #[test]
+    fn other_failures_are_not_gone() {
+        assert!(!is_gone(&serve_once(None), &[]));
+    }
+}